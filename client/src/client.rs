@@ -6,13 +6,15 @@ use num::iter::range_inclusive;
 use std::sync::Mutex;
 
 use common::block_position::BlockPosition;
-use common::communicate::ClientId;
+use common::brush;
+use common::communicate::Session;
 use common::entity::EntityId;
 use common::lod::LODIndex;
 use common::surroundings_loader::SurroundingsLoader;
 use common::terrain_block;
 use common::terrain_block::TerrainBlock;
 
+use prediction;
 use terrain_buffers;
 
 /// The distances at which LOD switches.
@@ -23,8 +25,10 @@ const MAX_LOAD_DISTANCE: i32 = 80;
 
 /// The main client state.
 pub struct T {
-  #[allow(missing_docs)]
-  pub id: ClientId,
+  /// Identifies this client to the server.
+  pub session: Session,
+  /// The optional protocol features the server agreed to use.
+  pub capabilities: Vec<String>,
   #[allow(missing_docs)]
   pub player_id: EntityId,
   #[allow(missing_docs)]
@@ -37,10 +41,19 @@ pub struct T {
   pub loaded_blocks: Mutex<HashMap<BlockPosition, (TerrainBlock, LODIndex)>>,
   /// The number of terrain requests that are outstanding,
   pub outstanding_terrain_requests: Mutex<u32>,
+  /// Our player's predicted movement. Lock `loaded_blocks` first if both are needed.
+  pub prediction: Mutex<prediction::T>,
+  /// The brush the player edits the terrain with.
+  pub brush: Mutex<brush::T>,
 }
 
 #[allow(missing_docs)]
-pub fn new(client_id: ClientId, player_id: EntityId, position: Point3<f32>) -> T {
+pub fn new(
+  session: Session,
+  capabilities: Vec<String>,
+  player_id: EntityId,
+  position: Point3<f32>,
+) -> T {
   let mut load_distance = load_distance(terrain_buffers::POLYGON_BUDGET as i32);
 
   if load_distance > MAX_LOAD_DISTANCE {
@@ -58,13 +71,22 @@ pub fn new(client_id: ClientId, player_id: EntityId, position: Point3<f32>) -> T
   };
 
   T {
-    id: client_id,
+    session: session,
+    capabilities: capabilities,
     player_id: player_id,
     player_position: Mutex::new(position),
     max_load_distance: load_distance,
     surroundings_loader: Mutex::new(surroundings_loader),
     loaded_blocks: Mutex::new(HashMap::new()),
     outstanding_terrain_requests: Mutex::new(0),
+    prediction: Mutex::new(prediction::new()),
+    brush: Mutex::new(
+      brush::T {
+        shape: brush::Shape::Sphere,
+        size: 4.0,
+        material: brush::Material::Terrain,
+      }
+    ),
   }
 }
 
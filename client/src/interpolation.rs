@@ -0,0 +1,207 @@
+//! Smooth entity motion between server snapshots.
+//!
+//! The server sends entity positions once per tick, which is usually less often than we render.
+//! Rather than jumping entities to each new position as it arrives, we render slightly in the past,
+//! interpolating between the two snapshots on either side of the render time.
+
+use cgmath::{Aabb3, Point, Point3, Vector};
+use std::collections::{HashMap, VecDeque};
+
+use common::communicate::Snapshot;
+use common::entity::EntityId;
+
+/// How far behind the latest snapshot to render, in ticks. Larger values tolerate more network jitter.
+const DELAY_TICKS: f64 = 2.0;
+/// The most samples to keep per entity.
+const MAX_SAMPLES: usize = 16;
+/// If snapshots start arriving this many ticks later than we expect, assume the clocks have drifted.
+const RESYNC_TICKS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub enum Kind {
+  Player,
+  Mob,
+}
+
+struct Track {
+  kind: Kind,
+  /// (tick, bounds) in increasing tick order.
+  samples: VecDeque<(u64, Aabb3<f32>)>,
+}
+
+fn lerp(a: &Point3<f32>, b: &Point3<f32>, s: f32) -> Point3<f32> {
+  a.add_v(&b.sub_p(a).mul_s(s))
+}
+
+impl Track {
+  fn push(&mut self, tick: u64, bounds: Aabb3<f32>) {
+    let last =
+      match self.samples.back() {
+        None => None,
+        Some(&(last_tick, ref last_bounds)) => Some((last_tick, last_bounds.clone())),
+      };
+    match last {
+      None => {},
+      Some((last_tick, last_bounds)) => {
+        if tick <= last_tick {
+          return
+        }
+        // Entities are left out of snapshots while they're still, so it was sitting at its last
+        // position until the tick before this one.
+        if last_tick + 1 < tick {
+          self.samples.push_back((tick - 1, last_bounds));
+        }
+      },
+    }
+
+    self.samples.push_back((tick, bounds));
+    while self.samples.len() > MAX_SAMPLES {
+      self.samples.pop_front();
+    }
+  }
+
+  /// The bounds at a fractional tick. Samples that are no longer needed are discarded.
+  fn at(&mut self, tick: f64) -> Aabb3<f32> {
+    while self.samples.len() >= 2 && (self.samples[1].0 as f64) <= tick {
+      self.samples.pop_front();
+    }
+
+    let (t0, ref b0) = self.samples[0];
+    if self.samples.len() < 2 || tick <= t0 as f64 {
+      // Don't extrapolate; hold the nearest position instead.
+      return b0.clone()
+    }
+
+    let (t1, ref b1) = self.samples[1];
+    let s = ((tick - t0 as f64) / (t1 - t0) as f64) as f32;
+    Aabb3::new(lerp(&b0.min, &b1.min, s), lerp(&b0.max, &b1.max, s))
+  }
+}
+
+/// Entity positions received from the server, and what we've drawn of them.
+pub struct T {
+  tracks: HashMap<EntityId, Track>,
+  /// The estimated local time (in ns) of tick 0.
+  epoch: Option<i64>,
+  tick_ns: u64,
+  drawn: HashMap<EntityId, Aabb3<f32>>,
+}
+
+#[allow(missing_docs)]
+pub fn new() -> T {
+  T {
+    tracks: HashMap::new(),
+    epoch: None,
+    tick_ns: 1,
+    drawn: HashMap::new(),
+  }
+}
+
+fn same(a: &Aabb3<f32>, b: &Aabb3<f32>) -> bool {
+  a.min == b.min && a.max == b.max
+}
+
+impl T {
+  /// Add a snapshot that arrived at local time `received` (in ns).
+  pub fn push(&mut self, received: u64, snapshot: &Snapshot) {
+    self.tick_ns = snapshot.tick_ns;
+
+    // The snapshot that arrived soonest after it was sent gives the best estimate of the server's
+    // clock, so keep the smallest offset we've seen.
+    let offset = received as i64 - (snapshot.tick * snapshot.tick_ns) as i64;
+    self.epoch =
+      match self.epoch {
+        None => Some(offset),
+        Some(epoch) => {
+          if offset < epoch || offset - epoch > (RESYNC_TICKS * snapshot.tick_ns) as i64 {
+            Some(offset)
+          } else {
+            Some(epoch)
+          }
+        },
+      };
+
+    let entities =
+      snapshot.players.iter().map(|e| (Kind::Player, e))
+      .chain(snapshot.mobs.iter().map(|e| (Kind::Mob, e)));
+    for (kind, &(id, ref bounds)) in entities {
+      self.tracks
+        .entry(id)
+        .or_insert_with(|| Track { kind: kind, samples: VecDeque::new() })
+        .push(snapshot.tick, bounds.clone());
+    }
+  }
+
+  /// Forget an entity.
+  pub fn remove(&mut self, id: EntityId) {
+    self.tracks.remove(&id);
+    self.drawn.remove(&id);
+  }
+
+  /// The entities whose interpolated bounds at local time `now` (in ns) differ from the last call.
+  pub fn changes(&mut self, now: u64) -> Vec<(EntityId, Kind, Aabb3<f32>)> {
+    let epoch =
+      match self.epoch {
+        None => return Vec::new(),
+        Some(epoch) => epoch,
+      };
+    let tick = (now as i64 - epoch) as f64 / self.tick_ns as f64 - DELAY_TICKS;
+
+    let mut changes = Vec::new();
+    for (&id, track) in self.tracks.iter_mut() {
+      let bounds = track.at(tick);
+      let changed = self.drawn.get(&id).map(|drawn| !same(drawn, &bounds)).unwrap_or(true);
+      if changed {
+        self.drawn.insert(id, bounds.clone());
+        changes.push((id, track.kind, bounds));
+      }
+    }
+    changes
+  }
+}
+
+#[cfg(test)]
+fn cube(x: f32) -> Aabb3<f32> {
+  Aabb3::new(Point3::new(x, 0.0, 0.0), Point3::new(x + 1.0, 1.0, 1.0))
+}
+
+#[cfg(test)]
+fn snapshot(tick: u64, players: Vec<(EntityId, Aabb3<f32>)>) -> Snapshot {
+  Snapshot {
+    tick: tick,
+    tick_ns: 100,
+    players: players,
+    mobs: Vec::new(),
+  }
+}
+
+#[test]
+fn interpolates_between_snapshots() {
+  let id = EntityId::default() + 1;
+  let mut entities = new();
+  entities.push(1000, &snapshot(10, vec!((id, cube(0.0)))));
+  entities.push(1100, &snapshot(11, vec!((id, cube(2.0)))));
+
+  // Two ticks behind the middle of tick 11 is halfway between 9 and 10; we only have 10 yet.
+  assert_eq!(entities.changes(1150)[0].2.min.x, 0.0);
+  // Two ticks behind the middle of tick 12 is halfway between 10 and 11.
+  let changes = entities.changes(1250);
+  assert_eq!(changes.len(), 1);
+  assert_eq!(changes[0].1, Kind::Player);
+  assert_eq!(changes[0].2.min.x, 1.0);
+  // Nothing moved since the last frame.
+  assert!(entities.changes(1250).is_empty());
+}
+
+#[test]
+fn still_entities_hold_position() {
+  let id = EntityId::default() + 1;
+  let mut entities = new();
+  entities.push(1000, &snapshot(10, vec!((id, cube(0.0)))));
+  // The entity was left out of ticks 11 to 13, so it didn't move until after tick 13.
+  entities.push(1400, &snapshot(14, vec!((id, cube(4.0)))));
+
+  assert_eq!(entities.changes(1450)[0].2.min.x, 0.0);
+  assert_eq!(entities.changes(1550)[0].2.min.x, 2.0);
+}
@@ -2,19 +2,22 @@ use num;
 use std::collections::hash_map::Entry::{Vacant, Occupied};
 
 use common::block_position::BlockPosition;
-use common::communicate::TerrainBlockSend;
+use common::block_wire;
+use common::communicate::{ClientToServer, TerrainBlockSend};
 use common::lod::LODIndex;
 use common::surroundings_loader;
 
 use client;
 use view_update::ClientToView;
 
-pub fn load_terrain_block<UpdateView>(
+pub fn load_terrain_block<UpdateView, UpdateServer>(
   client: &client::T,
   update_view: &mut UpdateView,
+  update_server: &mut UpdateServer,
   block: TerrainBlockSend,
 ) where
   UpdateView: FnMut(ClientToView),
+  UpdateServer: FnMut(ClientToServer),
 {
   let player_position =
     BlockPosition::of_world_position(&client.player_position.lock().unwrap().clone());
@@ -40,28 +43,76 @@ pub fn load_terrain_block<UpdateView>(
     return;
   }
 
+  let contents =
+    match block_wire::unpack(block.block) {
+      None => {
+        warn!("Dropping corrupt block {:?}", block.position);
+        return;
+      },
+      Some(contents) => contents,
+    };
+
   let mut updates = Vec::new();
 
-  match client.loaded_blocks.lock().unwrap().entry(block.position) {
-    Vacant(entry) => {
-      entry.insert((block.block.clone(), block.lod));
-    },
-    Occupied(mut entry) => {
-      {
-        // The block removal code is duplicated elsewhere.
+  match contents {
+    block_wire::Contents::Full(triangles) => {
+      let new_block = block_wire::to_block(&block.position, &triangles);
+
+      match client.loaded_blocks.lock().unwrap().entry(block.position) {
+        Vacant(entry) => {
+          entry.insert((new_block.clone(), block.lod));
+        },
+        Occupied(mut entry) => {
+          {
+            // The block removal code is duplicated elsewhere.
 
-        let &(ref prev_block, prev_lod) = entry.get();
-        for &id in &prev_block.ids {
-          updates.push(ClientToView::RemoveTerrain(id));
-        }
-        updates.push(ClientToView::RemoveBlockData(block.position, prev_lod));
+            let &(ref prev_block, prev_lod) = entry.get();
+            for &id in &prev_block.ids {
+              updates.push(ClientToView::RemoveTerrain(id));
+            }
+            updates.push(ClientToView::RemoveBlockData(block.position, prev_lod));
+          }
+          entry.insert((new_block.clone(), block.lod));
+        },
+      };
+
+      if !new_block.ids.is_empty() {
+        updates.push(ClientToView::AddBlock(block.position, new_block, block.lod));
       }
-      entry.insert((block.block.clone(), block.lod));
     },
-  };
+    block_wire::Contents::Delta(delta) => {
+      let mut loaded_blocks = client.loaded_blocks.lock().unwrap();
+      let applied =
+        match loaded_blocks.get(&block.position) {
+          Some(&(ref prev_block, prev_lod)) if prev_lod == block.lod => {
+            block_wire::apply(&block.position, prev_block, &delta)
+          },
+          _ => {
+            debug!("Not loading {:?}: delta for a block we don't have.", block.position);
+            return;
+          },
+        };
 
-  if !block.block.ids.is_empty() {
-    updates.push(ClientToView::AddBlock(block.position, block.block, block.lod));
+      match applied {
+        None => {
+          // We missed an update somewhere; start over from the whole block.
+          debug!("Delta for {:?} doesn't apply; re-requesting it.", block.position);
+          update_server(ClientToServer::RequestBlock(client.session, block.position, block.lod));
+          *client.outstanding_terrain_requests.lock().unwrap() += 1;
+          return;
+        },
+        Some((new_block, removed)) => {
+          for &id in &removed {
+            updates.push(ClientToView::RemoveTerrain(id));
+          }
+          let added = block_wire::to_block(&block.position, &delta.added);
+          if !added.ids.is_empty() {
+            updates.push(ClientToView::AddBlock(block.position, added, block.lod));
+          }
+          loaded_blocks.insert(block.position, (new_block, block.lod));
+        },
+      }
+    },
   }
 
   update_view(ClientToView::Atomic(updates));
@@ -1,11 +1,14 @@
 use env_logger;
+use std;
 use std::env;
 use std::sync::mpsc::{channel, Receiver, TryRecvError};
 use std::sync::Mutex;
 use stopwatch;
 use thread_scoped;
 
+use common::communicate;
 use common::communicate::{ClientToServer, ServerToClient};
+use common::framed_tcp;
 
 use client;
 use server;
@@ -31,10 +34,14 @@ fn main() {
   args.next().unwrap();
   let listen_url = args.next().unwrap_or(String::from("ipc:///tmp/client.ipc"));
   let server_url = args.next().unwrap_or(String::from("ipc:///tmp/server.ipc"));
+  let player_name = args.next().unwrap_or(String::from("player"));
+  let password = args.next().unwrap_or(String::new());
   assert!(args.next().is_none());
 
   info!("Sending to {}.", server_url);
-  info!("Listening on {}.", listen_url);
+  if framed_tcp::address(server_url.as_ref()).is_none() {
+    info!("Listening on {}.", listen_url);
+  }
 
   let (terrain_blocks_send, mut terrain_blocks_recv) = channel();
   let (view_thread_send0, mut view_thread_recv0) = channel();
@@ -52,7 +59,7 @@ fn main() {
 
   let server = server::new(&server_url, &listen_url);
 
-  let client = connect_client(&listen_url, &server);
+  let client = connect_client(&listen_url, &player_name, &password, &server);
   let client = &client;
 
   {
@@ -83,7 +90,7 @@ fn main() {
     {
       let server = server.clone();
       view_thread(
-        client.player_id,
+        client,
         &mut || {
           match view_thread_recv0.try_recv() {
             Ok(msg) => Some(msg),
@@ -109,23 +116,40 @@ fn main() {
     // View thread returned, so we got a quit event.
     *quit.lock().unwrap() = true;
 
+    server.talk.tell(&ClientToServer::Disconnect(client.session));
+
     let stopwatch = update_thread.join();
     stopwatch.print();
   }
 }
 
-fn connect_client(listen_url: &str, server: &server::T) -> client::T {
+fn connect_client(listen_url: &str, player_name: &str, password: &str, server: &server::T) -> client::T {
   // TODO: Consider using RPCs to solidify the request-response patterns.
-  server.talk.tell(&ClientToServer::Init(listen_url.to_owned()));
+  server.talk.tell(
+    &ClientToServer::Init(
+      communicate::PROTOCOL_VERSION,
+      listen_url.to_owned(),
+      communicate::CAPABILITIES.iter().map(|&c| String::from(c)).collect(),
+    )
+  );
   loop {
     match server.listen.wait() {
-      ServerToClient::LeaseId(client_id) => {
-        server.talk.tell(&ClientToServer::AddPlayer(client_id));
-        let client_id = client_id;
+      ServerToClient::Rejected(reason) => {
+        error!("The server refused the connection: {}", reason);
+        std::process::exit(1);
+      },
+      ServerToClient::LeaseId(session, capabilities) => {
+        info!("Using capabilities {:?}", capabilities);
+        server.talk.tell(&ClientToServer::AddPlayer(session, player_name.to_owned(), password.to_owned()));
         loop {
           match server.listen.wait() {
             ServerToClient::PlayerAdded(player_id, position) => {
-              return client::new(client_id, player_id, position);
+              return client::new(session, capabilities, player_id, position);
+            },
+            ServerToClient::Error(reason) => {
+              error!("The server refused the player: {}", reason);
+              server.talk.tell(&ClientToServer::Disconnect(session));
+              std::process::exit(1);
             },
             msg => {
               // Ignore other messages in the meantime.
@@ -36,11 +36,13 @@ mod camera;
 mod client;
 mod fontloader;
 mod hud;
+mod interpolation;
 mod light;
 mod load_terrain;
 mod main;
 mod mob_buffers;
 mod player_buffers;
+mod prediction;
 mod process_event;
 mod render;
 mod server;
@@ -0,0 +1,254 @@
+//! Predict our own player's movement, so it responds to input without waiting for the server.
+//!
+//! Inputs are applied locally as soon as they happen, and the player is stepped at the server's
+//! tick rate against the terrain we have loaded. Everything done locally is logged until the server
+//! acknowledges it; when an authoritative state arrives, we start from it and replay whatever the
+//! server hasn't seen yet.
+
+use cgmath::{Aabb3, Point, Point3, Vector3};
+use std::collections::{HashMap, VecDeque};
+
+use common::block_position::BlockPosition;
+use common::collision;
+use common::collision::Impact;
+use common::interval_timer::IntervalTimer;
+use common::lod::LODIndex;
+use common::movement;
+use common::terrain_block;
+use common::terrain_block::TerrainBlock;
+
+/// Stop logging if the server stops acknowledging us; predictions are only approximate after this.
+const MAX_LOG_LENGTH: usize = 1 << 12;
+
+#[allow(missing_docs)]
+pub type LoadedBlocks = HashMap<BlockPosition, (TerrainBlock, LODIndex)>;
+
+enum Event {
+  Input(u32, movement::Input),
+  Step,
+}
+
+/// A point in a player's history: the last input applied, and the number of steps since.
+type Point = (u32, u32);
+
+fn after(point: Point, event: &Event) -> Point {
+  match event {
+    &Event::Input(sequence, _) => (sequence, 0),
+    &Event::Step => (point.0, point.1 + 1),
+  }
+}
+
+fn touch(aabb1: &Aabb3<f32>, aabb2: &Aabb3<f32>) -> bool {
+  true
+  && aabb1.min.x <= aabb2.max.x
+  && aabb1.min.y <= aabb2.max.y
+  && aabb1.min.z <= aabb2.max.z
+  && aabb2.min.x <= aabb1.max.x
+  && aabb2.min.y <= aabb1.max.y
+  && aabb2.min.z <= aabb1.max.z
+}
+
+// The player moving through the terrain we have loaded. Other entities are ignored.
+struct LoadedTerrain<'a> {
+  bounds: &'a mut Aabb3<f32>,
+  blocks: &'a LoadedBlocks,
+}
+
+impl<'a> movement::World for LoadedTerrain<'a> {
+  fn bounds(&self) -> Aabb3<f32> {
+    self.bounds.clone()
+  }
+
+  fn sweep(&self, bounds: &Aabb3<f32>, v: &Vector3<f32>) -> Option<Impact> {
+    let swept = collision::swept_bounds(bounds, v);
+    // Triangles can stick out of their blocks a little, so look in the neighboring blocks too.
+    let margin = Vector3::new(1.0, 1.0, 1.0);
+    let low = BlockPosition::of_world_position(&swept.min.add_v(&-margin));
+    let high = BlockPosition::of_world_position(&swept.max.add_v(&margin));
+    let (low, high) = (low.as_pnt(), high.as_pnt());
+    let mut first = None;
+    for x in low.x .. high.x + 1 {
+    for y in low.y .. high.y + 1 {
+    for z in low.z .. high.z + 1 {
+      let block =
+        match self.blocks.get(&BlockPosition::new(x, y, z)) {
+          None => continue,
+          Some(&(ref block, _)) => block,
+        };
+      for triangle in &block.vertex_coordinates {
+        let triangle_bounds = terrain_block::make_bounds(&triangle.v1, &triangle.v2, &triangle.v3);
+        if touch(&triangle_bounds, &swept) {
+          first = collision::earliest(first, collision::sweep_triangle(bounds, v, triangle));
+        }
+      }
+    }}}
+    first
+  }
+
+  fn move_to(&mut self, bounds: Aabb3<f32>) {
+    *self.bounds = bounds;
+  }
+}
+
+/// The predicted state of our player.
+pub struct T {
+  /// None until the server first tells us where the player is.
+  predicted: Option<(movement::State, Aabb3<f32>)>,
+  next_input: u32,
+  /// The latest point the server has acknowledged.
+  acked: Point,
+  /// Everything we've done since `acked`.
+  log: VecDeque<Event>,
+  /// Steps the player at the server's tick rate, once we know it.
+  timer: Option<IntervalTimer>,
+  /// The predicted position hasn't been taken since it changed.
+  moved: bool,
+}
+
+#[allow(missing_docs)]
+pub fn new() -> T {
+  T {
+    predicted: None,
+    next_input: 1,
+    acked: (0, 0),
+    log: VecDeque::new(),
+    timer: None,
+    moved: false,
+  }
+}
+
+impl T {
+  fn push(&mut self, event: Event) {
+    if self.log.len() >= MAX_LOG_LENGTH {
+      self.log.pop_front();
+    }
+    self.log.push_back(event);
+  }
+
+  /// Start stepping the player every `tick_ns`, if we haven't already.
+  pub fn start(&mut self, tick_ns: u64, now: u64) {
+    if self.timer.is_none() {
+      self.timer = Some(IntervalTimer::new(tick_ns, now));
+    }
+  }
+
+  /// Apply an input locally, and return the sequence number to send it to the server with.
+  pub fn input(&mut self, input: movement::Input) -> u32 {
+    let sequence = self.next_input;
+    self.next_input += 1;
+
+    match self.predicted {
+      None => {},
+      Some((ref mut state, _)) => {
+        state.apply(&input);
+        self.moved = true;
+      },
+    }
+    self.push(Event::Input(sequence, input));
+    sequence
+  }
+
+  /// Step the player for any ticks that have passed by local time `now`.
+  pub fn update(&mut self, now: u64, blocks: &LoadedBlocks) {
+    let steps =
+      match self.timer {
+        None => return,
+        Some(ref mut timer) => timer.update(now),
+      };
+    if self.predicted.is_none() {
+      return
+    }
+
+    for _ in 0 .. steps {
+      {
+        let &mut (ref mut state, ref mut bounds) = self.predicted.as_mut().unwrap();
+        state.step(&mut LoadedTerrain { bounds: bounds, blocks: blocks });
+      }
+      self.push(Event::Step);
+      self.moved = true;
+    }
+  }
+
+  /// Start from the server's state and replay what it hasn't seen yet.
+  pub fn reconcile(&mut self, ack: movement::Ack, blocks: &LoadedBlocks) {
+    let acked = (ack.input, ack.steps);
+    if acked < self.acked {
+      warn!("Ignoring stale player state {:?}", acked);
+      return
+    }
+
+    loop {
+      let next =
+        match self.log.front() {
+          None => break,
+          Some(event) => after(self.acked, event),
+        };
+      if next > acked {
+        break
+      }
+      self.log.pop_front();
+      self.acked = next;
+    }
+    self.acked = acked;
+
+    let mut state = ack.state;
+    let mut bounds = ack.bounds;
+    for event in &self.log {
+      match event {
+        &Event::Input(_, ref input) => state.apply(input),
+        &Event::Step => state.step(&mut LoadedTerrain { bounds: &mut bounds, blocks: blocks }),
+      }
+    }
+
+    self.predicted = Some((state, bounds));
+    self.moved = true;
+  }
+
+  /// The predicted position, if it's changed since this was last called.
+  pub fn take_position(&mut self) -> Option<Point3<f32>> {
+    if !self.moved {
+      return None
+    }
+    self.moved = false;
+    self.predicted.as_ref().map(|&(ref state, _)| state.position)
+  }
+}
+
+#[cfg(test)]
+fn ack(input: u32, steps: u32, x: f32) -> movement::Ack {
+  let mut state = movement::State::new(Point3::new(x, 1.0, 0.0));
+  // Keep the player still, apart from walking.
+  state.accel = Vector3::new(0.0, 0.0, 0.0);
+  movement::Ack {
+    input: input,
+    steps: steps,
+    state: state,
+    bounds: Aabb3::new(Point3::new(x, 0.0, 0.0), Point3::new(x + 1.0, 2.0, 1.0)),
+  }
+}
+
+#[test]
+fn replays_unacknowledged_inputs() {
+  let blocks = HashMap::new();
+  let mut prediction = new();
+  prediction.start(10, 0);
+  prediction.reconcile(ack(0, 0, 0.0), &blocks);
+
+  let walk = movement::Input::Walk(Vector3::new(1.0, 0.0, 0.0));
+  assert_eq!(prediction.input(walk), 1);
+  prediction.update(0, &blocks);
+  prediction.update(10, &blocks);
+  let predicted = prediction.take_position().unwrap();
+  assert!(predicted.x > 0.0);
+
+  // The server hasn't seen the walk yet, so the prediction shouldn't change.
+  prediction.reconcile(ack(0, 2, 0.0), &blocks);
+  assert_eq!(prediction.take_position(), Some(predicted));
+
+  // The server has applied the walk and both steps, and disagrees about where we started.
+  let mut server = ack(1, 2, 5.0);
+  server.state.speed = Vector3::new(0.0, 0.0, 0.0);
+  prediction.reconcile(server, &blocks);
+  assert_eq!(prediction.take_position().unwrap().x, 5.0);
+  assert!(prediction.log.is_empty());
+}
@@ -9,16 +9,17 @@ use sdl2::video;
 use std::f32::consts::PI;
 use stopwatch;
 
+use common::brush;
 use common::communicate::ClientToServer;
-use common::entity::EntityId;
-use common::communicate::ClientToServer::*;
+use common::movement::Input;
 
+use client;
 use view;
 
 #[allow(missing_docs)]
 pub fn process_event<UpdateServer>(
   sdl: &sdl2::Sdl,
-  player_id: EntityId,
+  client: &client::T,
   update_server: &mut UpdateServer,
   view: &mut view::T,
   window: &mut video::Window,
@@ -29,29 +30,43 @@ pub fn process_event<UpdateServer>(
     Event::KeyDown{keycode, repeat, ..} => {
       keycode.map(|keycode| {
         if !repeat {
-          key_press(player_id, update_server, view, keycode);
+          key_press(client, update_server, view, keycode);
         }
       });
     },
     Event::KeyUp{keycode, repeat, ..} => {
       keycode.map(|keycode| {
         if !repeat {
-          key_release(player_id, update_server, keycode);
+          key_release(client, update_server, keycode);
         }
       });
     },
     Event::MouseMotion{x, y, ..} => {
-      mouse_move(sdl, player_id, update_server, view, window, x, y);
+      mouse_move(sdl, client, update_server, view, window, x, y);
     },
     Event::MouseButtonDown{mouse_btn, ..} => {
-      mouse_press(player_id, update_server, mouse_btn);
+      mouse_press(client, update_server, mouse_btn);
+    },
+    Event::MouseWheel{y, ..} => {
+      change_brush(client, |brush| brush.resize(y as f32));
     },
     _ => {},
   }
 }
 
+// Predict the input's effect on our player, and send it to the server.
+fn input<UpdateServer>(
+  client: &client::T,
+  update_server: &mut UpdateServer,
+  input: Input,
+) where UpdateServer: FnMut(ClientToServer)
+{
+  let sequence = client.prediction.lock().unwrap().input(input);
+  update_server(ClientToServer::Input(client.session, client.player_id, sequence, input));
+}
+
 fn key_press<UpdateServer>(
-  player_id: EntityId,
+  client: &client::T,
   update_server: &mut UpdateServer,
   view: &mut view::T,
   key: Keycode,
@@ -60,46 +75,58 @@ fn key_press<UpdateServer>(
   stopwatch::time("event.key_press", || {
     match key {
       Keycode::A => {
-        update_server(Walk(player_id, Vector3::new(-1.0, 0.0, 0.0)));
+        input(client, update_server, Input::Walk(Vector3::new(-1.0, 0.0, 0.0)));
       },
       Keycode::D => {
-        update_server(Walk(player_id, Vector3::new(1.0, 0.0, 0.0)));
+        input(client, update_server, Input::Walk(Vector3::new(1.0, 0.0, 0.0)));
       },
       Keycode::Space => {
-        update_server(StartJump(player_id));
+        input(client, update_server, Input::StartJump);
       },
       Keycode::W => {
-        update_server(Walk(player_id, Vector3::new(0.0, 0.0, -1.0)));
+        input(client, update_server, Input::Walk(Vector3::new(0.0, 0.0, -1.0)));
       },
       Keycode::S => {
-        update_server(Walk(player_id, Vector3::new(0.0, 0.0, 1.0)));
+        input(client, update_server, Input::Walk(Vector3::new(0.0, 0.0, 1.0)));
       },
       Keycode::Left => {
-        update_server(RotatePlayer(player_id, Vector2::new(PI / 12.0, 0.0)));
+        input(client, update_server, Input::Rotate(Vector2::new(PI / 12.0, 0.0)));
         view.camera.rotate_lateral(PI / 12.0);
       },
       Keycode::Right => {
-        update_server(RotatePlayer(player_id, Vector2::new(-PI / 12.0, 0.0)));
+        input(client, update_server, Input::Rotate(Vector2::new(-PI / 12.0, 0.0)));
         view.camera.rotate_lateral(-PI / 12.0);
       },
       Keycode::Up => {
-        update_server(RotatePlayer(player_id, Vector2::new(0.0, PI / 12.0)));
+        input(client, update_server, Input::Rotate(Vector2::new(0.0, PI / 12.0)));
         view.camera.rotate_vertical(PI / 12.0);
       },
       Keycode::Down => {
-        update_server(RotatePlayer(player_id, Vector2::new(0.0, -PI / 12.0)));
+        input(client, update_server, Input::Rotate(Vector2::new(0.0, -PI / 12.0)));
         view.camera.rotate_vertical(-PI / 12.0);
       },
       Keycode::H => {
         view.show_hud = !view.show_hud;
       },
+      Keycode::B => {
+        change_brush(client, |brush| brush::T { shape: brush.shape.next(), .. brush });
+      },
+      Keycode::M => {
+        change_brush(client, |brush| brush::T { material: brush.material.next(), .. brush });
+      },
+      Keycode::Z => {
+        update_server(ClientToServer::Undo(client.session, client.player_id));
+      },
+      Keycode::Y => {
+        update_server(ClientToServer::Redo(client.session, client.player_id));
+      },
       _ => {},
     }
   })
 }
 
 fn mouse_press<UpdateServer>(
-  player_id: EntityId,
+  client: &client::T,
   update_server: &mut UpdateServer,
   mouse_btn: Mouse,
 ) where UpdateServer: FnMut(ClientToServer)
@@ -107,13 +134,20 @@ fn mouse_press<UpdateServer>(
   stopwatch::time("event.mouse_press", || {
     match mouse_btn {
       Mouse::Left => {
+        let brush = *client.brush.lock().unwrap();
         update_server(
-          ClientToServer::Add(player_id)
+          ClientToServer::Brush(client.session, client.player_id, brush)
         );
       },
       Mouse::Right => {
+        let brush = client.brush.lock().unwrap().eraser();
+        update_server(
+          ClientToServer::Brush(client.session, client.player_id, brush)
+        );
+      },
+      Mouse::Middle => {
         update_server(
-          ClientToServer::Remove(player_id)
+          ClientToServer::PlantTree(client.session, client.player_id)
         );
       },
       _ => {},
@@ -121,8 +155,16 @@ fn mouse_press<UpdateServer>(
   })
 }
 
+fn change_brush<Change>(client: &client::T, change: Change)
+  where Change: FnOnce(brush::T) -> brush::T
+{
+  let mut brush = client.brush.lock().unwrap();
+  *brush = change(*brush);
+  info!("Brush: {:?} of size {} and {:?}", brush.shape, brush.size, brush.material);
+}
+
 fn key_release<UpdateServer>(
-  player_id: EntityId,
+  client: &client::T,
   update_server: &mut UpdateServer,
   key: Keycode,
 ) where UpdateServer: FnMut(ClientToServer)
@@ -131,19 +173,19 @@ fn key_release<UpdateServer>(
     match key {
       // accelerations are negated from those in key_press.
       Keycode::A => {
-        update_server(Walk(player_id, Vector3::new(1.0, 0.0, 0.0)));
+        input(client, update_server, Input::Walk(Vector3::new(1.0, 0.0, 0.0)));
       },
       Keycode::D => {
-        update_server(Walk(player_id, Vector3::new(-1.0, 0.0, 0.0)));
+        input(client, update_server, Input::Walk(Vector3::new(-1.0, 0.0, 0.0)));
       },
       Keycode::Space => {
-        update_server(StopJump(player_id));
+        input(client, update_server, Input::StopJump);
       },
       Keycode::W => {
-        update_server(Walk(player_id, Vector3::new(0.0, 0.0, 1.0)));
+        input(client, update_server, Input::Walk(Vector3::new(0.0, 0.0, 1.0)));
       },
       Keycode::S => {
-        update_server(Walk(player_id, Vector3::new(0.0, 0.0, -1.0)));
+        input(client, update_server, Input::Walk(Vector3::new(0.0, 0.0, -1.0)));
       },
       _ => {}
     }
@@ -152,7 +194,7 @@ fn key_release<UpdateServer>(
 
 fn mouse_move<UpdateServer>(
   sdl: &sdl2::Sdl,
-  player_id: EntityId,
+  client: &client::T,
   update_server: &mut UpdateServer,
   view: &mut view::T,
   window: &mut video::Window,
@@ -169,7 +211,7 @@ fn mouse_move<UpdateServer>(
     let to_radians = Vector2::new(-1.0 / 1000.0, 1.0 / 1600.0);
     let r = Vector2::new(d.x as f32 * to_radians.x, d.y as f32 * to_radians.y);
 
-    update_server(RotatePlayer(player_id, r));
+    input(client, update_server, Input::Rotate(r));
     view.camera.rotate_lateral(r.x);
     view.camera.rotate_vertical(r.y);
 
@@ -1,5 +1,6 @@
 use std;
 
+use common::framed_tcp;
 use common::socket::{SendSocket, ReceiveSocket};
 
 pub mod send {
@@ -15,7 +16,10 @@ pub mod send {
       use bincode::rustc_serialize::encode;
       use bincode::SizeLimit;
       let msg = encode(msg, SizeLimit::Infinite).unwrap();
-      self.0.send(msg).unwrap();
+      // If the send thread has stopped, the connection is gone and the receiving side will shut us down.
+      if self.0.send(msg).is_err() {
+        debug!("Dropping message to the server; the connection is closed.");
+      }
     }
   }
 }
@@ -31,21 +35,51 @@ pub mod recv {
   #[derive(Clone)]
   pub struct T (pub std::sync::Arc<Receiver<Vec<u8>>>);
 
+  // The receive thread stops when the connection does, and there's nothing to do without one.
+  fn lost_connection() -> ! {
+    error!("Lost the connection to the server.");
+    std::process::exit(1);
+  }
+
+  // Messages we can't decode are logged and dropped, rather than taking down the client.
+  fn decode(msg: &[u8]) -> Option<ServerToClient> {
+    match bincode::rustc_serialize::decode(msg) {
+      Ok(msg) => Some(msg),
+      Err(err) => {
+        error!("Dropping undecodable message from server ({} bytes): {:?}", msg.len(), err);
+        None
+      },
+    }
+  }
+
   impl T {
     pub fn try(&self) -> Option<ServerToClient> {
-      match self.0.try_recv() {
-        Ok(msg) => Some(bincode::rustc_serialize::decode(&msg).unwrap()),
-        Err(TryRecvError::Empty) => None,
-        e => {
-          e.unwrap();
-          unreachable!();
-        },
+      loop {
+        match self.0.try_recv() {
+          Ok(msg) => {
+            match decode(msg.as_ref()) {
+              None => {},
+              msg => return msg,
+            }
+          },
+          Err(TryRecvError::Empty) => return None,
+          Err(TryRecvError::Disconnected) => lost_connection(),
+        }
       }
     }
 
     pub fn wait(&self) -> ServerToClient {
-      let msg = self.0.recv().unwrap();
-      bincode::rustc_serialize::decode(msg.as_ref()).unwrap()
+      loop {
+        let msg =
+          match self.0.recv() {
+            Ok(msg) => msg,
+            Err(_) => lost_connection(),
+          };
+        match decode(msg.as_ref()) {
+          None => {},
+          Some(msg) => return msg,
+        }
+      }
     }
   }
 }
@@ -58,6 +92,8 @@ pub struct T {
 
 unsafe impl Send for T {}
 
+/// Connect to the server. If `server_url` is a framed TCP URL, messages both ways go over one
+/// connection and `listen_url` isn't listened on; otherwise the server sends to `listen_url`.
 pub fn new(
   server_url: &str,
   listen_url: &str,
@@ -65,6 +101,53 @@ pub fn new(
   let (send_send, send_recv) = std::sync::mpsc::channel();
   let (recv_send, recv_recv) = std::sync::mpsc::channel();
 
+  match framed_tcp::address(server_url) {
+    None => {},
+    Some(address) => {
+      let (writer, mut reader) =
+        framed_tcp::connect(address)
+        .unwrap_or_else(|err| panic!("Couldn't connect to {}: {:?}", server_url, err));
+
+      let _recv_thread =
+        std::thread::spawn(move || {
+          loop {
+            let msg =
+              match reader.read() {
+                Ok(msg) => msg,
+                Err(err) => {
+                  error!("Error reading from the server: {:?}", err);
+                  return
+                },
+              };
+            if recv_send.send(msg).is_err() {
+              // The client is shutting down.
+              return
+            }
+          }
+        });
+
+      let _send_thread =
+        std::thread::spawn(move || {
+          // This ends when the client drops its sender.
+          for msg in send_recv.iter() {
+            match writer.write(msg.as_ref()) {
+              Ok(()) => {},
+              Err(err) => {
+                // The writer closes the connection, so the receive thread stops too.
+                error!("Error sending to the server: {:?}", err);
+                return
+              },
+            }
+          }
+        });
+
+      return T {
+        talk: send::T (send_send),
+        listen: recv::T (std::sync::Arc::new(recv_recv)),
+      }
+    },
+  }
+
   let _recv_thread ={
     let listen_url = listen_url.to_owned();
     let recv_send = recv_send.clone();
@@ -1,25 +1,18 @@
-use cgmath::{Aabb3, Point, Point3, Vector, Vector3};
+use cgmath::Vector3;
 use std::f32;
 use std::f32::consts::PI;
 use stopwatch;
+use time;
 
-use common::color::{Color3, Color4};
+use common::color::Color3;
 use common::communicate;
 use common::communicate::{ClientToServer, ServerToClient, TerrainBlockSend};
+use common::terrain_block::TerrainBlock;
 
 use client;
 use light;
-use vertex::ColoredVertex;
 use view_update::ClientToView;
 
-pub const TRIANGLES_PER_BOX: u32 = 12;
-pub const VERTICES_PER_TRIANGLE: u32 = 3;
-pub const TRIANGLE_VERTICES_PER_BOX: u32 = TRIANGLES_PER_BOX * VERTICES_PER_TRIANGLE;
-
-fn center(bounds: &Aabb3<f32>) -> Point3<f32> {
-  bounds.min.add_v(&bounds.max.to_vec()).mul_s(0.5)
-}
-
 pub fn apply_server_update<UpdateView, UpdateServer, QueueBlock>(
   client: &client::T,
   update_view: &mut UpdateView,
@@ -33,33 +26,37 @@ pub fn apply_server_update<UpdateView, UpdateServer, QueueBlock>(
 {
   stopwatch::time("apply_server_update", move || {
     match update {
-      ServerToClient::LeaseId(_) => {
+      ServerToClient::Rejected(reason) => {
+        warn!("Unexpected rejection from the server: {}", reason);
+      },
+      ServerToClient::LeaseId(_, _) => {
         warn!("Client ID has already been leased.");
       },
       ServerToClient::Ping => {
-        update_server(ClientToServer::Ping(client.id));
+        update_server(ClientToServer::Ping(client.session));
       },
       ServerToClient::PlayerAdded(id, _) => {
         warn!("Unexpected PlayerAdded event: {:?}.", id);
       },
-      ServerToClient::UpdatePlayer(player_id, bounds) => {
-        let mesh = to_triangles(&bounds, &Color4::of_rgba(0.0, 0.0, 1.0, 1.0));
-        update_view(ClientToView::UpdatePlayer(player_id, mesh));
-
-        // We "lock" the client to client.player_id, so for updates to that player only,
-        // there is more client-specific logic.
+      ServerToClient::Snapshot(snapshot) => {
+        let now = time::precise_time_ns();
+        client.prediction.lock().unwrap().start(snapshot.tick_ns, now);
+        update_view(ClientToView::Snapshot(now, snapshot));
+      },
+      ServerToClient::RemovePlayer(player_id) => {
+        update_view(ClientToView::RemovePlayer(player_id));
+      },
+      ServerToClient::RemoveMob(mob_id) => {
+        update_view(ClientToView::RemoveMob(mob_id));
+      },
+      ServerToClient::PlayerState(player_id, ack) => {
         if player_id != client.player_id {
+          warn!("Unexpected state for player {:?}", player_id);
           return
         }
 
-        let position = center(&bounds);
-
-        *client.player_position.lock().unwrap() = position;
-        update_view(ClientToView::MoveCamera(position));
-      },
-      ServerToClient::UpdateMob(id, bounds) => {
-        let mesh = to_triangles(&bounds, &Color4::of_rgba(1.0, 0.0, 0.0, 1.0));
-        update_view(ClientToView::UpdateMob(id, mesh));
+        let loaded_blocks = client.loaded_blocks.lock().unwrap();
+        client.prediction.lock().unwrap().reconcile(ack, &loaded_blocks);
       },
       ServerToClient::UpdateSun(fraction) => {
         // Convert to radians.
@@ -92,6 +89,9 @@ pub fn apply_server_update<UpdateView, UpdateServer, QueueBlock>(
 
         update_view(ClientToView::SetClearColor(sun_color));
       },
+      ServerToClient::Error(err) => {
+        warn!("Server refused a request: {}", err);
+      },
       ServerToClient::Block(block, reason) => {
         match reason {
           communicate::BlockReason::Updated => {},
@@ -101,44 +101,14 @@ pub fn apply_server_update<UpdateView, UpdateServer, QueueBlock>(
         }
         queue_block(block);
       },
+      ServerToClient::BlockRefused(position, lod, err) => {
+        debug!("Server refused block {:?} at {:?}: {}", position, lod, err);
+        *client.outstanding_terrain_requests.lock().unwrap() -= 1;
+        // Don't ask again; there's nothing there that we could load.
+        client.loaded_blocks.lock().unwrap()
+          .entry(position)
+          .or_insert((TerrainBlock::empty(), lod));
+      },
     }
   })
 }
-
-fn to_triangles(
-  bounds: &Aabb3<f32>,
-  c: &Color4<f32>,
-) -> [ColoredVertex; TRIANGLE_VERTICES_PER_BOX as usize] {
-  let (x1, y1, z1) = (bounds.min.x, bounds.min.y, bounds.min.z);
-  let (x2, y2, z2) = (bounds.max.x, bounds.max.y, bounds.max.z);
-
-  let vtx = |x, y, z| {
-    ColoredVertex {
-      position: Point3::new(x, y, z),
-      color: c.clone(),
-    }
-  };
-
-  // Remember: x increases to the right, y increases up, and z becomes more
-  // negative as depth from the viewer increases.
-  [
-    // front
-    vtx(x1, y1, z2), vtx(x2, y2, z2), vtx(x1, y2, z2),
-    vtx(x1, y1, z2), vtx(x2, y1, z2), vtx(x2, y2, z2),
-    // left
-    vtx(x1, y1, z1), vtx(x1, y2, z2), vtx(x1, y2, z1),
-    vtx(x1, y1, z1), vtx(x1, y1, z2), vtx(x1, y2, z2),
-    // top
-    vtx(x1, y2, z1), vtx(x2, y2, z2), vtx(x2, y2, z1),
-    vtx(x1, y2, z1), vtx(x1, y2, z2), vtx(x2, y2, z2),
-    // back
-    vtx(x1, y1, z1), vtx(x2, y2, z1), vtx(x2, y1, z1),
-    vtx(x1, y1, z1), vtx(x1, y2, z1), vtx(x2, y2, z1),
-    // right
-    vtx(x2, y1, z1), vtx(x2, y2, z2), vtx(x2, y1, z2),
-    vtx(x2, y1, z1), vtx(x2, y2, z1), vtx(x2, y2, z2),
-    // bottom
-    vtx(x1, y1, z1), vtx(x2, y1, z2), vtx(x1, y1, z2),
-    vtx(x1, y1, z1), vtx(x2, y1, z1), vtx(x2, y1, z2),
-  ]
-}
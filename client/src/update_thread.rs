@@ -51,6 +51,20 @@ pub fn update_thread<RecvServer, RecvBlock, UpdateView0, UpdateView1, UpdateServ
           }
         }
 
+        stopwatch::time("update_prediction", || {
+          let position;
+          {
+            let loaded_blocks = client.loaded_blocks.lock().unwrap();
+            let mut prediction = client.prediction.lock().unwrap();
+            prediction.update(time::precise_time_ns(), &loaded_blocks);
+            position = prediction.take_position();
+          }
+          position.map(|position| {
+            *client.player_position.lock().unwrap() = position;
+            update_view0(ClientToView::MoveCamera(position));
+          });
+        });
+
         stopwatch::time("update_surroundings", || {
           let start = time::precise_time_ns();
           let player_position = *client.player_position.lock().unwrap();
@@ -89,7 +103,7 @@ pub fn update_thread<RecvServer, RecvBlock, UpdateView0, UpdateView1, UpdateServ
                   if loaded_lod != Some(lod) {
                     update_server(
                       ClientToServer::RequestBlock(
-                        client.id,
+                        client.session,
                         block_position,
                         lod,
                       )
@@ -110,7 +124,7 @@ pub fn update_thread<RecvServer, RecvBlock, UpdateView0, UpdateView1, UpdateServ
                   if lod_change == Some(true) {
                     update_server(
                       ClientToServer::RequestBlock(
-                        client.id,
+                        client.session,
                         block_position,
                         new_lod,
                       )
@@ -150,6 +164,7 @@ pub fn update_thread<RecvServer, RecvBlock, UpdateView0, UpdateView1, UpdateServ
           load_terrain_block(
             client,
             update_view1,
+            update_server,
             block,
           );
 
@@ -13,6 +13,7 @@ use camera::Camera;
 use fontloader::FontLoader;
 use gl;
 use gl::types::*;
+use interpolation;
 use mob_buffers::MobBuffers;
 use player_buffers::PlayerBuffers;
 use shaders::Shaders;
@@ -46,6 +47,9 @@ pub struct T<'a> {
   #[allow(missing_docs)]
   pub fontloader: FontLoader,
 
+  /// Entity positions received from the server, for smoothing their motion.
+  pub entities: interpolation::T,
+
   #[allow(missing_docs)]
   pub camera: Camera,
 
@@ -142,6 +146,8 @@ impl<'a> T<'a> {
       text_textures: text_textures,
       fontloader: FontLoader::new(),
 
+      entities: interpolation::new(),
+
       camera: {
         let fovy = cgmath::rad(3.14 / 3.0);
         let aspect = window_size.x as f32 / window_size.y as f32;
@@ -11,14 +11,14 @@ use time;
 use yaglw::gl_context::GLContext;
 
 use common::communicate::ClientToServer;
-use common::entity::EntityId;
 use common::interval_timer::IntervalTimer;
 
+use client;
 use hud::make_hud;
 use process_event::process_event;
 use render::render;
 use view;
-use view_update::{ClientToView, apply_client_to_view};
+use view_update::{ClientToView, apply_client_to_view, interpolate_entities};
 
 pub const FRAMES_PER_SECOND: u64 = 30;
 
@@ -32,7 +32,7 @@ enum ViewIteration {
 
 #[allow(missing_docs)]
 pub fn view_thread<Recv0, Recv1, UpdateServer>(
-  player_id: EntityId,
+  client: &client::T,
   recv0: &mut Recv0,
   recv1: &mut Recv1,
   update_server: &mut UpdateServer,
@@ -138,7 +138,7 @@ pub fn view_thread<Recv0, Recv1, UpdateServer>(
               if has_focus {
                 process_event(
                   &sdl,
-                  player_id,
+                  client,
                   update_server,
                   &mut view,
                   &mut window,
@@ -168,6 +168,8 @@ pub fn view_thread<Recv0, Recv1, UpdateServer>(
 
         let renders = render_timer.update(time::precise_time_ns());
         if renders > 0 {
+          interpolate_entities(&mut view, client.player_id, time::precise_time_ns());
+
           stopwatch::time("render", || {
             render(&mut view);
             // swap buffers
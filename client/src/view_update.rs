@@ -1,30 +1,34 @@
 //! Define the updates passed from the client to the view.
 
-use cgmath::Point3;
+use cgmath::{Aabb3, Point3};
 use stopwatch;
 
 use common::block_position::BlockPosition;
-use common::color::Color3;
+use common::color::{Color3, Color4};
+use common::communicate::Snapshot;
 use common::entity::EntityId;
 use common::lod::LODIndex;
 use common::terrain_block::TerrainBlock;
 
+use interpolation;
 use light;
 use light::{set_sun, set_ambient_light};
-use mob_buffers::VERTICES_PER_MOB;
-use player_buffers::VERTICES_PER_PLAYER;
 use vertex::ColoredVertex;
 use view;
 
+const VERTICES_PER_BOX: usize = 36;
+
 /// Messages from the client to the view.
 pub enum ClientToView {
   /// Set the camera location.
   MoveCamera(Point3<f32>),
 
-  /// Update a player mesh.
-  UpdatePlayer(EntityId, [ColoredVertex; VERTICES_PER_PLAYER]),
-  /// Update a mob mesh.
-  UpdateMob(EntityId, [ColoredVertex; VERTICES_PER_MOB]),
+  /// Entity positions from the server, and the local time they arrived.
+  Snapshot(u64, Snapshot),
+  /// Remove a player mesh.
+  RemovePlayer(EntityId),
+  /// Remove a mob mesh.
+  RemoveMob(EntityId),
 
   /// Update the sun.
   SetSun(light::Sun),
@@ -51,11 +55,16 @@ pub fn apply_client_to_view(view: &mut view::T, up: ClientToView) {
     ClientToView::MoveCamera(position) => {
       view.camera.translate_to(position);
     },
-    ClientToView::UpdateMob(id, triangles) => {
-      view.mob_buffers.insert(&mut view.gl, id, &triangles);
+    ClientToView::Snapshot(received, snapshot) => {
+      view.entities.push(received, &snapshot);
+    },
+    ClientToView::RemovePlayer(id) => {
+      view.entities.remove(id);
+      view.player_buffers.swap_remove(&mut view.gl, id);
     },
-    ClientToView::UpdatePlayer(id, triangles) => {
-      view.player_buffers.insert(&mut view.gl, id, &triangles);
+    ClientToView::RemoveMob(id) => {
+      view.entities.remove(id);
+      view.mob_buffers.swap_remove(&mut view.gl, id);
     },
     ClientToView::SetSun(sun) => {
       set_sun(
@@ -98,3 +107,62 @@ pub fn apply_client_to_view(view: &mut view::T, up: ClientToView) {
     },
   };
 }
+
+/// Move entity meshes to where they are at local time `now`.
+/// Our own player, `player_id`, is predicted rather than interpolated, and isn't drawn.
+pub fn interpolate_entities(view: &mut view::T, player_id: EntityId, now: u64) {
+  for (id, kind, bounds) in view.entities.changes(now) {
+    if id == player_id {
+      continue
+    }
+
+    match kind {
+      interpolation::Kind::Player => {
+        let mesh = to_triangles(&bounds, &Color4::of_rgba(0.0, 0.0, 1.0, 1.0));
+        view.player_buffers.insert(&mut view.gl, id, &mesh);
+      },
+      interpolation::Kind::Mob => {
+        let mesh = to_triangles(&bounds, &Color4::of_rgba(1.0, 0.0, 0.0, 1.0));
+        view.mob_buffers.insert(&mut view.gl, id, &mesh);
+      },
+    }
+  }
+}
+
+fn to_triangles(
+  bounds: &Aabb3<f32>,
+  c: &Color4<f32>,
+) -> [ColoredVertex; VERTICES_PER_BOX] {
+  let (x1, y1, z1) = (bounds.min.x, bounds.min.y, bounds.min.z);
+  let (x2, y2, z2) = (bounds.max.x, bounds.max.y, bounds.max.z);
+
+  let vtx = |x, y, z| {
+    ColoredVertex {
+      position: Point3::new(x, y, z),
+      color: c.clone(),
+    }
+  };
+
+  // Remember: x increases to the right, y increases up, and z becomes more
+  // negative as depth from the viewer increases.
+  [
+    // front
+    vtx(x1, y1, z2), vtx(x2, y2, z2), vtx(x1, y2, z2),
+    vtx(x1, y1, z2), vtx(x2, y1, z2), vtx(x2, y2, z2),
+    // left
+    vtx(x1, y1, z1), vtx(x1, y2, z2), vtx(x1, y2, z1),
+    vtx(x1, y1, z1), vtx(x1, y1, z2), vtx(x1, y2, z2),
+    // top
+    vtx(x1, y2, z1), vtx(x2, y2, z2), vtx(x2, y2, z1),
+    vtx(x1, y2, z1), vtx(x1, y2, z2), vtx(x2, y2, z2),
+    // back
+    vtx(x1, y1, z1), vtx(x2, y2, z1), vtx(x2, y1, z1),
+    vtx(x1, y1, z1), vtx(x1, y2, z1), vtx(x2, y2, z1),
+    // right
+    vtx(x2, y1, z1), vtx(x2, y2, z2), vtx(x2, y1, z2),
+    vtx(x2, y1, z1), vtx(x2, y2, z1), vtx(x2, y2, z2),
+    // bottom
+    vtx(x1, y1, z1), vtx(x2, y1, z2), vtx(x1, y1, z2),
+    vtx(x1, y1, z1), vtx(x2, y1, z1), vtx(x2, y1, z2),
+  ]
+}
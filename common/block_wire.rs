@@ -0,0 +1,383 @@
+//! A compact encoding of `TerrainBlock`s, for sending them from the server to clients.
+//!
+//! Vertex positions are quantized relative to the block they're in, normals are octahedral-encoded
+//! into two bytes, materials are bytes, triangle ids are run-length encoded, and the per-triangle
+//! bounds (which only the server uses) are dropped. Blocks updated by a brush can be sent as the
+//! difference from the version the client already has.
+
+use bincode::SizeLimit;
+use bincode::rustc_serialize::{encode, decode};
+use cgmath::{Point3, Vector3, EuclideanVector};
+use std::collections::HashSet;
+use std::hash::Hasher;
+
+use block_position::BlockPosition;
+use entity::EntityId;
+use lz;
+use terrain_block;
+use terrain_block::{TerrainBlock, Triangle, tri};
+
+#[cfg(test)]
+use cgmath::{Point, Vector};
+
+/// Vertices can stray up to a block width outside their block, so positions are quantized across
+/// three block widths, starting one block width below the block's low corner.
+const POSITION_SPAN: f32 = 3.0 * terrain_block::WIDTH as f32;
+const POSITION_STEPS: f32 = 65535.0;
+
+/// A vertex position, relative to its block.
+pub type QuantizedPoint = (u16, u16, u16);
+/// An octahedral-encoded unit vector.
+pub type OctahedralNormal = (i8, i8);
+
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+/// A list of triangles, each field ordered the same way.
+pub struct Triangles {
+  #[allow(missing_docs)]
+  pub vertices: Vec<Triangle<QuantizedPoint>>,
+  #[allow(missing_docs)]
+  pub normals: Vec<Triangle<OctahedralNormal>>,
+  #[allow(missing_docs)]
+  pub materials: Vec<u8>,
+  /// Runs of consecutive triangle ids, as (first id, length).
+  pub ids: Vec<(EntityId, u32)>,
+}
+
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+/// The changes from one version of a block to the next.
+pub struct Delta {
+  /// The `version` of the block this applies to.
+  pub base: u64,
+  /// Runs of the ids of the triangles that are gone, as (first id, length).
+  pub removed: Vec<(EntityId, u32)>,
+  /// The triangles that are new.
+  pub added: Triangles,
+}
+
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+#[allow(missing_docs)]
+pub enum Contents {
+  Full(Triangles),
+  Delta(Delta),
+}
+
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+/// A block's contents as sent, possibly compressed.
+pub enum T {
+  #[allow(missing_docs)]
+  Raw(Contents),
+  /// The bincoded `Contents`, compressed with `lz`.
+  Compressed(Vec<u8>),
+}
+
+// FNV-1a, which hashes the same way in every build, unlike the standard library's hashers.
+struct Fnv(u64);
+
+impl Hasher for Fnv {
+  fn finish(&self) -> u64 {
+    self.0
+  }
+
+  fn write(&mut self, bytes: &[u8]) {
+    for &b in bytes {
+      self.0 = (self.0 ^ b as u64).wrapping_mul(0x100000001b3);
+    }
+  }
+}
+
+/// Identify a version of a block. Every regeneration of a triangle gets a fresh id,
+/// so different versions of a block have different ids.
+pub fn version(block: &TerrainBlock) -> u64 {
+  let mut hasher = Fnv(0xcbf29ce484222325);
+  // `Hash` writes native-endian, platform-sized numbers, so feed in fixed-width little-endian ones instead.
+  let len = block.ids.len() as u64;
+  hasher.write(&[
+    len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8,
+    (len >> 32) as u8, (len >> 40) as u8, (len >> 48) as u8, (len >> 56) as u8,
+  ]);
+  for id in &block.ids {
+    let id = id.as_u32();
+    hasher.write(&[id as u8, (id >> 8) as u8, (id >> 16) as u8, (id >> 24) as u8]);
+  }
+  hasher.finish()
+}
+
+fn quantize_coordinate(x: f32, low: f32) -> u16 {
+  let t = (x - low + terrain_block::WIDTH as f32) / POSITION_SPAN;
+  let t = f32::max(0.0, f32::min(1.0, t));
+  (t * POSITION_STEPS).round() as u16
+}
+
+fn dequantize_coordinate(x: u16, low: f32) -> f32 {
+  x as f32 / POSITION_STEPS * POSITION_SPAN + low - terrain_block::WIDTH as f32
+}
+
+/// Quantize a position relative to the low corner of its block.
+pub fn quantize_position(block_low: &Point3<f32>, p: &Point3<f32>) -> QuantizedPoint {
+  (
+    quantize_coordinate(p.x, block_low.x),
+    quantize_coordinate(p.y, block_low.y),
+    quantize_coordinate(p.z, block_low.z),
+  )
+}
+
+#[allow(missing_docs)]
+pub fn dequantize_position(block_low: &Point3<f32>, p: &QuantizedPoint) -> Point3<f32> {
+  Point3::new(
+    dequantize_coordinate(p.0, block_low.x),
+    dequantize_coordinate(p.1, block_low.y),
+    dequantize_coordinate(p.2, block_low.z),
+  )
+}
+
+// Fold the lower hemisphere of the octahedron over the upper one.
+fn fold(u: f32, v: f32) -> (f32, f32) {
+  ((1.0 - v.abs()) * u.signum(), (1.0 - u.abs()) * v.signum())
+}
+
+/// Map a unit vector onto the octahedron |x| + |y| + |z| = 1, unfolded into a square.
+pub fn encode_normal(n: &Vector3<f32>) -> OctahedralNormal {
+  let l1 = n.x.abs() + n.y.abs() + n.z.abs();
+  if l1 == 0.0 {
+    return (0, 0)
+  }
+  let (mut u, mut v) = (n.x / l1, n.y / l1);
+  if n.z < 0.0 {
+    let (fu, fv) = fold(u, v);
+    u = fu;
+    v = fv;
+  }
+  ((u * 127.0).round() as i8, (v * 127.0).round() as i8)
+}
+
+#[allow(missing_docs)]
+pub fn decode_normal(n: &OctahedralNormal) -> Vector3<f32> {
+  let (mut u, mut v) = (n.0 as f32 / 127.0, n.1 as f32 / 127.0);
+  let z = 1.0 - u.abs() - v.abs();
+  if z < 0.0 {
+    let (fu, fv) = fold(u, v);
+    u = fu;
+    v = fv;
+  }
+  Vector3::new(u, v, z).normalize()
+}
+
+fn id_runs<It>(ids: It) -> Vec<(EntityId, u32)> where It: Iterator<Item=EntityId> {
+  let mut runs: Vec<(EntityId, u32)> = Vec::new();
+  for id in ids {
+    let extends_run =
+      match runs.last() {
+        None => false,
+        Some(&(first, len)) => first + len == id,
+      };
+    if extends_run {
+      runs.last_mut().unwrap().1 += 1;
+    } else {
+      runs.push((id, 1));
+    }
+  }
+  runs
+}
+
+fn expand_runs(runs: &[(EntityId, u32)]) -> Vec<EntityId> {
+  let mut ids = Vec::new();
+  for &(first, len) in runs {
+    for i in 0 .. len {
+      ids.push(first + i);
+    }
+  }
+  ids
+}
+
+// Encode the triangles of `block` at the given indices.
+fn encode_triangles<It>(position: &BlockPosition, block: &TerrainBlock, indices: It) -> Triangles
+  where It: Iterator<Item=usize>,
+{
+  let low = position.to_world_position();
+  let mut triangles =
+    Triangles {
+      vertices: Vec::new(),
+      normals: Vec::new(),
+      materials: Vec::new(),
+      ids: Vec::new(),
+    };
+  let mut ids = Vec::new();
+  for i in indices {
+    let v = &block.vertex_coordinates[i];
+    triangles.vertices.push(
+      tri(quantize_position(&low, &v.v1), quantize_position(&low, &v.v2), quantize_position(&low, &v.v3))
+    );
+    let n = &block.normals[i];
+    triangles.normals.push(tri(encode_normal(&n.v1), encode_normal(&n.v2), encode_normal(&n.v3)));
+    triangles.materials.push(block.materials[i] as u8);
+    ids.push(block.ids[i]);
+  }
+  triangles.ids = id_runs(ids.into_iter());
+  triangles
+}
+
+/// Encode a whole block.
+pub fn full(position: &BlockPosition, block: &TerrainBlock) -> Contents {
+  Contents::Full(encode_triangles(position, block, 0 .. block.ids.len()))
+}
+
+/// Encode the changes from `old` to `new`, or the whole of `new` if that would be smaller.
+/// Triangles that didn't change should keep their ids from `old`.
+pub fn diff(position: &BlockPosition, old: &TerrainBlock, new: &TerrainBlock) -> Contents {
+  let old_ids: HashSet<EntityId> = old.ids.iter().cloned().collect();
+  let new_ids: HashSet<EntityId> = new.ids.iter().cloned().collect();
+
+  let removed: Vec<EntityId> = old.ids.iter().cloned().filter(|id| !new_ids.contains(id)).collect();
+  let added: Vec<usize> = (0 .. new.ids.len()).filter(|&i| !old_ids.contains(&new.ids[i])).collect();
+
+  if removed.len() + added.len() >= new.ids.len() {
+    return full(position, new)
+  }
+
+  Contents::Delta(
+    Delta {
+      base: version(old),
+      removed: id_runs(removed.into_iter()),
+      added: encode_triangles(position, new, added.into_iter()),
+    }
+  )
+}
+
+/// Decode triangles into a block, without any bounds.
+pub fn to_block(position: &BlockPosition, triangles: &Triangles) -> TerrainBlock {
+  let low = position.to_world_position();
+  let mut block = TerrainBlock::empty();
+  for v in &triangles.vertices {
+    block.vertex_coordinates.push(
+      tri(dequantize_position(&low, &v.v1), dequantize_position(&low, &v.v2), dequantize_position(&low, &v.v3))
+    );
+  }
+  for n in &triangles.normals {
+    block.normals.push(tri(decode_normal(&n.v1), decode_normal(&n.v2), decode_normal(&n.v3)));
+  }
+  block.materials = triangles.materials.iter().map(|&m| m as i32).collect();
+  block.ids = expand_runs(&triangles.ids);
+  block
+}
+
+/// Apply a delta to the block it was made from.
+/// Returns the new block and the ids of the triangles that were removed, or None if `base` isn't
+/// the version of the block the delta was made from.
+pub fn apply(
+  position: &BlockPosition,
+  base: &TerrainBlock,
+  delta: &Delta,
+) -> Option<(TerrainBlock, Vec<EntityId>)> {
+  if version(base) != delta.base {
+    return None
+  }
+
+  let removed = expand_runs(&delta.removed);
+  let removed_set: HashSet<EntityId> = removed.iter().cloned().collect();
+
+  let mut block = TerrainBlock::empty();
+  for i in 0 .. base.ids.len() {
+    if removed_set.contains(&base.ids[i]) {
+      continue
+    }
+    block.vertex_coordinates.push(base.vertex_coordinates[i]);
+    block.normals.push(base.normals[i]);
+    block.materials.push(base.materials[i]);
+    block.ids.push(base.ids[i]);
+  }
+
+  let added = to_block(position, &delta.added);
+  block.vertex_coordinates.extend(added.vertex_coordinates.into_iter());
+  block.normals.extend(added.normals.into_iter());
+  block.materials.extend(added.materials.into_iter());
+  block.ids.extend(added.ids.into_iter());
+
+  Some((block, removed))
+}
+
+/// Package contents for sending, compressing them if that's allowed and makes them smaller.
+pub fn pack(contents: Contents, compress: bool) -> T {
+  if compress {
+    let bytes = encode(&contents, SizeLimit::Infinite).unwrap();
+    let compressed = lz::compress(bytes.as_ref());
+    if compressed.len() < bytes.len() {
+      return T::Compressed(compressed)
+    }
+  }
+  T::Raw(contents)
+}
+
+/// Get the contents back out of a package, or None if it's corrupt.
+pub fn unpack(packed: T) -> Option<Contents> {
+  match packed {
+    T::Raw(contents) => Some(contents),
+    T::Compressed(bytes) => {
+      lz::decompress(bytes.as_ref())
+        .and_then(|bytes| decode(bytes.as_ref()).ok())
+    },
+  }
+}
+
+#[cfg(test)]
+fn block_of(ids: &[u32]) -> TerrainBlock {
+  let mut block = TerrainBlock::empty();
+  for &id in ids {
+    let p = Point3::new(id as f32 / 4.0, 1.0, 2.0);
+    block.vertex_coordinates.push(tri(p, p.add_v(&Vector3::new(1.0, 0.0, 0.0)), p));
+    block.normals.push(tri(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 0.0, -1.0), Vector3::new(1.0, 0.0, 0.0)));
+    block.materials.push(1);
+    block.ids.push(EntityId::default() + id);
+  }
+  block
+}
+
+#[test]
+fn positions_round_trip() {
+  let low = Point3::new(-8.0, 16.0, 0.0);
+  for &p in &[
+    Point3::new(-8.0, 16.0, 0.0),
+    Point3::new(-4.3, 19.9, 7.99),
+    Point3::new(-15.5, 8.5, 15.5),
+  ] {
+    let q = dequantize_position(&low, &quantize_position(&low, &p));
+    assert!(q.sub_p(&p).length() < 0.001, "{:?} became {:?}", p, q);
+  }
+}
+
+#[test]
+fn normals_round_trip() {
+  for &n in &[
+    Vector3::new(0.0, 1.0, 0.0),
+    Vector3::new(0.0, 0.0, -1.0),
+    Vector3::new(1.0, -1.0, -1.0).normalize(),
+    Vector3::new(-0.2, 0.3, 0.9).normalize(),
+  ] {
+    let m = decode_normal(&encode_normal(&n));
+    assert!(m.sub_v(&n).length() < 0.02, "{:?} became {:?}", n, m);
+  }
+}
+
+#[test]
+fn delta_round_trip() {
+  let position = BlockPosition::new(0, 0, 0);
+  let old = block_of(&[1, 2, 3, 4, 5, 6, 7, 8]);
+  let new = block_of(&[1, 2, 3, 5, 6, 7, 8, 20]);
+
+  let delta =
+    match unpack(pack(diff(&position, &old, &new), true)) {
+      Some(Contents::Delta(delta)) => delta,
+      contents => panic!("Expected a delta, got {:?}", contents),
+    };
+  assert!(apply(&position, &new, &delta).is_none());
+
+  let (block, removed) = apply(&position, &old, &delta).unwrap();
+  assert_eq!(removed, vec!(EntityId::default() + 4));
+  assert_eq!(block.ids, new.ids);
+  assert_eq!(version(&block), version(&new));
+}
+
+#[test]
+fn versions_are_the_same_everywhere() {
+  // FNV-1a of the little-endian u64 length 2, then the little-endian u32 ids 1 and 256.
+  assert_eq!(version(&block_of(&[1, 256])), 0x0fcaff4081224971);
+}
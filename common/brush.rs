@@ -0,0 +1,90 @@
+//! The brushes players edit the terrain with.
+
+/// The smallest brush size.
+pub const MIN_SIZE: f32 = 1.0;
+/// The largest brush size.
+pub const MAX_SIZE: f32 = 16.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+#[allow(missing_docs)]
+pub enum Shape {
+  Sphere,
+  Cube,
+  /// Upright, as tall as it is wide.
+  Cylinder,
+}
+
+impl Shape {
+  /// The shape after this one in the palette.
+  pub fn next(self) -> Shape {
+    match self {
+      Shape::Sphere => Shape::Cube,
+      Shape::Cube => Shape::Cylinder,
+      Shape::Cylinder => Shape::Sphere,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+/// What a brush fills its shape with. Brushing with `Empty` removes terrain.
+#[allow(missing_docs)]
+pub enum Material {
+  Empty,
+  Terrain,
+  Bark,
+  Leaves,
+  Stone,
+}
+
+impl Material {
+  /// The placeable material after this one in the palette.
+  pub fn next(self) -> Material {
+    match self {
+      Material::Empty => Material::Terrain,
+      Material::Terrain => Material::Bark,
+      Material::Bark => Material::Leaves,
+      Material::Leaves => Material::Stone,
+      Material::Stone => Material::Terrain,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, RustcEncodable, RustcDecodable)]
+/// A brush stroke's shape, size and material.
+pub struct T {
+  #[allow(missing_docs)]
+  pub shape: Shape,
+  /// The radius of the shape, or half its width.
+  pub size: f32,
+  #[allow(missing_docs)]
+  pub material: Material,
+}
+
+impl T {
+  /// The same brush, but resized by `ds`, within [MIN_SIZE, MAX_SIZE].
+  pub fn resize(self, ds: f32) -> T {
+    T {
+      size: (self.size + ds).max(MIN_SIZE).min(MAX_SIZE),
+      .. self
+    }
+  }
+
+  /// The same brush, but erasing instead of placing.
+  pub fn eraser(self) -> T {
+    T {
+      material: Material::Empty,
+      .. self
+    }
+  }
+}
+
+#[test]
+fn the_palette_never_picks_empty() {
+  for &start in &[Material::Empty, Material::Terrain, Material::Bark, Material::Leaves, Material::Stone] {
+    let mut material = start;
+    for _ in 0..10 {
+      material = material.next();
+      assert!(material != Material::Empty, "{:?} cycled to Empty", start);
+    }
+  }
+}
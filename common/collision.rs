@@ -0,0 +1,374 @@
+//! Swept AABB collision: how far a box can move before it runs into another one.
+
+use cgmath::{Aabb3, EuclideanVector, Point, Point3, Vector, Vector3};
+use std::f32;
+
+use terrain_block;
+use terrain_block::Triangle;
+
+#[derive(Debug, Clone)]
+/// The first contact between a moving box and an obstacle.
+pub struct Impact {
+  /// The fraction of the movement completed at the moment of contact, in [0, 1].
+  pub toi: f32,
+  /// The unit normal of the obstacle's face that was hit, pointing out of the obstacle.
+  pub normal: Vector3<f32>,
+  /// The bounds of the obstacle that was hit.
+  pub obstacle: Aabb3<f32>,
+}
+
+fn get(p: &Point3<f32>, axis: usize) -> f32 {
+  match axis {
+    0 => p.x,
+    1 => p.y,
+    _ => p.z,
+  }
+}
+
+fn get_v(v: &Vector3<f32>, axis: usize) -> f32 {
+  match axis {
+    0 => v.x,
+    1 => v.y,
+    _ => v.z,
+  }
+}
+
+fn set(p: &mut Point3<f32>, axis: usize, x: f32) {
+  match axis {
+    0 => p.x = x,
+    1 => p.y = x,
+    _ => p.z = x,
+  }
+}
+
+fn unit(axis: usize, sign: f32) -> Vector3<f32> {
+  match axis {
+    0 => Vector3::new(sign, 0.0, 0.0),
+    1 => Vector3::new(0.0, sign, 0.0),
+    _ => Vector3::new(0.0, 0.0, sign),
+  }
+}
+
+/// `bounds`, moved by `v`.
+pub fn translate(bounds: &Aabb3<f32>, v: &Vector3<f32>) -> Aabb3<f32> {
+  Aabb3::new(bounds.min.add_v(v), bounds.max.add_v(v))
+}
+
+/// The bounds of everything `bounds` passes through while moving by `v`.
+pub fn swept_bounds(bounds: &Aabb3<f32>, v: &Vector3<f32>) -> Aabb3<f32> {
+  let moved = translate(bounds, v);
+  Aabb3::new(
+    Point3::new(
+      bounds.min.x.min(moved.min.x),
+      bounds.min.y.min(moved.min.y),
+      bounds.min.z.min(moved.min.z),
+    ),
+    Point3::new(
+      bounds.max.x.max(moved.max.x),
+      bounds.max.y.max(moved.max.y),
+      bounds.max.z.max(moved.max.z),
+    ),
+  )
+}
+
+// How far two things can overlap and still count as touching, so that rounding doesn't let things
+// sink into each other.
+const CONTACT_TOLERANCE: f32 = 0.001;
+
+// The moving box and an obstacle projected onto a candidate separating axis.
+struct Projection {
+  axis: Vector3<f32>,
+  min: f32,
+  max: f32,
+  obstacle_min: f32,
+  obstacle_max: f32,
+  // The box's movement along the axis.
+  speed: f32,
+}
+
+impl Projection {
+  fn new(axis: Vector3<f32>, bounds: &Aabb3<f32>, v: &Vector3<f32>, obstacle: &[Point3<f32>]) -> Projection {
+    let center = bounds.min.add_v(&bounds.max.to_vec()).mul_s(0.5).to_vec();
+    let half_extents = bounds.max.sub_p(&bounds.min).mul_s(0.5);
+    let radius =
+      half_extents.x * axis.x.abs() + half_extents.y * axis.y.abs() + half_extents.z * axis.z.abs();
+    let c = center.dot(&axis);
+
+    let mut obstacle_min = f32::INFINITY;
+    let mut obstacle_max = f32::NEG_INFINITY;
+    for p in obstacle {
+      let x = p.to_vec().dot(&axis);
+      obstacle_min = obstacle_min.min(x);
+      obstacle_max = obstacle_max.max(x);
+    }
+
+    Projection {
+      axis: axis,
+      min: c - radius,
+      max: c + radius,
+      obstacle_min: obstacle_min,
+      obstacle_max: obstacle_max,
+      speed: v.dot(&axis),
+    }
+  }
+}
+
+// Sweep along every candidate separating axis; the box hits the obstacle when it's no longer separated along any of them.
+fn sweep_projections(projections: &[Projection], obstacle: &Aabb3<f32>) -> Option<Impact> {
+  let mut entry = f32::NEG_INFINITY;
+  let mut exit = f32::INFINITY;
+  let mut normal = Vector3::new(0.0, 0.0, 0.0);
+  // In case they're already overlapping: the axis they overlap least along, which way is out, and how fast
+  // the box is moving out.
+  let mut shallowest: Option<(f32, Vector3<f32>, f32)> = None;
+
+  for p in projections {
+    let (depth, out, speed_out) =
+      if p.max - p.obstacle_min < p.obstacle_max - p.min {
+        (p.max - p.obstacle_min, -p.axis, -p.speed)
+      } else {
+        (p.obstacle_max - p.min, p.axis, p.speed)
+      };
+    if shallowest.map_or(true, |(d, _, _)| depth < d) {
+      shallowest = Some((depth, out, speed_out));
+    }
+
+    let (axis_entry, axis_exit) =
+      if p.speed > 0.0 {
+        ((p.obstacle_min - p.max) / p.speed, (p.obstacle_max - p.min) / p.speed)
+      } else if p.speed < 0.0 {
+        ((p.obstacle_max - p.min) / p.speed, (p.obstacle_min - p.max) / p.speed)
+      } else if depth > 0.0 {
+        // Not moving along this axis, but always overlapping on it.
+        continue
+      } else {
+        return None
+      };
+
+    if axis_entry > entry {
+      entry = axis_entry;
+      normal = if p.speed > 0.0 { -p.axis } else { p.axis };
+    }
+    exit = exit.min(axis_exit);
+  }
+
+  if entry < 0.0 {
+    if exit <= 0.0 {
+      return None
+    }
+
+    // They're already overlapping. Deep overlaps are ignored so that things stuck inside each other
+    // can get out; shallow ones are contacts, as long as the box is moving further in.
+    return match shallowest {
+      Some((depth, out, speed_out)) if depth <= CONTACT_TOLERANCE && speed_out < 0.0 =>
+        Some(Impact {
+          toi: 0.0,
+          normal: out,
+          obstacle: obstacle.clone(),
+        }),
+      _ => None,
+    }
+  }
+
+  if entry > 1.0 || entry >= exit {
+    return None
+  }
+
+  Some(Impact {
+    toi: entry,
+    normal: normal,
+    obstacle: obstacle.clone(),
+  })
+}
+
+fn box_corners(bounds: &Aabb3<f32>) -> [Point3<f32>; 2] {
+  [bounds.min, bounds.max]
+}
+
+/// Find when `bounds` would first touch `obstacle` while moving by `v`.
+/// Boxes that are already overlapping don't collide, so that things stuck inside each other can get out.
+/// Boxes that are only touching collide if they're moving into each other.
+pub fn sweep(bounds: &Aabb3<f32>, v: &Vector3<f32>, obstacle: &Aabb3<f32>) -> Option<Impact> {
+  let corners = box_corners(obstacle);
+  let projections: Vec<_> =
+    (0 .. 3)
+    .map(|axis| Projection::new(unit(axis, 1.0), bounds, v, &corners))
+    .collect();
+  sweep_projections(&projections, obstacle)
+}
+
+/// Find when `bounds` would first touch `triangle` while moving by `v`, in the same way as `sweep`.
+pub fn sweep_triangle(bounds: &Aabb3<f32>, v: &Vector3<f32>, triangle: &Triangle<Point3<f32>>) -> Option<Impact> {
+  let vertices = [triangle.v1, triangle.v2, triangle.v3];
+  let edges = [
+    triangle.v2.sub_p(&triangle.v1),
+    triangle.v3.sub_p(&triangle.v2),
+    triangle.v1.sub_p(&triangle.v3),
+  ];
+
+  // The box's faces, the triangle's face, and every pair of box and triangle edges.
+  let mut axes = vec!(unit(0, 1.0), unit(1, 1.0), unit(2, 1.0), edges[0].cross(&edges[1]));
+  for axis in 0 .. 3 {
+    for edge in &edges {
+      axes.push(unit(axis, 1.0).cross(edge));
+    }
+  }
+
+  let projections: Vec<_> =
+    axes.into_iter()
+    // Parallel edges don't give an axis.
+    .filter(|axis| axis.length2() > 1e-12)
+    .map(|axis| Projection::new(axis.normalize(), bounds, v, &vertices))
+    .collect();
+  sweep_projections(&projections, &terrain_block::make_bounds(&triangle.v1, &triangle.v2, &triangle.v3))
+}
+
+/// Whichever impact happens first.
+pub fn earliest(i1: Option<Impact>, i2: Option<Impact>) -> Option<Impact> {
+  match (i1, i2) {
+    (None, i) => i,
+    (i, None) => i,
+    (Some(i1), Some(i2)) => Some(if i2.toi < i1.toi { i2 } else { i1 }),
+  }
+}
+
+/// Where `bounds` ends up when moving by `v` stops at `impact`. When an axis-aligned face was hit, it's placed
+/// exactly against the obstacle, so rounding can't leave the two overlapping or separated.
+pub fn contact(bounds: &Aabb3<f32>, v: &Vector3<f32>, impact: &Impact) -> Aabb3<f32> {
+  let mut moved = translate(bounds, &v.mul_s(impact.toi));
+  let aligned = (0 .. 3).filter(|&axis| get_v(&impact.normal, axis) != 0.0).count() == 1;
+  if !aligned {
+    return moved
+  }
+
+  for axis in 0 .. 3 {
+    let n = get_v(&impact.normal, axis);
+    let size = get(&bounds.max, axis) - get(&bounds.min, axis);
+    if n > 0.0 {
+      let face = get(&impact.obstacle.max, axis);
+      set(&mut moved.min, axis, face);
+      set(&mut moved.max, axis, face + size);
+    } else if n < 0.0 {
+      let face = get(&impact.obstacle.min, axis);
+      set(&mut moved.min, axis, face - size);
+      set(&mut moved.max, axis, face);
+    }
+  }
+  moved
+}
+
+/// Remove the part of `v` heading into a surface with normal `normal`, leaving the part that slides along it.
+pub fn slide(v: &Vector3<f32>, normal: &Vector3<f32>) -> Vector3<f32> {
+  let into = v.dot(normal);
+  if into < 0.0 {
+    v.sub_v(&normal.mul_s(into))
+  } else {
+    *v
+  }
+}
+
+/// The most collisions handled in one move. Sliding along one surface can run into another, and surfaces at an angle
+/// to each other can keep redirecting the motion without ever stopping it, so the rest of the move is dropped after
+/// this many.
+pub const MAX_SLIDES: u32 = 3;
+
+/// Move `bounds` by `v`, sliding along whatever it runs into, and return where it ends up.
+/// `sweep` finds the first impact when moving some bounds by some vector. `hit` is told about each impact, along
+/// with the bounds in contact with the obstacle and the motion that's left; it can return other bounds to carry on
+/// from with that motion (e.g. to step up onto the obstacle) instead of sliding.
+pub fn move_sliding<Sweep, Hit>(bounds: &Aabb3<f32>, v: &Vector3<f32>, mut sweep: Sweep, mut hit: Hit) -> Aabb3<f32>
+  where
+    Sweep: FnMut(&Aabb3<f32>, &Vector3<f32>) -> Option<Impact>,
+    Hit: FnMut(&Impact, &Aabb3<f32>, &Vector3<f32>) -> Option<Aabb3<f32>>,
+{
+  let mut bounds = bounds.clone();
+  let mut v = *v;
+  for _ in 0 .. MAX_SLIDES {
+    let impact =
+      match sweep(&bounds, &v) {
+        None => return translate(&bounds, &v),
+        Some(impact) => impact,
+      };
+
+    bounds = contact(&bounds, &v, &impact);
+    let rest = v.mul_s(1.0 - impact.toi);
+    match hit(&impact, &bounds, &rest) {
+      None => v = slide(&rest, &impact.normal),
+      Some(elsewhere) => {
+        bounds = elsewhere;
+        v = rest;
+      },
+    }
+  }
+  bounds
+}
+
+#[cfg(test)]
+fn unit_box(x: f32, y: f32, z: f32) -> Aabb3<f32> {
+  Aabb3::new(Point3::new(x, y, z), Point3::new(x + 1.0, y + 1.0, z + 1.0))
+}
+
+#[test]
+fn finds_time_and_normal_of_impact() {
+  let impact = sweep(&unit_box(0.0, 2.0, 0.0), &Vector3::new(0.0, -4.0, 0.0), &unit_box(0.0, 0.0, 0.0)).unwrap();
+  assert_eq!(impact.toi, 0.25);
+  assert_eq!(impact.normal, Vector3::new(0.0, 1.0, 0.0));
+
+  let landed = contact(&unit_box(0.0, 2.0, 0.0), &Vector3::new(0.0, -4.0, 0.0), &impact);
+  assert_eq!(landed.min.y, 1.0);
+}
+
+#[test]
+fn fast_boxes_do_not_tunnel() {
+  let thin = Aabb3::new(Point3::new(-10.0, 0.0, -10.0), Point3::new(10.0, 0.01, 10.0));
+  let impact = sweep(&unit_box(0.0, 50.0, 0.0), &Vector3::new(0.0, -100.0, 0.0), &thin);
+  assert!(impact.is_some());
+}
+
+#[test]
+fn resting_boxes_slide_freely() {
+  let floor = unit_box(0.0, 0.0, 0.0);
+  let resting = unit_box(0.0, 1.0, 0.0);
+  assert!(sweep(&resting, &Vector3::new(0.5, 0.0, 0.0), &floor).is_none());
+  assert!(sweep(&resting, &Vector3::new(0.0, -0.5, 0.0), &floor).is_some());
+  assert_eq!(slide(&Vector3::new(0.5, -0.5, 0.0), &Vector3::new(0.0, 1.0, 0.0)), Vector3::new(0.5, 0.0, 0.0));
+}
+
+#[test]
+fn lands_on_slopes_without_hovering() {
+  // A 45 degree slope along x, rising to the right.
+  let triangle =
+    terrain_block::tri(
+      Point3::new(0.0, 0.0, -10.0),
+      Point3::new(10.0, 10.0, -10.0),
+      Point3::new(0.0, 0.0, 10.0),
+    );
+  let falling = unit_box(4.0, 10.0, 0.0);
+  let impact = sweep_triangle(&falling, &Vector3::new(0.0, -10.0, 0.0), &triangle).unwrap();
+  // The box's low corner at x = 5 touches the slope at y = 5.
+  assert!((impact.toi - 0.5).abs() < 0.0001);
+  assert!(impact.normal.y > 0.0 && impact.normal.x < 0.0);
+
+  // Well clear of the slope, nothing is hit, even inside the triangle's bounds.
+  assert!(sweep_triangle(&unit_box(1.0, 8.0, 0.0), &Vector3::new(0.5, 0.0, 0.0), &triangle).is_none());
+}
+
+#[test]
+fn rays_are_swept_points() {
+  let triangle =
+    terrain_block::tri(
+      Point3::new(0.0, 0.0, 0.0),
+      Point3::new(0.0, 0.0, 4.0),
+      Point3::new(4.0, 0.0, 0.0),
+    );
+  let origin = Point3::new(1.0, 2.0, 1.0);
+  let point = Aabb3::new(origin, origin);
+  let impact = sweep_triangle(&point, &Vector3::new(0.0, -4.0, 0.0), &triangle).unwrap();
+  assert_eq!(impact.toi, 0.5);
+  assert_eq!(impact.normal, Vector3::new(0.0, 1.0, 0.0));
+
+  // Passing beside the triangle's hypotenuse misses it.
+  let origin = Point3::new(3.0, 2.0, 3.0);
+  let point = Aabb3::new(origin, origin);
+  assert!(sweep_triangle(&point, &Vector3::new(0.0, -4.0, 0.0), &triangle).is_none());
+}
@@ -1,13 +1,33 @@
 //! Defines the messages passed between client and server.
 
-use cgmath::{Aabb3, Vector2, Vector3, Point3};
+use cgmath::{Aabb3, Point3};
 use std::default::Default;
 use std::ops::Add;
 
 use block_position::BlockPosition;
+use block_wire;
+use brush;
 use entity::EntityId;
 use lod::LODIndex;
-use terrain_block::TerrainBlock;
+use movement;
+
+/// Bump this whenever the encoding of any message changes.
+pub const PROTOCOL_VERSION: u32 = 11;
+
+/// Terrain blocks may be sent `block_wire::T::Compressed`.
+pub const LZ_BLOCKS: &'static str = "lz-blocks";
+
+/// Optional protocol features this build supports.
+/// The features used by a connection are the ones both sides support.
+pub const CAPABILITIES: &'static [&'static str] = &[LZ_BLOCKS];
+
+/// The capabilities in `theirs` that this build also supports.
+pub fn negotiate_capabilities(theirs: &[String]) -> Vec<String> {
+  theirs.iter()
+    .filter(|c| CAPABILITIES.iter().any(|ours| ours == c))
+    .cloned()
+    .collect()
+}
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, RustcEncodable, RustcDecodable)]
 /// Unique client ID.
@@ -28,40 +48,72 @@ impl Add<u32> for ClientId {
   }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, RustcEncodable, RustcDecodable)]
+/// A secret issued to each client when it connects, so the server can tell that a message
+/// claiming to be from a client really is.
+pub struct SessionToken(pub u64);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+/// Identifies the client sending a message.
+pub struct Session {
+  #[allow(missing_docs)]
+  pub client_id: ClientId,
+  #[allow(missing_docs)]
+  pub token: SessionToken,
+}
+
 #[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
-/// TerrainBlock plus identifying info, e.g. for transmission between server and client.
+/// A TerrainBlock in its wire format, plus identifying info.
 pub struct TerrainBlockSend {
   #[allow(missing_docs)]
   pub position: BlockPosition,
   #[allow(missing_docs)]
-  pub block: TerrainBlock,
+  pub block: block_wire::T,
   #[allow(missing_docs)]
   pub lod: LODIndex,
 }
 
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+/// The positions of entities at the end of a server tick.
+pub struct Snapshot {
+  /// Ticks are numbered consecutively. Entities that aren't in a snapshot didn't move that tick.
+  pub tick: u64,
+  /// The nominal length of a tick, in nanoseconds.
+  pub tick_ns: u64,
+  #[allow(missing_docs)]
+  pub players: Vec<(EntityId, Aabb3<f32>)>,
+  #[allow(missing_docs)]
+  pub mobs: Vec<(EntityId, Aabb3<f32>)>,
+}
+
 #[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
 /// Messages the client sends to the server.
 pub enum ClientToServer {
   /// Notify the server that the client exists, and provide a "return address".
-  Init(String),
+  /// Also provides the client's protocol version and capabilities.
+  /// This must stay the first variant, and its first two fields must never change,
+  /// so that servers can tell any client that it's incompatible.
+  Init(u32, String, Vec<String>),
   /// Ping
-  Ping(ClientId),
-  /// Ask the server to create a new player.
-  AddPlayer(ClientId),
-  /// Add a vector the player's acceleration.
-  Walk(EntityId, Vector3<f32>),
-  /// Rotate the player by some amount.
-  RotatePlayer(EntityId, Vector2<f32>),
-  /// [Try to] start a jump for the player.
-  StartJump(EntityId),
-  /// [Try to] stop a jump for the player.
-  StopJump(EntityId),
+  Ping(Session),
+  /// Ask the server to create a new player with the given name and password.
+  /// The password is only checked for names the server has one for.
+  AddPlayer(Session, String, String),
+  /// Move the player. Each client numbers its inputs consecutively from 1, so that the server can
+  /// tell it which ones its player state includes.
+  Input(Session, EntityId, u32, movement::Input),
   /// Ask the server to send a block of terrain.
-  RequestBlock(ClientId, BlockPosition, LODIndex),
-  /// Brush-remove where the player's looking.
-  Add(EntityId),
-  /// Brush-add at where the player's looking.
-  Remove(EntityId),
+  RequestBlock(Session, BlockPosition, LODIndex),
+  /// Plant a tree where the player's looking.
+  PlantTree(Session, EntityId),
+  /// Apply a brush where the player's looking.
+  Brush(Session, EntityId, brush::T),
+  /// Undo the player's last terrain edit.
+  Undo(Session, EntityId),
+  /// Redo the player's last undone terrain edit.
+  Redo(Session, EntityId),
+  /// The client is leaving; release everything it owns.
+  Disconnect(Session),
 }
 
 /// Why a block is being sent to a client.
@@ -76,22 +128,35 @@ pub enum BlockReason {
 #[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
 /// Messages the server sends to the client.
 pub enum ServerToClient {
-  /// Provide the client a unique id to tag its messages.
-  LeaseId(ClientId),
+  /// The server refused the connection, for the given reason.
+  /// This must stay the first variant, so that clients on any protocol version can decode it.
+  Rejected(String),
+  /// Provide the client a unique id and session token to tag its messages,
+  /// along with the capabilities that will be used for the connection.
+  LeaseId(Session, Vec<String>),
   /// Ping
   Ping,
 
   /// Complete an AddPlayer request.
   PlayerAdded(EntityId, Point3<f32>),
-  /// Update a player's position.
-  UpdatePlayer(EntityId, Aabb3<f32>),
+  /// A player has left the world.
+  RemovePlayer(EntityId),
+  /// A mob has left the world.
+  RemoveMob(EntityId),
+  /// The authoritative movement state of one of the client's own players.
+  PlayerState(EntityId, movement::Ack),
 
-  /// Update the client's view of a mob with a given mesh.
-  UpdateMob(EntityId, Aabb3<f32>),
+  /// Update the positions of the players and mobs that changed.
+  Snapshot(Snapshot),
 
   /// The sun as a [0, 1) portion of its cycle.
   UpdateSun(f32),
 
   /// Provide a block of terrain to a client.
   Block(TerrainBlockSend, BlockReason),
+  /// A block request was refused, for the given reason, and the block won't be sent.
+  BlockRefused(BlockPosition, LODIndex, String),
+
+  /// A request from this client was refused, for the given reason.
+  Error(String),
 }
@@ -7,6 +7,13 @@ use std::ops::Add;
 /// Unique ID for a loaded entity.
 pub struct EntityId(u32);
 
+impl EntityId {
+  /// The id as a plain number.
+  pub fn as_u32(&self) -> u32 {
+    self.0
+  }
+}
+
 impl Default for EntityId {
   fn default() -> EntityId {
     EntityId(0)
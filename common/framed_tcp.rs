@@ -0,0 +1,161 @@
+//! Length-prefixed messages over a single TCP connection, which carries traffic both ways.
+//! Each message is sent as a little-endian u32 length followed by that many bytes.
+
+use std;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::time::Duration;
+
+/// URLs with this scheme use framed TCP, e.g. `framed-tcp://127.0.0.1:8888`.
+pub const SCHEME: &'static str = "framed-tcp://";
+
+/// Refuse to allocate buffers for messages bigger than this.
+const MAX_MESSAGE_SIZE: usize = 1 << 26;
+
+/// Give up on sending to a peer that hasn't read anything in this long.
+const WRITE_TIMEOUT_SECS: u64 = 30;
+
+/// The host:port part of a framed TCP URL, or None if it's some other kind of URL.
+pub fn address(url: &str) -> Option<&str> {
+  if url.starts_with(SCHEME) {
+    Some(&url[SCHEME.len() ..])
+  } else {
+    None
+  }
+}
+
+fn write_frame(stream: &mut TcpStream, msg: &[u8]) -> io::Result<()> {
+  let len = msg.len() as u32;
+  let header = [len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8];
+  try!(stream.write_all(&header));
+  try!(stream.write_all(msg));
+  stream.flush()
+}
+
+fn read_frame(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+  let mut header = [0; 4];
+  try!(stream.read_exact(&mut header));
+  let len =
+    (header[0] as usize)
+    | ((header[1] as usize) << 8)
+    | ((header[2] as usize) << 16)
+    | ((header[3] as usize) << 24);
+  if len > MAX_MESSAGE_SIZE {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Message too large: {} bytes", len)))
+  }
+
+  let mut msg = vec!(0; len);
+  try!(stream.read_exact(&mut msg));
+  Ok(msg)
+}
+
+/// The sending half of a connection. Clones send over the same connection.
+#[derive(Clone)]
+pub struct Writer(Arc<Mutex<TcpStream>>);
+
+impl Writer {
+  /// Block until this message has been sent.
+  /// A failed write can leave part of a frame on the wire, so the connection is closed after any error.
+  pub fn write(&self, msg: &[u8]) -> io::Result<()> {
+    let mut stream = self.0.lock().unwrap();
+    let r = write_frame(&mut stream, msg);
+    if r.is_err() {
+      match stream.shutdown(Shutdown::Both) {
+        Ok(()) => {},
+        Err(err) => debug!("Error closing connection: {:?}", err),
+      }
+    }
+    r
+  }
+}
+
+/// The receiving half of a connection.
+pub struct Reader(TcpStream);
+
+impl Reader {
+  /// Block until a message arrives.
+  pub fn read(&mut self) -> io::Result<Vec<u8>> {
+    read_frame(&mut self.0)
+  }
+}
+
+/// Open a connection to a listening server.
+pub fn connect(address: &str) -> io::Result<(Writer, Reader)> {
+  let stream = try!(TcpStream::connect(address));
+  try!(stream.set_nodelay(true));
+  let reader = try!(stream.try_clone());
+  Ok((Writer(Arc::new(Mutex::new(stream))), Reader(reader)))
+}
+
+/// Accepts connections, and gathers the messages from all of them.
+pub struct Listener {
+  messages: Receiver<(Vec<u8>, Writer)>,
+}
+
+impl Listener {
+  /// Start listening for connections at `address`.
+  pub fn bind(address: &str) -> io::Result<Listener> {
+    let listener = try!(TcpListener::bind(address));
+    let (send, recv) = channel();
+
+    std::thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream =
+          match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+              warn!("Error accepting connection: {:?}", err);
+              continue
+            },
+          };
+        let setup =
+          stream.set_nodelay(true)
+          // Without a timeout, a peer that stops reading blocks its senders forever.
+          .and_then(|()| stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS))))
+          .and_then(|()| stream.try_clone());
+        let writer =
+          match setup {
+            Ok(clone) => Writer(Arc::new(Mutex::new(clone))),
+            Err(err) => {
+              warn!("Error setting up connection: {:?}", err);
+              continue
+            },
+          };
+
+        let send = send.clone();
+        std::thread::spawn(move || {
+          loop {
+            match read_frame(&mut stream) {
+              Ok(msg) => {
+                if send.send((msg, writer.clone())).is_err() {
+                  // The listener is gone.
+                  return
+                }
+              },
+              Err(err) => {
+                debug!("Connection closed: {:?}", err);
+                return
+              },
+            }
+          }
+        });
+      }
+    });
+
+    Ok(Listener {
+      messages: recv,
+    })
+  }
+
+  /// Try to read a message from any connection, along with a way to reply over the same connection.
+  pub fn try_read(&mut self) -> Option<(Vec<u8>, Writer)> {
+    match self.messages.try_recv() {
+      Ok(msg) => Some(msg),
+      Err(TryRecvError::Empty) => None,
+      Err(TryRecvError::Disconnected) => panic!("Framed TCP listener thread stopped"),
+    }
+  }
+}
@@ -69,6 +69,19 @@ impl LODMap {
     })
   }
 
+  /// Does anyone have a handle on `position`?
+  pub fn is_loaded(&self, position: &BlockPosition) -> bool {
+    self.loaded.contains_key(position)
+  }
+
+  /// Find all the positions that `owner` has a handle on.
+  pub fn owned_by(&self, owner: OwnerId) -> Vec<BlockPosition> {
+    self.loaded.iter()
+      .filter(|&(_, bls)| bls.owner_lods.iter().any(|&(o, _)| o == owner))
+      .map(|(&position, _)| position)
+      .collect()
+  }
+
   // TODO: Can probably get rid of the LODChange returns; we only assert with em.
 
   /// Acquire/update an owner's handle in `position`.
@@ -0,0 +1,150 @@
+//! A small LZ77-style compressor, for shrinking messages that repeat themselves (e.g. terrain blocks).
+//!
+//! The compressed stream is a series of tokens. A control byte below 0x80 is followed by
+//! `control + 1` literal bytes. A control byte of 0x80 or above is a back-reference of
+//! `(control & 0x7f) + MIN_MATCH` bytes, followed by a little-endian u16 offset back into the output.
+
+use std::cmp::min;
+
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = 0x7f + MIN_MATCH;
+const MAX_LITERALS: usize = 0x80;
+const MAX_OFFSET: usize = 0xffff;
+
+const LG_TABLE_SIZE: usize = 12;
+
+// Hash the `MIN_MATCH` bytes at the start of `bytes`.
+fn hash(bytes: &[u8]) -> usize {
+  let x =
+    (bytes[0] as u32)
+    | ((bytes[1] as u32) << 8)
+    | ((bytes[2] as u32) << 16)
+    | ((bytes[3] as u32) << 24);
+  (x.wrapping_mul(2654435761) >> (32 - LG_TABLE_SIZE)) as usize
+}
+
+fn flush_literals(output: &mut Vec<u8>, literals: &[u8]) {
+  for chunk in literals.chunks(MAX_LITERALS) {
+    output.push((chunk.len() - 1) as u8);
+    output.extend(chunk.iter().cloned());
+  }
+}
+
+/// Compress a byte string.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+  let mut output = Vec::new();
+  // The most recent position each hash was seen at.
+  let mut table = vec!(None; 1 << LG_TABLE_SIZE);
+  let mut literal_start = 0;
+  let mut i = 0;
+
+  while i + MIN_MATCH <= input.len() {
+    let h = hash(&input[i..]);
+    let candidate = table[h];
+    table[h] = Some(i);
+
+    let match_len =
+      match candidate {
+        Some(c) if i - c <= MAX_OFFSET => {
+          let max_len = min(MAX_MATCH, input.len() - i);
+          let mut len = 0;
+          while len < max_len && input[c + len] == input[i + len] {
+            len += 1;
+          }
+          len
+        },
+        _ => 0,
+      };
+
+    if match_len < MIN_MATCH {
+      i += 1;
+      continue
+    }
+
+    flush_literals(&mut output, &input[literal_start .. i]);
+    let offset = i - candidate.unwrap();
+    output.push(0x80 | (match_len - MIN_MATCH) as u8);
+    output.push(offset as u8);
+    output.push((offset >> 8) as u8);
+
+    i += match_len;
+    literal_start = i;
+  }
+
+  flush_literals(&mut output, &input[literal_start ..]);
+  output
+}
+
+/// Decompress a byte string produced by `compress`, or return None if it's malformed.
+pub fn decompress(input: &[u8]) -> Option<Vec<u8>> {
+  let mut output = Vec::with_capacity(input.len() * 2);
+  let mut i = 0;
+
+  while i < input.len() {
+    let control = input[i] as usize;
+    i += 1;
+
+    if control < 0x80 {
+      let len = control + 1;
+      if i + len > input.len() {
+        return None
+      }
+      output.extend(input[i .. i + len].iter().cloned());
+      i += len;
+    } else {
+      if i + 2 > input.len() {
+        return None
+      }
+      let len = (control & 0x7f) + MIN_MATCH;
+      let offset = (input[i] as usize) | ((input[i + 1] as usize) << 8);
+      i += 2;
+      if offset == 0 || offset > output.len() {
+        return None
+      }
+      // The match may overlap the bytes it's producing, so copy one byte at a time.
+      let start = output.len() - offset;
+      for j in 0 .. len {
+        let b = output[start + j];
+        output.push(b);
+      }
+    }
+  }
+
+  Some(output)
+}
+
+#[test]
+fn empty() {
+  assert_eq!(compress(&[]), Vec::new());
+  assert_eq!(decompress(&[]), Some(Vec::new()));
+}
+
+#[test]
+fn round_trip() {
+  let inputs: Vec<Vec<u8>> = vec!(
+    vec!(1),
+    vec!(0; 1000),
+    (0 .. 1000).map(|i| (i * 7 % 256) as u8).collect(),
+    (0 .. 1000).map(|i| (i * i % 251) as u8).collect(),
+    b"abcabcabcabcabd, abcabcabcabcabd".to_vec(),
+  );
+  for input in inputs.iter() {
+    assert_eq!(decompress(&compress(input)).as_ref(), Some(input));
+  }
+}
+
+#[test]
+fn repetition_shrinks() {
+  let input = vec!(3; 4096);
+  assert!(compress(&input).len() < input.len() / 16);
+}
+
+#[test]
+fn malformed() {
+  // Literals running past the end.
+  assert_eq!(decompress(&[5, 1, 2]), None);
+  // A back-reference before the start of the output.
+  assert_eq!(decompress(&[0, 1, 0x80, 2, 0]), None);
+  // A truncated back-reference.
+  assert_eq!(decompress(&[0, 1, 0x80, 1]), None);
+}
@@ -13,6 +13,7 @@
 #![plugin(clippy)]
 #![allow(type_complexity)]
 
+extern crate bincode;
 extern crate cgmath;
 #[macro_use]
 extern crate log;
@@ -24,15 +25,22 @@ extern crate test;
 extern crate time;
 
 pub mod block_position;
+pub mod block_wire;
+pub mod brush;
 pub mod closure_series;
+pub mod collision;
 pub mod color;
 pub mod communicate;
 pub mod cube_shell;
 pub mod entity;
+pub mod framed_tcp;
 pub mod id_allocator;
 pub mod interval_timer;
 pub mod lod;
+pub mod lz;
+pub mod movement;
 pub mod range_abs;
 pub mod socket;
 pub mod surroundings_loader;
 pub mod terrain_block;
+pub mod transport;
@@ -0,0 +1,330 @@
+//! Player movement physics. The server runs this authoritatively, and clients run the same code to
+//! predict their own player's motion before the server confirms it.
+
+use cgmath;
+use cgmath::{Aabb3, Matrix, Matrix3, Point, Point3, Ray, Ray3, Vector, Vector2, Vector3};
+use std::f32::consts::PI;
+
+use collision;
+use collision::Impact;
+
+/// Standing on something refills the jump fuel to this many ticks of upward acceleration.
+pub const MAX_JUMP_FUEL: u32 = 4;
+/// Players walking into anything up to this tall step up onto it instead of stopping.
+pub const MAX_STEP_HEIGHT: f32 = 1.0;
+/// Surfaces that slope less than about 45 degrees are floors; steeper ones are walls.
+pub const MIN_FLOOR_NORMAL_Y: f32 = 0.7;
+/// The extra upward acceleration while jumping.
+const JUMP_ACCEL: f32 = 0.3;
+
+#[derive(Debug, Clone, Copy, RustcEncodable, RustcDecodable)]
+/// Things a player does that affect their movement.
+pub enum Input {
+  /// Add a vector to the player's walking acceleration, relative to their facing.
+  Walk(Vector3<f32>),
+  /// Rotate the player by some amount: lateral, then vertical.
+  Rotate(Vector2<f32>),
+  /// [Try to] start a jump.
+  StartJump,
+  /// [Try to] stop a jump.
+  StopJump,
+}
+
+/// What a moving player can run into.
+pub trait World {
+  /// The player's current bounds.
+  fn bounds(&self) -> Aabb3<f32>;
+  /// The first thing `bounds` would run into while moving by `v`, if it runs into anything.
+  fn sweep(&self, bounds: &Aabb3<f32>, v: &Vector3<f32>) -> Option<Impact>;
+  /// Move the player to `bounds`.
+  fn move_to(&mut self, bounds: Aabb3<f32>);
+}
+
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+/// The movement-related state of a player.
+pub struct State {
+  #[allow(missing_docs)]
+  pub position: Point3<f32>,
+  /// Units are world coordinates.
+  pub speed: Vector3<f32>,
+  /// Units are world coordinates.
+  pub accel: Vector3<f32>,
+  /// x/z units are relative to player facing.
+  pub walk_accel: Vector3<f32>,
+  /// This is depleted as we jump and replenished as we stand.
+  pub jump_fuel: u32,
+  /// Are we currently trying to jump? (e.g. holding the key).
+  pub is_jumping: bool,
+
+  /// Rotation around the y-axis, in radians.
+  pub lateral_rotation: f32,
+  /// "Pitch", in radians.
+  pub vertical_rotation: f32,
+}
+
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+/// The server's state for a player, and how far through that player's inputs it is.
+pub struct Ack {
+  /// The sequence number of the last input applied, or 0 if there haven't been any.
+  pub input: u32,
+  /// The number of steps taken since that input was applied.
+  pub steps: u32,
+  #[allow(missing_docs)]
+  pub state: State,
+  #[allow(missing_docs)]
+  pub bounds: Aabb3<f32>,
+}
+
+impl State {
+  #[allow(missing_docs)]
+  pub fn new(position: Point3<f32>) -> State {
+    State {
+      position: position,
+      speed: Vector3::new(0.0, 0.0, 0.0),
+      accel: Vector3::new(0.0, -0.1, 0.0),
+      walk_accel: Vector3::new(0.0, 0.0, 0.0),
+      jump_fuel: 0,
+      is_jumping: false,
+      lateral_rotation: 0.0,
+      vertical_rotation: 0.0,
+    }
+  }
+
+  /// Apply a player's input.
+  pub fn apply(&mut self, input: &Input) {
+    match *input {
+      Input::Walk(da) => {
+        self.walk_accel.add_self_v(&da.mul_s(0.2));
+      },
+      Input::Rotate(r) => {
+        self.rotate_lateral(r.x);
+        self.rotate_vertical(r.y);
+      },
+      Input::StartJump => {
+        if !self.is_jumping {
+          self.is_jumping = true;
+          self.accel.y = self.accel.y + JUMP_ACCEL;
+        }
+      },
+      Input::StopJump => {
+        if self.is_jumping {
+          self.is_jumping = false;
+          self.accel.y = self.accel.y - JUMP_ACCEL;
+        }
+      },
+    }
+  }
+
+  /// Advance the player by one tick.
+  pub fn step<W: World>(&mut self, world: &mut W) {
+    if self.is_jumping {
+      if self.jump_fuel > 0 {
+        self.jump_fuel -= 1;
+      } else {
+        self.apply(&Input::StopJump);
+      }
+    }
+
+    self.translate(world);
+
+    let y_axis = Vector3::new(0.0, 1.0, 0.0);
+    let walk_v =
+        Matrix3::from_axis_angle(&y_axis, cgmath::rad(self.lateral_rotation))
+        .mul_v(&self.walk_accel);
+    self.speed.add_self_v(&walk_v);
+    self.speed.add_self_v(&self.accel);
+    // friction
+    self.speed.mul_self_v(&Vector3::new(0.7, 0.99, 0.7 as f32));
+  }
+
+  /// Move the player by its speed, sliding along whatever it runs into.
+  /// If the player runs into something short enough, the player will step up onto it.
+  fn translate<W: World>(&mut self, world: &mut W) {
+    let init_bounds = world.bounds();
+    let v = self.speed;
+    let mut landed = false;
+
+    let bounds = {
+      let world = &*world;
+      let speed = &mut self.speed;
+      collision::move_sliding(
+        &init_bounds,
+        &v,
+        |bounds, v| world.sweep(bounds, v),
+        |impact, bounds, _| {
+          if impact.normal.y < MIN_FLOOR_NORMAL_Y {
+            let raised = step_up(world, bounds, &impact.obstacle);
+            if raised.is_some() {
+              return raised
+            }
+          } else {
+            landed = true;
+          }
+          *speed = collision::slide(speed, &impact.normal);
+          None
+        },
+      )
+    };
+
+    if landed {
+      self.jump_fuel = MAX_JUMP_FUEL;
+    } else if self.speed.y < 0.0 {
+      self.jump_fuel = 0;
+    }
+
+    self.position.add_self_v(&bounds.min.sub_p(&init_bounds.min));
+    world.move_to(bounds);
+  }
+
+  /// Rotate the player around the y axis, by `r` radians. Positive is counterclockwise.
+  pub fn rotate_lateral(&mut self, r: f32) {
+    self.lateral_rotation = self.lateral_rotation + r;
+  }
+
+  /// Changes the player's pitch by `r` radians. Positive is up.
+  /// Angles that "flip around" (i.e. looking too far up or down)
+  /// are sliently rejected.
+  pub fn rotate_vertical(&mut self, r: f32) {
+    let new_rotation = self.vertical_rotation + r;
+
+    if new_rotation < -PI / 2.0
+    || new_rotation >  PI / 2.0 {
+      return
+    }
+
+    self.vertical_rotation = new_rotation;
+  }
+
+  // axes
+
+  /// Return the "right" axis (i.e. the x-axis rotated to match you).
+  pub fn right(&self) -> Vector3<f32> {
+    Matrix3::from_axis_angle(&Vector3::new(0.0, 1.0, 0.0), cgmath::rad(self.lateral_rotation)).mul_v(&Vector3::new(1.0, 0.0, 0.0))
+  }
+
+  /// Return the "Ray axis (i.e. the z-axis rotated to match you).
+  pub fn forward(&self) -> Vector3<f32> {
+    let y_axis = Vector3::new(0.0, 1.0, 0.0);
+    let transform =
+      Matrix3::from_axis_angle(&self.right(), cgmath::rad(self.vertical_rotation))
+      .mul_m(&Matrix3::from_axis_angle(&y_axis, cgmath::rad(self.lateral_rotation)));
+    let forward_orig = Vector3::new(0.0, 0.0, -1.0);
+
+    transform.mul_v(&forward_orig)
+  }
+
+  #[allow(missing_docs)]
+  pub fn forward_ray(&self) -> Ray3<f32> {
+    Ray::new(self.position, self.forward())
+  }
+}
+
+// If `bounds` can climb on top of `obstacle`, the bounds on top of it.
+fn step_up<W: World>(world: &W, bounds: &Aabb3<f32>, obstacle: &Aabb3<f32>) -> Option<Aabb3<f32>> {
+  let step_height = obstacle.max.y - bounds.min.y;
+  if step_height <= 0.0 || step_height > MAX_STEP_HEIGHT {
+    return None
+  }
+
+  let up = Vector3::new(0.0, step_height, 0.0);
+  if world.sweep(bounds, &up).is_some() {
+    // There's no room above.
+    return None
+  }
+
+  // Place the player exactly on top, so rounding doesn't leave it stuck in the obstacle's side.
+  let height = bounds.max.y - bounds.min.y;
+  let mut raised = bounds.clone();
+  raised.min.y = obstacle.max.y;
+  raised.max.y = obstacle.max.y + height;
+  Some(raised)
+}
+
+#[cfg(test)]
+struct Floor {
+  bounds: Aabb3<f32>,
+  obstacles: Vec<Aabb3<f32>>,
+}
+
+#[cfg(test)]
+impl Floor {
+  fn new(bounds: Aabb3<f32>) -> Floor {
+    Floor {
+      bounds: bounds,
+      obstacles: vec!(Aabb3::new(Point3::new(-100.0, -1.0, -100.0), Point3::new(100.0, 0.0, 100.0))),
+    }
+  }
+}
+
+#[cfg(test)]
+impl World for Floor {
+  fn bounds(&self) -> Aabb3<f32> {
+    self.bounds.clone()
+  }
+
+  fn sweep(&self, bounds: &Aabb3<f32>, v: &Vector3<f32>) -> Option<Impact> {
+    self.obstacles.iter()
+      .map(|obstacle| collision::sweep(bounds, v, obstacle))
+      .fold(None, collision::earliest)
+  }
+
+  fn move_to(&mut self, bounds: Aabb3<f32>) {
+    self.bounds = bounds;
+  }
+}
+
+#[test]
+fn falls_and_lands() {
+  let mut world = Floor::new(Aabb3::new(Point3::new(0.0, 1.5, 0.0), Point3::new(1.0, 3.5, 1.0)));
+  let mut state = State::new(Point3::new(0.5, 2.5, 0.5));
+  for _ in 0 .. 100 {
+    state.step(&mut world);
+  }
+  assert_eq!(world.bounds.min.y, 0.0);
+  assert_eq!(state.jump_fuel, MAX_JUMP_FUEL);
+  assert!((state.position.y - 1.0).abs() < 0.001);
+}
+
+#[test]
+fn jumping_spends_fuel() {
+  let mut state = State::new(Point3::new(0.0, 0.0, 0.0));
+  state.jump_fuel = 2;
+  state.apply(&Input::StartJump);
+  // Starting a jump twice doesn't accelerate twice.
+  state.apply(&Input::StartJump);
+  assert_eq!(state.accel.y, -0.1 + JUMP_ACCEL);
+
+  let mut world = Floor::new(Aabb3::new(Point3::new(0.0, 10.0, 0.0), Point3::new(1.0, 12.0, 1.0)));
+  for _ in 0 .. 3 {
+    state.step(&mut world);
+  }
+  assert!(!state.is_jumping);
+  assert_eq!(state.accel.y, -0.1);
+}
+
+#[test]
+fn lands_exactly_at_high_speed() {
+  let mut world = Floor::new(Aabb3::new(Point3::new(0.0, 50.0, 0.0), Point3::new(1.0, 52.0, 1.0)));
+  let mut state = State::new(Point3::new(0.5, 51.0, 0.5));
+  state.speed = Vector3::new(0.0, -100.0, 0.0);
+  state.step(&mut world);
+  assert_eq!(world.bounds.min.y, 0.0);
+  assert!(state.speed.y <= 0.0 && state.speed.y > -1.0);
+}
+
+#[test]
+fn slides_along_walls_and_steps_up() {
+  let mut world = Floor::new(Aabb3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 2.0, 1.0)));
+  // A wall along x, and a step in the way.
+  world.obstacles.push(Aabb3::new(Point3::new(-100.0, 0.0, -1.0), Point3::new(100.0, 10.0, 0.0)));
+  world.obstacles.push(Aabb3::new(Point3::new(2.0, 0.0, -1.0), Point3::new(3.0, 0.5, 3.0)));
+  let mut state = State::new(Point3::new(0.5, 1.0, 0.5));
+  state.speed = Vector3::new(0.5, -0.1, -0.5);
+  state.step(&mut world);
+  assert_eq!(world.bounds.min.z, 0.0);
+  assert_eq!(world.bounds.min.x, 0.5);
+  state.speed = Vector3::new(1.0, -0.1, 0.0);
+  state.step(&mut world);
+  assert_eq!(world.bounds.min.y, 0.5);
+  assert_eq!(world.bounds.min.x, 1.5);
+}
@@ -41,6 +41,28 @@ pub struct Triangle<T> {
   pub v3: T,
 }
 
+/// The bounding box of a triangle. Client and server must agree on this, since the client predicts
+/// collisions with the terrain it has loaded.
+pub fn make_bounds(
+  v1: &Point3<f32>,
+  v2: &Point3<f32>,
+  v3: &Point3<f32>,
+) -> Aabb3<f32> {
+  let minx = v1.x.min(v2.x).min(v3.x);
+  let maxx = v1.x.max(v2.x).max(v3.x);
+
+  let miny = v1.y.min(v2.y).min(v3.y);
+  let maxy = v1.y.max(v2.y).max(v3.y);
+
+  let minz = v1.z.min(v2.z).min(v3.z);
+  let maxz = v1.z.max(v2.z).max(v3.z);
+
+  Aabb3::new(
+    Point3::new(minx, miny, minz),
+    Point3::new(maxx, maxy, maxz),
+  )
+}
+
 /// Construct a triangle.
 pub fn tri<T>(v1: T, v2: T, v3: T) -> Triangle<T> {
   Triangle {
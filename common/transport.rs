@@ -0,0 +1,70 @@
+//! Backend-independent message transport between client and server.
+//!
+//! `ipc://` and `tcp://` URLs use nanomsg push/pull sockets, where the server connects back to a
+//! URL each client listens on. `framed-tcp://` URLs use a single TCP connection per client that
+//! carries messages both ways, so clients don't need to be reachable.
+
+use std::io;
+use std::time::Duration;
+
+use framed_tcp;
+use socket::{SendSocket, ReceiveSocket};
+
+/// A way to send messages to one peer.
+pub enum Sender {
+  #[allow(missing_docs)]
+  Nanomsg(SendSocket),
+  #[allow(missing_docs)]
+  FramedTcp(framed_tcp::Writer),
+}
+
+impl Sender {
+  /// Connect a nanomsg socket to a peer's listen URL.
+  pub fn connect(url: &str, timeout: Option<Duration>) -> Sender {
+    Sender::Nanomsg(SendSocket::new(url, timeout))
+  }
+
+  /// Block until we can send this message.
+  pub fn write(&mut self, msg: &[u8]) -> io::Result<()> {
+    match self {
+      &mut Sender::Nanomsg(ref mut socket) => socket.write(msg),
+      &mut Sender::FramedTcp(ref writer) => writer.write(msg),
+    }
+  }
+}
+
+/// Receives messages from any number of peers.
+pub enum Listener {
+  #[allow(missing_docs)]
+  Nanomsg(ReceiveSocket),
+  #[allow(missing_docs)]
+  FramedTcp(framed_tcp::Listener),
+}
+
+impl Listener {
+  /// Listen at `url`, using the backend its scheme selects.
+  pub fn bind(url: &str) -> Listener {
+    match framed_tcp::address(url) {
+      None => Listener::Nanomsg(ReceiveSocket::new(url, None)),
+      Some(address) => {
+        let listener =
+          framed_tcp::Listener::bind(address)
+          .unwrap_or_else(|err| panic!("Couldn't listen on {}: {:?}", url, err));
+        Listener::FramedTcp(listener)
+      },
+    }
+  }
+
+  /// Try to read a message. If the backend can reply over the connection the message came in on,
+  /// a `Sender` for that is returned with it.
+  pub fn try_read(&mut self) -> Option<(Vec<u8>, Option<Sender>)> {
+    match self {
+      &mut Listener::Nanomsg(ref mut socket) => {
+        socket.try_read().map(|msg| (msg, None))
+      },
+      &mut Listener::FramedTcp(ref mut listener) => {
+        listener.try_read().map(|(msg, writer)| (msg, Some(Sender::FramedTcp(writer))))
+      },
+    }
+  }
+}
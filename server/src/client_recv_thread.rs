@@ -1,26 +1,29 @@
-use cgmath::{Point, Point3, Vector, Vector3, Aabb3};
+use cgmath::{Point, Point3, Vector, Aabb3};
 use rand;
-use rand::distributions::IndependentSample;
+use rand::Rng;
 use std::convert::AsRef;
-use std::f32::consts::PI;
-use std::ops::DerefMut;
 use std::time::Duration;
 use stopwatch;
+use time;
 
-use common::communicate::{ClientToServer, ServerToClient};
+use common::communicate;
+use common::communicate::{ClientToServer, ServerToClient, Session, SessionToken};
 use common::entity;
-use common::socket::SendSocket;
+use common::transport;
 
-use player::Player;
+use disconnect;
+use gaia_queue;
+use entities;
+use player;
+use player::Controller;
+use protection;
+use server;
 use server::{Client, Server};
 use terrain;
 use voxel_data;
 use update_gaia;
 use update_gaia::LoadReason;
-
-fn center(bounds: &Aabb3<f32>) -> Point3<f32> {
-  bounds.min.add_v(&bounds.max.to_vec()).mul_s(1.0 / 2.0)
-}
+use validate::{validate, Invalid};
 
 fn cast(
   server: &Server,
@@ -28,9 +31,11 @@ fn cast(
 ) -> Option<voxel_data::bounds::T> {
   let ray;
   {
-    let players = server.players.lock().unwrap();
-    let player = players.get(&player_id).unwrap();
-    ray = player.forward_ray();
+    let entities = server.entities.lock().unwrap();
+    match entities.controllers.get(&player_id) {
+      None => return None,
+      Some(controller) => ray = controller.movement.forward_ray(),
+    }
   }
 
   server.terrain_loader.terrain.voxels.lock().unwrap().cast_ray(
@@ -44,191 +49,253 @@ fn cast(
   )
 }
 
+// Find a way to send to a newly-connecting client: over the connection it came in on if possible,
+// or else by connecting to the URL it's listening on.
+fn client_socket(client_url: &str, reply: Option<transport::Sender>) -> transport::Sender {
+  reply.unwrap_or_else(|| transport::Sender::connect(client_url, Some(Duration::from_secs(30))))
+}
+
+/// Tell a connecting client why it can't connect.
+pub fn reject_client(client_url: &str, reply: Option<transport::Sender>, reason: String) {
+  use bincode::SizeLimit;
+  use bincode::rustc_serialize::encode;
+
+  warn!("Rejecting client at {}: {}", client_url, reason);
+  let mut socket = client_socket(client_url, reply);
+  let msg = encode(&ServerToClient::Rejected(reason), SizeLimit::Infinite).unwrap();
+  match socket.write(msg.as_ref()) {
+    Ok(()) => {},
+    Err(err) => warn!("Error sending to client: {:?}", err),
+  }
+}
+
+// Players can be removed between validation and application, so this is a no-op if the player's gone.
+fn with_player<F>(
+  server: &Server,
+  player_id: entity::EntityId,
+  f: F,
+) where
+  F: FnOnce(&mut Controller),
+{
+  match server.entities.lock().unwrap().controllers.get_mut(&player_id) {
+    None => {},
+    Some(controller) => f(controller),
+  }
+}
+
+// Apply a player's brush if they're allowed to edit everywhere it reaches, or else tell their client why not.
+fn apply_brush<UpdateGaia>(
+  server: &Server,
+  update_gaia: &mut UpdateGaia,
+  session: &Session,
+  player_id: entity::EntityId,
+  brush: voxel_data::brush::T<Box<voxel_data::mosaic::T<terrain::voxel::Material> + Send>>,
+) where
+  UpdateGaia: FnMut(update_gaia::Message),
+{
+  let name =
+    match server.entities.lock().unwrap().names.get(&player_id) {
+      None => return,
+      Some(name) => name.clone(),
+    };
+
+  match server.protection.check_edit(name.as_ref(), &protection::of_voxels(&brush.bounds)) {
+    Ok(()) => update_gaia(update_gaia::Message::Edit(update_gaia::Edit::Brush(player_id, brush))),
+    Err(err) => {
+      info!("Refusing brush from {:?}: {}", name, err);
+      server.clients.lock().unwrap().get_mut(&session.client_id).map(|client| {
+        client.send(ServerToClient::Error(err));
+      });
+    },
+  }
+}
+
 pub fn apply_client_update<UpdateGaia>(
   server: &Server,
   update_gaia: &mut UpdateGaia,
+  reply: Option<transport::Sender>,
   update: ClientToServer,
 ) where
   UpdateGaia: FnMut(update_gaia::Message),
 {
   stopwatch::time("apply_client_update", move || {
-    match update {
-      ClientToServer::Init(client_url) => {
-        info!("Sending to {}.", client_url);
-
-        let mut client =
-          Client {
-            socket: SendSocket::new(client_url.as_ref(), Some(Duration::from_secs(30))),
-          };
-
-        let client_id = server.client_allocator.lock().unwrap().allocate();
-        client.send(ServerToClient::LeaseId(client_id));
-
-        server.clients.lock().unwrap().insert(client_id, client);
+    match validate(server, &update) {
+      Ok(()) => {},
+      Err(Invalid::Unattributable(err)) => {
+        warn!("Dropping invalid request {:?}: {}", update, err);
+        return
       },
-      ClientToServer::Ping(client_id) => {
-        server.clients.lock().unwrap()
-          .get_mut(&client_id)
-          .unwrap()
-          .send(ServerToClient::Ping);
+      Err(Invalid::Reply(client_id, err)) => {
+        warn!("Refusing request {:?} from {:?}: {}", update, client_id, err);
+        server.clients.lock().unwrap().get_mut(&client_id).map(|client| {
+          client.send(ServerToClient::Error(err));
+        });
+        return
+      },
+      Err(Invalid::Block(client_id, position, lod, err)) => {
+        debug!("Refusing block {:?} at {:?} for {:?}: {}", position, lod, client_id, err);
+        server.clients.lock().unwrap().get_mut(&client_id).map(|client| {
+          client.send(ServerToClient::BlockRefused(position, lod, err));
+        });
+        return
       },
-      ClientToServer::AddPlayer(client_id) => {
-        let mut player =
-          Player::new(
-            server.id_allocator.lock().unwrap().allocate(),
-            &server.owner_allocator,
+    }
+
+    match update {
+      ClientToServer::Init(protocol_version, client_url, capabilities) => {
+        if protocol_version != communicate::PROTOCOL_VERSION {
+          reject_client(
+            client_url.as_ref(),
+            reply,
+            format!(
+              "Incompatible protocol version: the server uses version {}, but the client uses version {}.",
+              communicate::PROTOCOL_VERSION,
+              protocol_version,
+            ),
           );
+          return
+        }
 
-        // TODO: shift upward until outside terrain
-        let min = Point3::new(0.0, 64.0, 4.0);
-        let max = min.add_v(&Vector3::new(1.0, 2.0, 1.0));
-        let bounds = Aabb3::new(min, max);
-        server.physics.lock().unwrap().insert_misc(player.entity_id, bounds.clone());
+        info!("Sending to {}.", client_url);
 
-        player.position = center(&bounds);
-        player.rotate_lateral(PI / 2.0);
+        let capabilities = communicate::negotiate_capabilities(capabilities.as_ref());
+        debug!("Using capabilities {:?}", capabilities);
 
-        let id = player.entity_id;
-        let pos = player.position;
+        // Don't use the server's RNG; it's seeded predictably.
+        let token = SessionToken(rand::OsRng::new().unwrap().gen());
 
-        server.players.lock().unwrap().insert(id, player);
+        let mut client =
+          Client {
+            outgoing: server::spawn_writer(client_socket(client_url.as_ref(), reply)),
+            token: token,
+            capabilities: capabilities.clone(),
+            players: Vec::new(),
+            last_ping: time::precise_time_ns(),
+            needs_full_snapshot: true,
+            broken: false,
+          };
 
-        let mut clients = server.clients.lock().unwrap();
-        let client = clients.get_mut(&client_id).unwrap();
+        let client_id = server.client_allocator.lock().unwrap().allocate();
         client.send(
-          ServerToClient::PlayerAdded(id, pos)
+          ServerToClient::LeaseId(
+            Session {
+              client_id: client_id,
+              token: token,
+            },
+            capabilities,
+          )
         );
+
+        server.clients.lock().unwrap().insert(client_id, client);
       },
-      ClientToServer::StartJump(player_id) => {
-        let mut players = server.players.lock().unwrap();
-        let player = players.get_mut(&player_id).unwrap();
-        if !player.is_jumping {
-          player.is_jumping = true;
-          // this 0.3 is duplicated in a few places
-          player.accel.y = player.accel.y + 0.3;
+      ClientToServer::Ping(session) => {
+        match server.clients.lock().unwrap().get_mut(&session.client_id) {
+          None => warn!("Ping from unknown client {:?}", session.client_id),
+          Some(client) => client.last_ping = time::precise_time_ns(),
         }
       },
-      ClientToServer::StopJump(player_id) => {
-        let mut players = server.players.lock().unwrap();
-        let player = players.get_mut(&player_id).unwrap();
-        if player.is_jumping {
-          player.is_jumping = false;
-          // this 0.3 is duplicated in a few places
-          player.accel.y = player.accel.y - 0.3;
-        }
+      ClientToServer::Disconnect(session) => {
+        info!("Client {:?} disconnected.", session.client_id);
+        disconnect::disconnect(server, session.client_id);
       },
-      ClientToServer::Walk(player_id, v) => {
-        let mut players = server.players.lock().unwrap();
-        let mut player = players.get_mut(&player_id).unwrap();
-        player.walk(v);
+      ClientToServer::AddPlayer(session, name, _) => {
+        let client_id = session.client_id;
+        let (id, pos) =
+          match player::add(server, server.spawn_point, name) {
+            Err(err) => {
+              info!("Refusing player for {:?}: {}", client_id, err);
+              server.clients.lock().unwrap().get_mut(&client_id).map(|client| {
+                client.send(ServerToClient::Error(err));
+              });
+              return
+            },
+            Ok(added) => added,
+          };
+
+        let added =
+          match server.clients.lock().unwrap().get_mut(&client_id) {
+            None => false,
+            Some(client) => {
+              client.players.push(id);
+              client.send(
+                ServerToClient::PlayerAdded(id, pos)
+              );
+              // The client ignores entity snapshots until it knows its player.
+              client.needs_full_snapshot = true;
+              true
+            },
+          };
+        if !added {
+          // The client disconnected while we were adding its player. It hasn't edited anything yet, so it has
+          // no history to forget.
+          entities::remove(server, id);
+        }
       },
-      ClientToServer::RotatePlayer(player_id, v) => {
-        let mut players = server.players.lock().unwrap();
-        let mut player = players.get_mut(&player_id).unwrap();
-        player.rotate_lateral(v.x);
-        player.rotate_vertical(v.y);
+      ClientToServer::Input(_, player_id, sequence, input) => {
+        with_player(server, player_id, |controller| {
+          controller.input(sequence, &input);
+        });
       },
-      ClientToServer::RequestBlock(client_id, position, lod) => {
-        update_gaia(update_gaia::Message::Load(position, lod, LoadReason::ForClient(client_id)));
+      ClientToServer::RequestBlock(session, position, lod) => {
+        let distance = gaia_queue::distance_to_client(server, session.client_id, &position);
+        update_gaia(
+          update_gaia::Message::Load(update_gaia::Load {
+            position: position,
+            lod: lod,
+            reason: LoadReason::ForClient(session.client_id),
+            distance: distance,
+          })
+        );
       },
-      ClientToServer::Add(player_id) => {
+      ClientToServer::PlantTree(session, player_id) => {
         let bounds = cast(server, player_id);
 
         bounds.map(|bounds| {
-          let mut rng = server.rng.lock().unwrap();
-          let rng = rng.deref_mut();
-
-          let trunk_radius =
-            rand::distributions::normal::Normal::new(2.0, 0.5)
-            .ind_sample(rng);
-          let trunk_radius =
-            f64::max(1.0, f64::min(3.0, trunk_radius));
-
-          let trunk_height =
-            rand::distributions::normal::Normal::new(8.0 * trunk_radius, 2.0 * trunk_radius)
-            .ind_sample(rng);
-          let trunk_height =
-            f64::max(4.0 * trunk_radius, f64::min(12.0 * trunk_radius, trunk_height));
-
-          let leaf_radius =
-            rand::distributions::normal::Normal::new(4.0 * trunk_radius, trunk_radius)
-            .ind_sample(rng);
-          let leaf_radius =
-            f64::max(2.0 * trunk_radius, f64::min(6.0 * trunk_radius, leaf_radius));
+          let (species, seed) = {
+            let mut rng = server.rng.lock().unwrap();
+            (*rng.choose(&terrain::tree::SPECIES).unwrap(), rng.gen())
+          };
+          debug!("Planting {:?} tree with seed {}", species, seed);
 
           let (low, high) = bounds.corners();
           let mut bottom = low.add_v(&high.to_vec()).div_s(2.0);
           bottom.y = low.y;
 
-          let trunk_height = trunk_height as f32;
-          let trunk_radius = trunk_radius as f32;
-          let leaf_radius = leaf_radius as f32;
-
-          let tree =
-            voxel_data::mosaic::translation::T {
-              translation: bottom.to_vec(),
-              mosaic: terrain::tree::new(rng, trunk_height, trunk_radius, leaf_radius),
-            };
+          let tree = terrain::tree::new(species, seed);
+          let (low, high) = tree.corners();
+          let low = bottom.add_v(&low.to_vec());
+          let high = bottom.add_v(&high.to_vec());
 
-          let center =
-            bottom.add_v(&Vector3::new(0.0, trunk_height / 2.0, 0.0));
-          let r = trunk_height / 2.0 + leaf_radius + 20.0;
           let brush =
             voxel_data::brush::T {
               bounds:
+                // Leave a voxel of room around the tree.
                 Aabb3::new(
-                  {
-                    let low = center.add_v(&-Vector3::new(r, r, r));
-                    Point3::new(low.x.floor() as i32, low.y.floor() as i32, low.z.floor() as i32)
-                  },
-                  {
-                    let high = center.add_v(&Vector3::new(r, r, r));
-                    Point3::new(high.x.ceil() as i32, high.y.ceil() as i32, high.z.ceil() as i32)
-                  },
+                  Point3::new(low.x.floor() as i32 - 1, low.y.floor() as i32 - 1, low.z.floor() as i32 - 1),
+                  Point3::new(high.x.ceil() as i32 + 1, high.y.ceil() as i32 + 1, high.z.ceil() as i32 + 1),
                 ),
               min_lg_size: 0,
-              mosaic: Box::new(tree) as Box<voxel_data::mosaic::T<terrain::voxel::Material> + Send>,
+              mosaic:
+                Box::new(
+                  voxel_data::mosaic::translation::T {
+                    translation: bottom.to_vec(),
+                    mosaic: tree,
+                  }
+                ) as Box<voxel_data::mosaic::T<terrain::voxel::Material> + Send>,
             };
 
-          update_gaia(update_gaia::Message::Brush(brush));
+          apply_brush(server, update_gaia, &session, player_id, brush);
         });
       },
-      ClientToServer::Remove(player_id) => {
-        let bounds = cast(server, player_id);
-
-        bounds.map(|bounds| {
-          debug!("bounds {:?}", bounds);
-          let center = bounds.center();
-          let r = 8.0;
-          let sphere =
-            voxel_data::mosaic::solid::T {
-              field: voxel_data::field::translation::T {
-                translation: center.to_vec(),
-                field: voxel_data::field::sphere::T {
-                  radius: r,
-                },
-              },
-              material: terrain::voxel::Material::Empty,
-            };
-          let r = sphere.field.field.radius + 1.0;
-          let brush =
-            voxel_data::brush::T {
-              bounds:
-                Aabb3::new(
-                  {
-                    let low = sphere.field.translation.add_v(&-Vector3::new(r, r, r));
-                    Point3::new(low.x.floor() as i32, low.y.floor() as i32, low.z.floor() as i32)
-                  },
-                  {
-                    let high = sphere.field.translation.add_v(&Vector3::new(r, r, r));
-                    Point3::new(high.x.ceil() as i32, high.y.ceil() as i32, high.z.ceil() as i32)
-                  },
-                ),
-              min_lg_size: 0,
-              mosaic: Box::new(sphere) as Box<voxel_data::mosaic::T<terrain::voxel::Material> + Send>,
-            };
-          let brush: voxel_data::brush::T<Box<voxel_data::mosaic::T<terrain::voxel::Material> + Send>> = brush;
-          update_gaia(update_gaia::Message::Brush(brush));
+      ClientToServer::Undo(_, player_id) => {
+        update_gaia(update_gaia::Message::Edit(update_gaia::Edit::Undo(player_id)));
+      },
+      ClientToServer::Redo(_, player_id) => {
+        update_gaia(update_gaia::Message::Edit(update_gaia::Edit::Redo(player_id)));
+      },
+      ClientToServer::Brush(session, player_id, brush) => {
+        cast(server, player_id).map(|bounds| {
+          apply_brush(server, update_gaia, &session, player_id, terrain::brush::new(&bounds.center(), &brush));
         });
       },
     };
@@ -0,0 +1,162 @@
+//! Server configuration, read from an optional JSON file and overridden from the command line.
+//!
+//! The file is a JSON object whose keys are the same as the command-line flags, e.g.
+//! `{ "seed": 7, "spawn-point": "0,64,4" }` is equivalent to `--seed 7 --spawn-point 0,64,4`.
+//! Any keys left out keep their default values.
+
+use cgmath::Point3;
+use rustc_serialize::json::Json;
+use std::cmp::max;
+use std::default::Default;
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+
+/// Parameters the server is started with.
+#[derive(Debug, Clone)]
+pub struct T {
+  pub listen_url: String,
+  /// The directory the world is saved to.
+  pub world_dir: String,
+  /// Seeds both terrain generation and the server's RNG.
+  pub seed: u32,
+  /// The world spans [-horizontal_extent, horizontal_extent] in x and z.
+  pub horizontal_extent: f32,
+  /// The world spans [-vertical_extent, vertical_extent] in y.
+  pub vertical_extent: f32,
+  pub updates_per_second: u64,
+  /// The length of a full sun cycle, in nanoseconds.
+  pub day_length_ns: u64,
+  /// The low corner of newly-added players.
+  pub spawn_point: Point3<f32>,
+  /// The number of threads generating terrain.
+  pub terrain_threads: u32,
+  /// A JSON file of mob behaviors and spawn rules; the defaults are used if it's None.
+  pub mob_file: Option<String>,
+}
+
+impl Default for T {
+  fn default() -> T {
+    T {
+      listen_url: String::from("ipc:///tmp/server.ipc"),
+      world_dir: String::from("world"),
+      seed: 0,
+      horizontal_extent: (1 << 11) as f32,
+      vertical_extent: 512.0,
+      updates_per_second: 30,
+      day_length_ns: 1600000 << 16,
+      spawn_point: Point3::new(0.0, 64.0, 4.0),
+      terrain_threads: 4,
+      mob_file: None,
+    }
+  }
+}
+
+fn parse<X: FromStr>(key: &str, value: &str) -> X {
+  match value.parse() {
+    Ok(x) => x,
+    Err(_) => panic!("Invalid value for {}: {:?}", key, value),
+  }
+}
+
+fn parse_point(key: &str, value: &str) -> Point3<f32> {
+  let coords: Vec<f32> = value.split(',').map(|x| parse(key, x.trim())).collect();
+  if coords.len() != 3 {
+    panic!("{} should be of the form x,y,z, not {:?}", key, value);
+  }
+  Point3::new(coords[0], coords[1], coords[2])
+}
+
+impl T {
+  /// Set a single configuration parameter by name.
+  pub fn set(&mut self, key: &str, value: &str) {
+    match key {
+      "listen-url" => self.listen_url = String::from(value),
+      "world-dir" => self.world_dir = String::from(value),
+      "seed" => self.seed = parse(key, value),
+      "horizontal-extent" => self.horizontal_extent = parse(key, value),
+      "vertical-extent" => self.vertical_extent = parse(key, value),
+      "updates-per-second" => {
+        self.updates_per_second = parse(key, value);
+        assert!(self.updates_per_second > 0, "updates-per-second must be positive");
+      },
+      "day-length-ns" => self.day_length_ns = parse(key, value),
+      "spawn-point" => self.spawn_point = parse_point(key, value),
+      "terrain-threads" => {
+        self.terrain_threads = parse(key, value);
+        assert!(self.terrain_threads > 0, "terrain-threads must be positive");
+      },
+      "mob-file" => self.mob_file = Some(String::from(value)),
+      _ => panic!("Unrecognized configuration parameter: {:?}", key),
+    }
+  }
+
+  /// Apply the settings in a JSON config file.
+  pub fn load_file(&mut self, path: &str) {
+    let mut contents = String::new();
+    File::open(path)
+      .and_then(|mut file| file.read_to_string(&mut contents))
+      .unwrap_or_else(|err| panic!("Couldn't read config file {:?}: {:?}", path, err));
+
+    let json =
+      Json::from_str(contents.as_ref())
+      .unwrap_or_else(|err| panic!("Couldn't parse config file {:?}: {:?}", path, err));
+    let object =
+      match json {
+        Json::Object(object) => object,
+        _ => panic!("Config file {:?} should contain a JSON object", path),
+      };
+
+    for (key, value) in object.into_iter() {
+      let value =
+        match value {
+          Json::String(s) => s,
+          Json::I64(x) => x.to_string(),
+          Json::U64(x) => x.to_string(),
+          Json::F64(x) => x.to_string(),
+          value => panic!("Unsupported value for {} in {:?}: {:?}", key, path, value),
+        };
+      self.set(key.as_ref(), value.as_ref());
+    }
+  }
+
+  /// Sun ticks happen 2^16 times per day.
+  pub fn sun_tick_ns(&self) -> u64 {
+    max(1, self.day_length_ns >> 16)
+  }
+}
+
+/// Build a configuration from command-line arguments (excluding the program name).
+///
+/// `--config <file>` loads a config file; every other `--<key> <value>` pair overrides a single
+/// parameter, and the settings are applied in order. For compatibility, up to two positional
+/// arguments are accepted as the listen URL and the world directory.
+pub fn from_args<Args>(mut args: Args) -> T
+  where Args: Iterator<Item=String>,
+{
+  let mut config: T = Default::default();
+  let mut positional = 0;
+
+  while let Some(arg) = args.next() {
+    if arg.starts_with("--") {
+      let key = &arg[2..];
+      let value =
+        args.next()
+        .unwrap_or_else(|| panic!("Missing value for {}", arg));
+      if key == "config" {
+        config.load_file(value.as_ref());
+      } else {
+        config.set(key, value.as_ref());
+      }
+    } else {
+      match positional {
+        0 => config.set("listen-url", arg.as_ref()),
+        1 => config.set("world-dir", arg.as_ref()),
+        _ => panic!("Unexpected argument: {:?}", arg),
+      }
+      positional += 1;
+    }
+  }
+
+  config
+}
@@ -0,0 +1,64 @@
+//! Remove clients from the server, along with everything they own.
+
+use time;
+
+use common::communicate::{ClientId, ServerToClient};
+use common::entity::EntityId;
+
+use entities;
+use server::Server;
+
+/// How often clients are pinged.
+pub const PING_INTERVAL_NS: u64 = 5_000_000_000;
+/// Clients that haven't answered a ping in this long are disconnected.
+pub const PING_TIMEOUT_NS: u64 = 30_000_000_000;
+
+/// Ping all the clients, and disconnect the ones that have stopped responding.
+pub fn ping_clients(server: &Server) {
+  let now = time::precise_time_ns();
+  if server.ping_timer.lock().unwrap().update(now) == 0 {
+    return
+  }
+
+  let mut timed_out = Vec::new();
+  for (&id, client) in server.clients.lock().unwrap().iter_mut() {
+    // Receive threads can set `last_ping` after `now` was read.
+    if client.broken || now.saturating_sub(client.last_ping) > PING_TIMEOUT_NS {
+      timed_out.push(id);
+    } else {
+      client.send(ServerToClient::Ping);
+    }
+  }
+
+  for id in timed_out.into_iter() {
+    info!("Client {:?} stopped responding.", id);
+    disconnect(server, id);
+  }
+}
+
+/// Forget a client, and remove its players from the world.
+pub fn disconnect(server: &Server, client_id: ClientId) {
+  let client =
+    match server.clients.lock().unwrap().remove(&client_id) {
+      None => {
+        warn!("Disconnecting unknown client {:?}", client_id);
+        return
+      },
+      Some(client) => client,
+    };
+
+  for &player_id in &client.players {
+    remove_player(server, player_id);
+  }
+
+  // Dropping the client stops its writer thread, which closes its socket once everything queued is sent.
+}
+
+fn remove_player(server: &Server, player_id: EntityId) {
+  entities::remove(server, player_id);
+  server.edit_history.lock().unwrap().remove(player_id);
+
+  for (_, client) in server.clients.lock().unwrap().iter_mut() {
+    client.send(ServerToClient::RemovePlayer(player_id));
+  }
+}
@@ -0,0 +1,255 @@
+//! Players' recent terrain edits, so they can be undone and redone.
+//!
+//! Undoing or redoing puts back every voxel in the area an edit touched, so it would also wipe out anything
+//! other players have done there since. Instead, an undo or redo is refused once another player has edited
+//! any of the same area, and it's dropped from the history.
+
+use cgmath::Aabb3;
+use std::collections::{HashMap, VecDeque};
+
+use common::entity::EntityId;
+
+use terrain::Snapshot;
+
+/// Forget a player's oldest edits once their saved voxels add up to more than this.
+/// The most recent edit is always kept.
+pub const MAX_SAVED_VOXELS: usize = 1 << 21;
+
+// Edits are numbered in the order they're applied.
+type Stamp = u64;
+
+// Something a player can restore, and the edit after which it was saved.
+struct Entry {
+  stamp: Stamp,
+  snapshot: Snapshot,
+}
+
+/// One player's undo and redo stacks.
+struct T {
+  // What each edit overwrote, oldest first.
+  undo: VecDeque<Entry>,
+  // What each undo overwrote, most recently undone last.
+  redo: Vec<Entry>,
+}
+
+impl T {
+  fn push_undo(&mut self, entry: Entry) {
+    self.undo.push_back(entry);
+    let mut saved: usize =
+      self.undo.iter().chain(self.redo.iter()).fold(0, |saved, entry| saved + entry.snapshot.len());
+    while saved > MAX_SAVED_VOXELS && self.undo.len() > 1 {
+      saved -= self.undo.pop_front().unwrap().snapshot.len();
+    }
+  }
+
+  fn oldest_stamp(&self) -> Option<Stamp> {
+    self.undo.iter().chain(self.redo.iter()).map(|entry| entry.stamp).min()
+  }
+}
+
+// An applied edit, which might overlap what other players want to undo.
+struct Edit {
+  stamp: Stamp,
+  player: EntityId,
+  bounds: Aabb3<i32>,
+}
+
+fn overlap(a: &Aabb3<i32>, b: &Aabb3<i32>) -> bool {
+  true
+  && a.min.x <= b.max.x && b.min.x <= a.max.x
+  && a.min.y <= b.max.y && b.min.y <= a.max.y
+  && a.min.z <= b.max.z && b.min.z <= a.max.z
+}
+
+/// Every player's undo and redo history.
+pub struct Histories {
+  players: HashMap<EntityId, T>,
+  next_stamp: Stamp,
+  // Edits that some history entry is older than, oldest first.
+  recent: VecDeque<Edit>,
+}
+
+#[allow(missing_docs)]
+pub fn new() -> Histories {
+  Histories {
+    players: HashMap::new(),
+    next_stamp: 0,
+    recent: VecDeque::new(),
+  }
+}
+
+impl Histories {
+  /// Forget a player's history.
+  pub fn remove(&mut self, player: EntityId) {
+    self.players.remove(&player);
+    self.forget_old_edits();
+  }
+
+  // Note an edit to `bounds`, and return its stamp.
+  fn applied(&mut self, player: EntityId, bounds: &Aabb3<i32>) -> Stamp {
+    let stamp = self.next_stamp;
+    self.next_stamp += 1;
+    self.recent.push_back(Edit {
+      stamp: stamp,
+      player: player,
+      bounds: bounds.clone(),
+    });
+    stamp
+  }
+
+  // Edits can only conflict with history entries newer than them.
+  fn forget_old_edits(&mut self) {
+    let oldest = self.players.values().filter_map(|history| history.oldest_stamp()).min();
+    loop {
+      let old = self.recent.front().map_or(false, |edit| oldest.map_or(true, |oldest| edit.stamp <= oldest));
+      if !old {
+        break
+      }
+      self.recent.pop_front();
+    }
+  }
+
+  // Check that nobody else has edited what an entry would restore since it was saved.
+  fn check(&self, player: EntityId, entry: &Entry) -> Result<(), String> {
+    let conflict =
+      self.recent.iter().any(|edit| {
+        edit.stamp > entry.stamp && edit.player != player && overlap(&edit.bounds, &entry.snapshot.bounds)
+      });
+    if conflict {
+      Err(String::from("Someone else has edited there since, so it can't be restored."))
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Record what a new edit by `player` overwrote. Anything the player undid can't be redone anymore.
+  /// The edit is recorded even if the player has no history, since it might conflict with someone else's.
+  pub fn edited(&mut self, player: EntityId, before: Snapshot, keep_history: bool) {
+    let stamp = self.applied(player, &before.bounds);
+    if keep_history {
+      let history =
+        self.players.entry(player).or_insert_with(|| {
+          T {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+          }
+        });
+      history.redo.clear();
+      history.push_undo(Entry { stamp: stamp, snapshot: before });
+    }
+    self.forget_old_edits();
+  }
+
+  /// Take the contents to restore to undo `player`'s last edit. Returns an error if someone else has edited
+  /// there since, in which case the edit is forgotten.
+  pub fn take_undo(&mut self, player: EntityId) -> Result<Option<Snapshot>, String> {
+    let entry =
+      match self.players.get_mut(&player).and_then(|history| history.undo.pop_back()) {
+        None => return Ok(None),
+        Some(entry) => entry,
+      };
+    let checked = self.check(player, &entry);
+    self.forget_old_edits();
+    checked.map(|()| Some(entry.snapshot))
+  }
+
+  /// Record what undoing an edit overwrote, so it can be redone.
+  pub fn undone(&mut self, player: EntityId, before: Snapshot) {
+    let stamp = self.applied(player, &before.bounds);
+    self.players.get_mut(&player).map(|history| {
+      history.redo.push(Entry { stamp: stamp, snapshot: before });
+    });
+    self.forget_old_edits();
+  }
+
+  /// Take the contents to restore to redo `player`'s last undone edit. Returns an error if someone else has
+  /// edited there since, in which case the undo is forgotten.
+  pub fn take_redo(&mut self, player: EntityId) -> Result<Option<Snapshot>, String> {
+    let entry =
+      match self.players.get_mut(&player).and_then(|history| history.redo.pop()) {
+        None => return Ok(None),
+        Some(entry) => entry,
+      };
+    let checked = self.check(player, &entry);
+    self.forget_old_edits();
+    checked.map(|()| Some(entry.snapshot))
+  }
+
+  /// Record what redoing an edit overwrote, so it can be undone again.
+  pub fn redone(&mut self, player: EntityId, before: Snapshot) {
+    let stamp = self.applied(player, &before.bounds);
+    self.players.get_mut(&player).map(|history| {
+      history.push_undo(Entry { stamp: stamp, snapshot: before });
+    });
+    self.forget_old_edits();
+  }
+}
+
+#[cfg(test)]
+fn cube(low: i32, high: i32) -> Snapshot {
+  use cgmath::Point3;
+  Snapshot::empty(Aabb3::new(Point3::new(low, low, low), Point3::new(high, high, high)))
+}
+
+#[test]
+fn own_overlapping_edits_can_be_undone() {
+  let alice = EntityId::default();
+  let mut histories = new();
+  histories.edited(alice, cube(0, 4), true);
+  histories.edited(alice, cube(2, 6), true);
+
+  let snapshot = histories.take_undo(alice).unwrap().unwrap();
+  assert_eq!(snapshot.bounds.min.x, 2);
+  histories.undone(alice, snapshot);
+  let snapshot = histories.take_undo(alice).unwrap().unwrap();
+  assert_eq!(snapshot.bounds.min.x, 0);
+  histories.undone(alice, snapshot);
+  assert!(histories.take_undo(alice).unwrap().is_none());
+}
+
+#[test]
+fn others_overlapping_edits_refuse_undo() {
+  let alice = EntityId::default();
+  let bob = alice + 1;
+  let mut histories = new();
+  histories.edited(alice, cube(0, 4), true);
+  histories.edited(alice, cube(10, 14), true);
+  histories.edited(bob, cube(4, 8), true);
+
+  // Bob's edit doesn't touch Alice's last one, but it does touch the one before.
+  assert!(histories.take_undo(alice).unwrap().is_some());
+  assert!(histories.take_undo(alice).is_err());
+  // The refused edit is forgotten.
+  assert!(histories.take_undo(alice).unwrap().is_none());
+  assert!(histories.take_undo(bob).unwrap().is_some());
+}
+
+#[test]
+fn new_edits_clear_redo() {
+  let alice = EntityId::default();
+  let mut histories = new();
+  histories.edited(alice, cube(0, 4), true);
+  let snapshot = histories.take_undo(alice).unwrap().unwrap();
+  histories.undone(alice, snapshot);
+  histories.edited(alice, cube(8, 12), true);
+  assert!(histories.take_redo(alice).unwrap().is_none());
+}
+
+#[test]
+fn edits_are_forgotten_once_no_history_predates_them() {
+  let alice = EntityId::default();
+  let bob = alice + 1;
+  let mut histories = new();
+  histories.edited(alice, cube(0, 4), true);
+  histories.edited(bob, cube(0, 4), true);
+  // Only Bob's edit could conflict with Alice's history.
+  assert_eq!(histories.recent.len(), 1);
+
+  histories.remove(alice);
+  histories.remove(bob);
+  assert!(histories.recent.is_empty());
+
+  // Edits by players without history are only kept while someone's history predates them.
+  histories.edited(bob, cube(0, 4), false);
+  assert!(histories.recent.is_empty());
+}
@@ -0,0 +1,138 @@
+//! Entities are ids with some set of components. Each part of the server iterates over just the
+//! components it cares about, so a new kind of entity is a new combination of components.
+//!
+//! Entities' bounds double as their colliders and transforms, and live in `Physics`.
+
+use cgmath::{Aabb3, Point, Point3, Vector3};
+use std::collections::HashMap;
+
+use common::block_position::BlockPosition;
+use common::entity::EntityId;
+use common::lod::{LOD, OwnerId};
+use common::surroundings_loader::{SurroundingsLoader, LoadType};
+
+use mob;
+use player;
+use server::Server;
+use update_gaia;
+
+/// Keeps the terrain around an entity loaded.
+pub struct Loader {
+  pub surroundings: SurroundingsLoader,
+  pub owner: OwnerId,
+  pub lod: LOD,
+}
+
+impl Loader {
+  #[allow(missing_docs)]
+  pub fn new(server: &Server, radius: i32, lod: LOD) -> Loader {
+    Loader {
+      surroundings: SurroundingsLoader::new(radius, Vec::new()),
+      owner: server.owner_allocator.lock().unwrap().allocate(),
+      lod: lod,
+    }
+  }
+
+  /// Load and unload terrain as the entity moves to `position`.
+  pub fn update<RequestBlock>(
+    &mut self,
+    server: &Server,
+    position: &Point3<f32>,
+    request_block: &mut RequestBlock,
+  ) where
+    RequestBlock: FnMut(update_gaia::Message),
+  {
+    let position = BlockPosition::of_world_position(position);
+    for (block, load_type) in self.surroundings.updates(position.as_pnt()) {
+      let block = BlockPosition::of_pnt(&block);
+      match load_type {
+        LoadType::Load | LoadType::Update => {
+          server.terrain_loader.load(
+            &server.id_allocator,
+            &server.physics,
+            &block,
+            self.lod,
+            self.owner,
+            &position,
+            request_block,
+          );
+        },
+        LoadType::Unload => {
+          server.terrain_loader.unload(
+            &server.physics,
+            &block,
+            self.owner,
+          );
+        },
+      }
+    }
+  }
+
+  /// Release all the terrain this loader is keeping loaded.
+  pub fn release(&self, server: &Server) {
+    server.terrain_loader.unload_owner(&server.physics, self.owner);
+  }
+}
+
+/// How clients should draw an entity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+  Player,
+  Mob,
+}
+
+/// Every entity's components, by entity.
+pub struct T {
+  /// Entities moved by a client's inputs.
+  pub controllers: HashMap<EntityId, player::Controller>,
+  /// Entities that move on their own, and how fast.
+  pub velocities: HashMap<EntityId, Vector3<f32>>,
+  /// Entities that decide for themselves where to go.
+  pub behaviors: HashMap<EntityId, mob::Behavior>,
+  /// Entities that keep the terrain around them loaded.
+  pub loaders: HashMap<EntityId, Vec<Loader>>,
+  /// Entities that clients can see.
+  pub shapes: HashMap<EntityId, Shape>,
+  /// The names players chose, which decide where they can edit the terrain.
+  pub names: HashMap<EntityId, String>,
+}
+
+#[allow(missing_docs)]
+pub fn new() -> T {
+  T {
+    controllers: HashMap::new(),
+    velocities: HashMap::new(),
+    behaviors: HashMap::new(),
+    loaders: HashMap::new(),
+    shapes: HashMap::new(),
+    names: HashMap::new(),
+  }
+}
+
+/// The center of an entity's bounds, if it has any.
+pub fn position(server: &Server, id: EntityId) -> Option<Point3<f32>> {
+  server.physics.lock().unwrap().get_bounds(id).map(center)
+}
+
+#[allow(missing_docs)]
+pub fn center(bounds: &Aabb3<f32>) -> Point3<f32> {
+  bounds.min.add_v(&bounds.max.to_vec()).mul_s(0.5)
+}
+
+/// Remove an entity and all its components, and release what it was holding onto.
+pub fn remove(server: &Server, id: EntityId) {
+  let loaders = {
+    let mut entities = server.entities.lock().unwrap();
+    entities.controllers.remove(&id);
+    entities.velocities.remove(&id);
+    entities.behaviors.remove(&id);
+    entities.shapes.remove(&id);
+    entities.names.remove(&id);
+    entities.loaders.remove(&id).unwrap_or(Vec::new())
+  };
+
+  for loader in &loaders {
+    loader.release(server);
+  }
+  server.physics.lock().unwrap().remove_misc(id);
+}
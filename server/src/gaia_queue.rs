@@ -0,0 +1,190 @@
+//! Requests waiting for gaia. Loads are served closest-first instead of in arrival order, but edits are applied
+//! in exactly the order they arrive, ahead of any loads.
+//!
+//! Players move while their loads wait, so a load's distance is checked again when it reaches the front of the
+//! queue, and it's put back if it's gotten farther away.
+
+use cgmath::Point;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::sync::Mutex;
+
+use common::block_position::BlockPosition;
+use common::communicate::ClientId;
+
+use server::Server;
+use update_gaia::{Edit, Load, LoadReason, Message};
+
+/// The squared distance, in blocks, between a block and whoever requested it.
+pub fn distance(block: &BlockPosition, requester: &BlockPosition) -> u32 {
+  let d = block.as_pnt().sub_p(requester.as_pnt());
+  let (x, y, z) = (d.x as i64, d.y as i64, d.z as i64);
+  let d = x * x + y * y + z * z;
+  if d > u32::max_value() as i64 {
+    u32::max_value()
+  } else {
+    d as u32
+  }
+}
+
+/// The squared distance in blocks from a block to the nearest of a client's players.
+pub fn distance_to_client(server: &Server, client_id: ClientId, position: &BlockPosition) -> u32 {
+  let player_ids =
+    server.clients.lock().unwrap()
+    .get(&client_id)
+    .map(|client| client.players.clone())
+    .unwrap_or(Vec::new());
+  let entities = server.entities.lock().unwrap();
+  player_ids.iter()
+    .filter_map(|id| entities.controllers.get(id))
+    .map(|controller| distance(position, &BlockPosition::of_world_position(&controller.movement.position)))
+    .min()
+    // Clients without players don't have anywhere to be near.
+    .unwrap_or(0)
+}
+
+/// How far a load is from whoever asked for it now. Loads for the server's own loaders keep the distance
+/// they were queued with; those loaders unload what they've moved away from.
+pub fn current_distance(server: &Server, load: &Load) -> u32 {
+  match load.reason {
+    LoadReason::Local(_) => load.distance,
+    LoadReason::ForClient(client_id) => distance_to_client(server, client_id, &load.position),
+  }
+}
+
+struct Request {
+  // Lower is served sooner.
+  priority: (u32, u32),
+  // Break ties in arrival order.
+  sequence: u64,
+  load: Load,
+}
+
+impl PartialEq for Request {
+  fn eq(&self, other: &Request) -> bool {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+
+impl Eq for Request {}
+
+impl PartialOrd for Request {
+  fn partial_cmp(&self, other: &Request) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Request {
+  fn cmp(&self, other: &Request) -> Ordering {
+    // `BinaryHeap` pops the greatest element, so the lowest priority value has to compare greatest.
+    (other.priority, other.sequence).cmp(&(self.priority, self.sequence))
+  }
+}
+
+struct Queue {
+  requests: BinaryHeap<Request>,
+  next_sequence: u64,
+  edits: VecDeque<Edit>,
+}
+
+/// A priority queue of requests to gaia, safe to share between threads.
+pub struct T {
+  queue: Mutex<Queue>,
+}
+
+#[allow(missing_docs)]
+pub fn new() -> T {
+  T {
+    queue:
+      Mutex::new(Queue {
+        requests: BinaryHeap::new(),
+        next_sequence: 0,
+        edits: VecDeque::new(),
+      }),
+  }
+}
+
+fn push_load(queue: &mut Queue, load: Load) {
+  let sequence = queue.next_sequence;
+  queue.next_sequence += 1;
+  queue.requests.push(
+    Request {
+      priority: (load.distance, load.lod.0),
+      sequence: sequence,
+      load: load,
+    }
+  );
+}
+
+impl T {
+  #[allow(missing_docs)]
+  pub fn push(&self, message: Message) {
+    let mut queue = self.queue.lock().unwrap();
+    let load =
+      match message {
+        Message::Edit(edit) => {
+          queue.edits.push_back(edit);
+          return
+        },
+        Message::Load(load) => load,
+      };
+    push_load(&mut queue, load);
+  }
+
+  /// Are there edits waiting to be applied?
+  pub fn has_edits(&self) -> bool {
+    !self.queue.lock().unwrap().edits.is_empty()
+  }
+
+  /// Take the oldest edit. To apply edits in order, only take them while holding `Server::edit_history`.
+  pub fn pop_edit(&self) -> Option<Edit> {
+    self.queue.lock().unwrap().edits.pop_front()
+  }
+
+  /// Take the most urgent load, if there are any. `current_distance` says how far a load is from its
+  /// requester now, e.g. `current_distance(server, load)`; it's called without the queue locked.
+  pub fn pop<F>(&self, mut current_distance: F) -> Option<Load>
+    where F: FnMut(&Load) -> u32
+  {
+    // Each load that's gotten farther is put back at most once per call.
+    let mut retries = self.queue.lock().unwrap().requests.len();
+    loop {
+      let mut load =
+        match self.queue.lock().unwrap().requests.pop() {
+          None => return None,
+          Some(request) => request.load,
+        };
+      let distance = current_distance(&load);
+      if distance <= load.distance || retries == 0 {
+        return Some(load)
+      }
+      retries -= 1;
+      load.distance = distance;
+      push_load(&mut self.queue.lock().unwrap(), load);
+    }
+  }
+}
+
+#[test]
+fn loads_that_got_farther_wait_their_turn() {
+  use common::lod::LODIndex;
+  use std::default::Default;
+
+  let queue = new();
+  let load = |x: i32, distance: u32| {
+    Message::Load(Load {
+      position: BlockPosition::new(x, 0, 0),
+      lod: LODIndex(0),
+      reason: LoadReason::ForClient(Default::default()),
+      distance: distance,
+    })
+  };
+  queue.push(load(1, 1));
+  queue.push(load(5, 4));
+
+  // The requester has moved to x = 5 since both were queued.
+  let now = |load: &Load| distance(&load.position, &BlockPosition::new(5, 0, 0));
+  assert_eq!(queue.pop(&now).map(|load| load.position), Some(BlockPosition::new(5, 0, 0)));
+  assert_eq!(queue.pop(&now).map(|load| load.position), Some(BlockPosition::new(1, 0, 0)));
+  assert!(queue.pop(&now).is_none());
+}
@@ -4,20 +4,25 @@ use nanomsg;
 use std;
 use std::convert::AsRef;
 use std::env;
-use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
 use std::sync::Mutex;
+use std::time::Duration;
 use stopwatch;
 use thread_scoped;
 use time;
 
 use common::closure_series;
-use common::socket::ReceiveSocket;
+use common::communicate;
+use common::communicate::ClientToServer;
+use common::transport;
 
 use client_recv_thread::apply_client_update;
+use config;
+use gaia_queue;
 use server::Server;
 use update_gaia;
 use update_gaia::update_gaia;
 use update_world::update_world;
+use world_file;
 
 #[main]
 fn main() {
@@ -25,20 +30,18 @@ fn main() {
 
   let mut args = env::args();
   args.next().unwrap();
-  let listen_url
-    = args.next().unwrap_or(String::from("ipc:///tmp/server.ipc"));
-  assert!(args.next().is_none());
+  let config = config::from_args(args);
+  debug!("{:?}", config);
 
-  info!("Listening on {}.", listen_url);
+  info!("Listening on {}.", config.listen_url);
+  info!("World directory is {}.", config.world_dir);
 
-  let (gaia_send, gaia_recv) = channel();
+  let gaia_queue = gaia_queue::new();
 
-  let gaia_recv = Mutex::new(gaia_recv);
-
-  let listen_socket = ReceiveSocket::new(listen_url.as_ref(), None);
+  let listen_socket = transport::Listener::bind(config.listen_url.as_ref());
   let listen_socket = Mutex::new(listen_socket);
 
-  let server = Server::new();
+  let server = Server::new(&config);
   let server = &server;
 
   let quit_signal = Mutex::new(false);
@@ -47,16 +50,15 @@ fn main() {
 
   unsafe {
     let server = &server;
-    let gaia_send = gaia_send.clone();
-    let gaia_recv = &gaia_recv;
+    let gaia_queue = &gaia_queue;
     let quit_signal = &quit_signal;
     let listen_socket = &listen_socket;
     threads.push(thread_scoped::scoped(move || {
       closure_series::new(vec!(
         quit_upon(&quit_signal),
-        consider_world_update(&server, gaia_send.clone()),
-        network_listen(&listen_socket, server, gaia_send.clone()),
-        consider_gaia_update(&server, &gaia_recv),
+        consider_world_update(&server, gaia_queue),
+        network_listen(&listen_socket, server, gaia_queue),
+        consider_autosave(&server),
       ))
       .until_quit();
 
@@ -65,14 +67,14 @@ fn main() {
   }
   unsafe {
     let server = &server;
-    let gaia_send = gaia_send.clone();
+    let gaia_queue = &gaia_queue;
     let quit_signal = &quit_signal;
     let listen_socket = &listen_socket;
     threads.push(thread_scoped::scoped(move || {
       closure_series::new(vec!(
         quit_upon(&quit_signal),
-        consider_world_update(&server, gaia_send.clone()),
-        network_listen(&listen_socket, server, gaia_send.clone()),
+        consider_world_update(&server, gaia_queue),
+        network_listen(&listen_socket, server, gaia_queue),
       ))
       .until_quit();
 
@@ -80,6 +82,25 @@ fn main() {
     }));
   }
 
+  // Terrain generation is the bulk of the work, so it gets a pool of threads to itself.
+  for _ in 0..config.terrain_threads {
+    unsafe {
+      let server = &server;
+      let gaia_queue = &gaia_queue;
+      let quit_signal = &quit_signal;
+      threads.push(thread_scoped::scoped(move || {
+        closure_series::new(vec!(
+          quit_upon(&quit_signal),
+          consider_gaia_update(&server, gaia_queue),
+          idle(),
+        ))
+        .until_quit();
+
+        stopwatch::clone()
+      }));
+    }
+  }
+
   unsafe {
     let quit_signal = &quit_signal;
     threads.push(thread_scoped::scoped(move || {
@@ -97,9 +118,24 @@ fn main() {
     stopwatch.print();
   }
 
+  {
+    let physics = server.physics.lock().unwrap();
+    info!("Terrain octree: {:?}", physics.terrain_octree.stats());
+    info!("Misc octree: {:?}", physics.misc_octree.stats());
+  }
+
+  save(server);
+
   stopwatch::clone().print();
 }
 
+fn save(server: &Server) {
+  match world_file::save(server, &server.world_dir) {
+    Ok(()) => info!("Saved world to {:?}.", server.world_dir),
+    Err(err) => warn!("Error saving world to {:?}: {:?}", server.world_dir, err),
+  }
+}
+
 fn quit_upon(signal: &Mutex<bool>) -> closure_series::Closure {
   box move || {
     if *signal.lock().unwrap() {
@@ -110,15 +146,15 @@ fn quit_upon(signal: &Mutex<bool>) -> closure_series::Closure {
   }
 }
 
-fn consider_world_update(
-  server: &Server, 
-  to_gaia: Sender<update_gaia::Message>,
-) -> closure_series::Closure {
+fn consider_world_update<'a>(
+  server: &'a Server, 
+  to_gaia: &'a gaia_queue::T,
+) -> closure_series::Closure<'a> {
   box move || {
     if server.update_timer.lock().unwrap().update(time::precise_time_ns()) > 0 {
       update_world(
         server,
-        &to_gaia,
+        to_gaia,
       );
       closure_series::Restart
     } else {
@@ -128,16 +164,38 @@ fn consider_world_update(
 }
 
 fn network_listen<'a>(
-  socket: &'a Mutex<ReceiveSocket>, 
+  socket: &'a Mutex<transport::Listener>, 
   server: &'a Server, 
-  to_gaia: Sender<update_gaia::Message>,
+  to_gaia: &'a gaia_queue::T,
 ) -> closure_series::Closure<'a> {
   box move || {
-    match socket.lock().unwrap().try_read() {
+    let up = socket.lock().unwrap().try_read();
+    match up {
       None => closure_series::Continue,
-      Some(up) => {
-        let up = bincode::rustc_serialize::decode(up.as_ref()).unwrap();
-        apply_client_update(server, &mut |block| { to_gaia.send(block).unwrap() }, up);
+      Some((up, reply)) => {
+        match bincode::rustc_serialize::decode(up.as_ref()) {
+          Ok(up) => {
+            apply_client_update(server, &mut |block| { to_gaia.push(block) }, reply, up);
+          },
+          Err(err) => {
+            // Clients on other protocol versions still encode the start of `Init` the same way,
+            // so we can tell them why we can't understand them.
+            match bincode::rustc_serialize::decode::<(u32, u32, String)>(up.as_ref()) {
+              Ok((0, protocol_version, client_url))
+                if protocol_version != communicate::PROTOCOL_VERSION => {
+                apply_client_update(
+                  server,
+                  &mut |block| { to_gaia.push(block) },
+                  reply,
+                  ClientToServer::Init(protocol_version, client_url, Vec::new()),
+                );
+              },
+              _ => {
+                warn!("Dropping undecodable message ({} bytes): {:?}", up.len(), err);
+              },
+            }
+          },
+        }
         closure_series::Restart
       },
     }
@@ -146,23 +204,41 @@ fn network_listen<'a>(
 
 fn consider_gaia_update<'a>(
   server: &'a Server, 
-  to_gaia: &'a Mutex<Receiver<update_gaia::Message>>,
+  to_gaia: &'a gaia_queue::T,
 ) -> closure_series::Closure<'a> {
   box move || {
-    match to_gaia.lock().unwrap().try_recv() {
-      Ok(up) => {
+    if update_gaia::apply_edits(server, to_gaia) {
+      return closure_series::Restart
+    }
+    match to_gaia.pop(|load| gaia_queue::current_distance(server, load)) {
+      None => closure_series::Continue,
+      Some(up) => {
         update_gaia(server, up);
         closure_series::Restart
       },
-      Err(TryRecvError::Empty) => closure_series::Continue,
-      err => {
-        err.unwrap();
-        unreachable!();
-      },
     }
   }
 }
 
+// Back off briefly when there's nothing to do, instead of spinning on the gaia queue.
+fn idle<'a>() -> closure_series::Closure<'a> {
+  box move || {
+    std::thread::sleep(Duration::from_millis(1));
+    closure_series::Continue
+  }
+}
+
+fn consider_autosave(
+  server: &Server,
+) -> closure_series::Closure {
+  box move || {
+    if server.autosave_timer.lock().unwrap().update(time::precise_time_ns()) > 0 {
+      save(server);
+    }
+    closure_series::Continue
+  }
+}
+
 fn wait_for_quit(
   quit_signal: &Mutex<bool>,
 ) -> closure_series::Closure {
@@ -1,19 +1,437 @@
-use cgmath::{Point3, Vector3};
+//! Mob behaviors, as state machines described by data.
+//!
+//! Each state has an action the mob takes while it's in that state, and a list of transitions to
+//! other states; the first transition whose condition holds is taken. The rules are read from JSON,
+//! in the format of the server's default `mobs.json`.
+
+use cgmath::{Aabb3, EuclideanVector, Point, Point3, Vector, Vector3};
+use rand::Rng;
+use rustc_serialize::json::Json;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Arc;
 
 use common::entity::EntityId;
-use common::lod::OwnerId;
-use common::surroundings_loader::SurroundingsLoader;
 
+use entities;
+use navigation;
 use server::Server;
 
-pub type Behavior = fn(&Server, &mut Mob);
+const DEFAULT_RULES: &'static str = include_str!("../mobs.json");
+// Find a new path once the player is this far from where the old one was going.
+const REPLAN_DISTANCE: f32 = 2.0;
+// After failing to find a path, wait this many updates before trying again.
+const REPLAN_DELAY: u32 = 30;
+// A waypoint is reached once a mob is horizontally this close to it.
+const WAYPOINT_RADIUS: f32 = 0.5;
+
+/// What a mob does while it's in a state.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+  Idle,
+  /// Move toward the nearest player.
+  Follow,
+  /// Move away from the nearest player.
+  Flee,
+  /// Move in a random direction.
+  Wander,
+}
+
+/// Something that happened to a mob since its last update.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+  /// The mob ran into a wall.
+  Bumped,
+}
+
+/// When to leave a state.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+  /// The nearest player is closer than this.
+  Near(f32),
+  /// The nearest player is at least this far away, or there aren't any players.
+  Far(f32),
+  /// The mob has been in this state for this many seconds.
+  After(f32),
+  On(Event),
+}
+
+#[derive(Debug, Clone)]
+pub struct Transition {
+  pub condition: Condition,
+  /// The index of the state to switch to.
+  pub to: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct State {
+  pub name: String,
+  pub action: Action,
+  /// How far the action moves the mob each update.
+  pub speed: f32,
+  pub transitions: Vec<Transition>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Machine {
+  pub states: Vec<State>,
+  pub initial: usize,
+}
+
+/// Where and how many mobs spawn.
+#[derive(Debug, Clone)]
+pub struct SpawnRules {
+  /// Mobs are spawned until each player has this many nearby.
+  pub per_player: usize,
+  /// Mobs spawn on the terrain surface within this horizontal distance range of a player.
+  pub min_distance: f32,
+  pub max_distance: f32,
+  /// Mobs this far from every player are removed.
+  pub despawn_distance: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Rules {
+  pub spawn: SpawnRules,
+  pub behavior: Arc<Machine>,
+}
+
+fn field<'a>(json: &'a Json, key: &str, context: &str) -> Result<&'a Json, String> {
+  json.find(key).ok_or_else(|| format!("{} is missing {:?}", context, key))
+}
+
+fn number(json: &Json, key: &str, context: &str) -> Result<f32, String> {
+  try!(field(json, key, context)).as_f64()
+    .map(|x| x as f32)
+    .ok_or_else(|| format!("{} in {} should be a number", key, context))
+}
+
+fn parse_action(name: &str) -> Result<Action, String> {
+  match name {
+    "idle" => Ok(Action::Idle),
+    "follow" => Ok(Action::Follow),
+    "flee" => Ok(Action::Flee),
+    "wander" => Ok(Action::Wander),
+    _ => Err(format!("Unknown action {:?}", name)),
+  }
+}
+
+fn parse_event(name: &str) -> Result<Event, String> {
+  match name {
+    "bumped" => Ok(Event::Bumped),
+    _ => Err(format!("Unknown event {:?}", name)),
+  }
+}
+
+fn parse_transition(json: &Json, indices: &HashMap<String, usize>, context: &str) -> Result<Transition, String> {
+  let condition =
+    if json.find("near").is_some() {
+      Condition::Near(try!(number(json, "near", context)))
+    } else if json.find("far").is_some() {
+      Condition::Far(try!(number(json, "far", context)))
+    } else if json.find("after").is_some() {
+      Condition::After(try!(number(json, "after", context)))
+    } else {
+      let event =
+        try!(try!(field(json, "on", context)).as_string()
+          .ok_or_else(|| format!("on in {} should be a string", context)));
+      Condition::On(try!(parse_event(event)))
+    };
+
+  let to =
+    try!(try!(field(json, "to", context)).as_string()
+      .ok_or_else(|| format!("to in {} should be a string", context)));
+  let to = try!(indices.get(to).cloned().ok_or_else(|| format!("{} goes to unknown state {:?}", context, to)));
+
+  Ok(Transition {
+    condition: condition,
+    to: to,
+  })
+}
+
+fn parse_machine(json: &Json) -> Result<Machine, String> {
+  let states =
+    try!(try!(field(json, "states", "behavior")).as_object()
+      .ok_or_else(|| String::from("states should be an object")));
+
+  // States refer to each other by name, so number them all first.
+  let indices: HashMap<String, usize> =
+    states.keys().enumerate().map(|(i, name)| (name.clone(), i)).collect();
 
-pub struct Mob {
-  pub position: Point3<f32>,
-  pub speed: Vector3<f32>,
-  pub behavior: Behavior,
+  let mut machine_states = Vec::new();
+  for (name, state) in states.iter() {
+    let context = format!("state {:?}", name);
+    let action =
+      try!(try!(field(state, "action", &context)).as_string()
+        .ok_or_else(|| format!("action in {} should be a string", context)));
+    let speed =
+      if state.find("speed").is_some() {
+        try!(number(state, "speed", &context))
+      } else {
+        0.0
+      };
+    let transitions =
+      match state.find("transitions") {
+        None => Vec::new(),
+        Some(transitions) => {
+          let transitions =
+            try!(transitions.as_array().ok_or_else(|| format!("transitions in {} should be an array", context)));
+          let mut parsed = Vec::new();
+          for transition in transitions {
+            parsed.push(try!(parse_transition(transition, &indices, &context)));
+          }
+          parsed
+        },
+      };
+
+    machine_states.push(State {
+      name: name.clone(),
+      action: try!(parse_action(action)),
+      speed: speed,
+      transitions: transitions,
+    });
+  }
+
+  let initial =
+    try!(try!(field(json, "initial", "behavior")).as_string()
+      .ok_or_else(|| String::from("initial should be a string")));
+  let initial = try!(indices.get(initial).cloned().ok_or_else(|| format!("Unknown initial state {:?}", initial)));
+
+  Ok(Machine {
+    states: machine_states,
+    initial: initial,
+  })
+}
+
+fn parse_rules(contents: &str) -> Result<Rules, String> {
+  let json = try!(Json::from_str(contents).map_err(|err| format!("{:?}", err)));
+
+  let spawn = try!(field(&json, "spawn", "mob rules"));
+  let spawn =
+    SpawnRules {
+      per_player: try!(number(spawn, "per_player", "spawn")) as usize,
+      min_distance: try!(number(spawn, "min_distance", "spawn")),
+      max_distance: try!(number(spawn, "max_distance", "spawn")),
+      despawn_distance: try!(number(spawn, "despawn_distance", "spawn")),
+    };
+  if spawn.min_distance > spawn.max_distance {
+    return Err(String::from("min_distance should be at most max_distance"))
+  }
+
+  let behavior = try!(parse_machine(try!(field(&json, "behavior", "mob rules"))));
+
+  Ok(Rules {
+    spawn: spawn,
+    behavior: Arc::new(behavior),
+  })
+}
+
+/// Read the mob rules from a JSON file, or use the default ones.
+pub fn load_rules(path: Option<&str>) -> Rules {
+  match path {
+    None => parse_rules(DEFAULT_RULES).unwrap_or_else(|err| panic!("Invalid default mob rules: {}", err)),
+    Some(path) => {
+      let mut contents = String::new();
+      File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .unwrap_or_else(|err| panic!("Couldn't read mob file {:?}: {:?}", path, err));
+      parse_rules(contents.as_ref()).unwrap_or_else(|err| panic!("Invalid mob file {:?}: {}", path, err))
+    },
+  }
+}
+
+/// The nearest player to `mob` and the vector to it, if there are any players.
+pub fn nearest_player(server: &Server, mob: EntityId) -> Option<(EntityId, Vector3<f32>)> {
+  let mob_posn =
+    match entities::position(server, mob) {
+      None => return None,
+      Some(posn) => posn,
+    };
+
+  let players: Vec<EntityId> = server.entities.lock().unwrap().controllers.keys().cloned().collect();
+  // Players might have been removed since we listed them.
+  players.into_iter()
+    .filter_map(|id| entities::position(server, id).map(|player_posn| (id, player_posn.sub_p(&mob_posn))))
+    .fold(None, |nearest: Option<(EntityId, Vector3<f32>)>, (id, v)| {
+      match nearest {
+        Some((_, nearest_v)) if nearest_v.length2() <= v.length2() => nearest,
+        _ => Some((id, v)),
+      }
+    })
+}
+
+// Where an entity's feet are.
+fn feet(bounds: &Aabb3<f32>) -> Point3<f32> {
+  let center = entities::center(bounds);
+  Point3::new(center.x, bounds.min.y, center.z)
+}
+
+/// A mob's progress through its state machine.
+#[derive(Clone)]
+pub struct Behavior {
+  machine: Arc<Machine>,
+  state: usize,
+  updates_in_state: u32,
+  events: Vec<Event>,
+  /// Which way to wander.
+  heading: Option<Vector3<f32>>,
+  /// The way to the player being followed.
+  path: Option<navigation::Path>,
+  /// Updates left until looking for a path again.
+  replan_delay: u32,
+}
+
+impl Behavior {
+  #[allow(missing_docs)]
+  pub fn new(machine: Arc<Machine>) -> Behavior {
+    let initial = machine.initial;
+    Behavior {
+      machine: machine,
+      state: initial,
+      updates_in_state: 0,
+      events: Vec::new(),
+      heading: None,
+      path: None,
+      replan_delay: 0,
+    }
+  }
+
+  /// Record something that happened to the mob, for its next update.
+  pub fn notify(&mut self, event: Event) {
+    self.events.push(event);
+  }
+
+  // The direction to walk to follow a player, along a path over the terrain if there is one.
+  fn follow(&mut self, server: &Server, id: EntityId, player: EntityId, to_player: Vector3<f32>) -> Vector3<f32> {
+    let (mob, target) = {
+      let physics = server.physics.lock().unwrap();
+      match (physics.get_bounds(id), physics.get_bounds(player)) {
+        (Some(mob), Some(player)) => (feet(mob), feet(player)),
+        _ => return to_player,
+      }
+    };
+
+    let (stale, search) = {
+      let mut navigation = server.navigation.lock().unwrap();
+      let stale =
+        match self.path {
+          None => true,
+          Some(ref path) => !path.is_valid(&navigation) || path.goal().sub_p(&target).length() > REPLAN_DISTANCE,
+        };
+      (stale, stale && self.replan_delay == 0 && navigation.take_search())
+    };
+    // Mobs that miss out on this update's searches keep following their old path until the next one.
+    if search {
+      // This doesn't hold the navigation lock for the whole search.
+      self.path = navigation::find_path(&server.navigation, &server.terrain_loader.terrain, &mob, &target);
+      if self.path.is_none() {
+        self.replan_delay = REPLAN_DELAY;
+      }
+    } else if stale && self.replan_delay > 0 {
+      self.replan_delay -= 1;
+      self.path = None;
+    }
+
+    match self.path {
+      // Head straight for the player and hope for the best.
+      None => to_player,
+      Some(ref mut path) => {
+        let reached = |waypoint: &Point3<f32>| {
+          Vector3::new(waypoint.x - mob.x, 0.0, waypoint.z - mob.z).length() < WAYPOINT_RADIUS
+        };
+        while path.waypoints.front().map_or(false, |waypoint| reached(waypoint)) {
+          path.waypoints.pop_front();
+        }
+        path.waypoints.front().map_or(to_player, |waypoint| waypoint.sub_p(&mob))
+      },
+    }
+  }
+
+  fn holds(&self, server: &Server, condition: &Condition, to_player: &Option<Vector3<f32>>) -> bool {
+    match *condition {
+      Condition::Near(d) => to_player.map_or(false, |v| v.length() < d),
+      Condition::Far(d) => to_player.map_or(true, |v| v.length() >= d),
+      Condition::After(seconds) => {
+        let ns = self.updates_in_state as f32 * server.tick_ns as f32;
+        ns >= seconds * 1e9
+      },
+      Condition::On(event) => self.events.contains(&event),
+    }
+  }
+
+  /// Update the mob `id`: switch states if a transition applies, then act on its velocity.
+  pub fn update(&mut self, server: &Server, id: EntityId, velocity: &mut Vector3<f32>) {
+    let nearest = nearest_player(server, id);
+    let to_player = nearest.map(|(_, v)| v);
+
+    let next =
+      self.machine.states[self.state].transitions.iter()
+      .find(|transition| self.holds(server, &transition.condition, &to_player))
+      .map(|transition| transition.to);
+    match next {
+      None => self.updates_in_state += 1,
+      Some(next) => {
+        debug!("Mob {:?} is now {:?}", id, self.machine.states[next].name);
+        self.state = next;
+        self.updates_in_state = 0;
+        self.heading = None;
+        self.path = None;
+        self.replan_delay = 0;
+      },
+    }
+
+    let (action, speed) = {
+      let state = &self.machine.states[self.state];
+      (state.action, state.speed)
+    };
+    let direction =
+      match action {
+        Action::Idle => None,
+        Action::Follow => nearest.map(|(player, v)| self.follow(server, id, player, v)),
+        Action::Flee => to_player.map(|v| -v),
+        Action::Wander => {
+          if self.heading.is_none() || self.events.contains(&Event::Bumped) {
+            let angle = server.rng.lock().unwrap().gen_range(0.0, 2.0 * PI);
+            self.heading = Some(Vector3::new(angle.cos(), 0.0, angle.sin()));
+          }
+          self.heading
+        },
+      };
+    self.events.clear();
+
+    // Gravity takes care of the vertical.
+    let horizontal =
+      direction
+      .map(|v| Vector3::new(v.x, 0.0, v.z))
+      .and_then(|v| if v.length2() > 0.0 { Some(v.normalize().mul_s(speed)) } else { None })
+      .unwrap_or(Vector3::new(0.0, 0.0, 0.0));
+    velocity.x = horizontal.x;
+    velocity.z = horizontal.z;
+  }
+}
+
+#[test]
+fn default_rules_parse() {
+  let rules = parse_rules(DEFAULT_RULES).unwrap();
+  let machine = &rules.behavior;
+  assert_eq!(machine.states[machine.initial].name, "it");
+  for state in &machine.states {
+    for transition in &state.transitions {
+      assert!(transition.to < machine.states.len());
+    }
+  }
+}
 
-  pub entity_id: EntityId,
-  pub owner_id: OwnerId,
-  pub surroundings_loader: SurroundingsLoader,
+#[test]
+fn unknown_states_are_rejected() {
+  let rules = r#"{
+    "spawn": { "per_player": 1, "min_distance": 1, "max_distance": 2, "despawn_distance": 3 },
+    "behavior": {
+      "initial": "a",
+      "states": { "a": { "action": "idle", "transitions": [ { "after": 1, "to": "b" } ] } }
+    }
+  }"#;
+  assert!(parse_rules(rules).is_err());
 }
@@ -35,15 +35,25 @@ extern crate time;
 extern crate voxel_data;
 
 mod client_recv_thread;
+mod config;
+mod disconnect;
+mod edit_history;
+mod entities;
+mod gaia_queue;
 mod in_progress_terrain;
-mod init_mobs;
 mod main;
 mod mob;
+mod navigation;
 mod octree;
 mod physics;
 mod player;
+mod protection;
 mod server;
+mod snapshot;
+mod spawn_mobs;
 mod sun;
 mod terrain_loader;
 mod update_gaia;
 mod update_world;
+mod validate;
+mod world_file;
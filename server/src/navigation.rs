@@ -0,0 +1,515 @@
+//! Paths for mobs over the walkable surfaces of the terrain.
+//!
+//! The terrain is treated as columns of 1x1 cells. Every upward-facing surface with room above it
+//! is a node, and neighboring nodes are connected if a walker can step between their heights.
+//! The surfaces are worked out from the meshes of whichever blocks have been generated, as the
+//! searches need them, and forgotten when brushes change those blocks or nobody has them loaded.
+//!
+//! Searches only lock the cache to look blocks up, so they don't hold up brushes, and only a few
+//! are started each world update, so they don't hold up the update either.
+
+use cgmath::{EuclideanVector, Point, Point3};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use common::block_position::BlockPosition;
+use common::lod::LODMap;
+use common::movement::{MAX_STEP_HEIGHT, MIN_FLOOR_NORMAL_Y};
+use common::terrain_block::TerrainBlock;
+
+use terrain::Terrain;
+
+/// How much headroom a surface needs to be walked on.
+pub const CLEARANCE: f32 = 2.0;
+// Surfaces this close above a floor are part of it, rather than in the way.
+const SURFACE_TOLERANCE: f32 = 0.1;
+// How far below a point to look for the floor it's standing (or falling) over.
+const MAX_SNAP_DISTANCE: f32 = 4.0;
+// Give up on a search after expanding this many nodes.
+const MAX_SEARCH_NODES: usize = 4096;
+// Start at most this many searches in each world update.
+const MAX_SEARCHES_PER_UPDATE: u32 = 1;
+// Look for cached blocks that nobody has loaded anymore once every this many world updates.
+const EVICT_INTERVAL: u32 = 100;
+
+const NEIGHBORS: [(i32, i32); 8] = [
+  (1, 0), (-1, 0), (0, 1), (0, -1),
+  (1, 1), (1, -1), (-1, 1), (-1, -1),
+];
+
+/// Somewhere the terrain crosses the vertical line through the center of a column.
+#[derive(Debug, Clone, Copy)]
+pub struct Surface {
+  #[allow(missing_docs)]
+  pub y: f32,
+  /// Is this flat enough to stand on?
+  pub floor: bool,
+}
+
+// A walkable cell: the column's x and z, and its floor height rounded down. Floors in the same column
+// are at least `CLEARANCE` apart, so this is unique.
+type Node = (i32, i32, i32);
+
+fn center(node: &Node, y: f32) -> Point3<f32> {
+  Point3::new(node.0 as f32 + 0.5, y, node.1 as f32 + 0.5)
+}
+
+// Find where the triangles of a block cross the centers of the columns under them.
+fn surfaces_of_block(block: &TerrainBlock) -> HashMap<(i32, i32), Vec<Surface>> {
+  let mut columns = HashMap::new();
+  for (triangle, normals) in block.vertex_coordinates.iter().zip(block.normals.iter()) {
+    let (a, b, c) = (triangle.v1, triangle.v2, triangle.v3);
+    // Twice the signed area of the triangle's shadow on the xz plane.
+    let area = (b.x - a.x) * (c.z - a.z) - (c.x - a.x) * (b.z - a.z);
+    if area.abs() < 1e-6 {
+      // Walls don't cross any column centers.
+      continue
+    }
+    let floor = (normals.v1.y + normals.v2.y + normals.v3.y) / 3.0 >= MIN_FLOOR_NORMAL_Y;
+
+    let low_x = (a.x.min(b.x).min(c.x) - 0.5).ceil() as i32;
+    let high_x = (a.x.max(b.x).max(c.x) - 0.5).floor() as i32;
+    let low_z = (a.z.min(b.z).min(c.z) - 0.5).ceil() as i32;
+    let high_z = (a.z.max(b.z).max(c.z) - 0.5).floor() as i32;
+    for x in low_x .. high_x + 1 {
+    for z in low_z .. high_z + 1 {
+      let (px, pz) = (x as f32 + 0.5, z as f32 + 0.5);
+      // The barycentric coordinates of the column center.
+      let u = ((b.x - px) * (c.z - pz) - (c.x - px) * (b.z - pz)) / area;
+      let v = ((c.x - px) * (a.z - pz) - (a.x - px) * (c.z - pz)) / area;
+      let w = 1.0 - u - v;
+      if u < 0.0 || v < 0.0 || w < 0.0 {
+        continue
+      }
+      columns.entry((x, z)).or_insert_with(Vec::new).push(
+        Surface {
+          y: u * a.y + v * b.y + w * c.y,
+          floor: floor,
+        }
+      );
+    }}
+  }
+  columns
+}
+
+struct CachedBlock {
+  // The LOD of the mesh these surfaces came from.
+  lod: u32,
+  columns: HashMap<(i32, i32), Vec<Surface>>,
+}
+
+/// The walkable surfaces of the terrain, as far as they've been worked out.
+pub struct T {
+  blocks: HashMap<BlockPosition, Arc<CachedBlock>>,
+  // Set to a new number every time a block changes, so paths across it can tell they're stale.
+  // Blocks that are missing are at version 0, so forgetting a version can only make paths stale.
+  versions: HashMap<BlockPosition, u64>,
+  next_version: u64,
+  searches_left: u32,
+  updates_until_evict: u32,
+}
+
+#[allow(missing_docs)]
+pub fn new() -> T {
+  T {
+    blocks: HashMap::new(),
+    versions: HashMap::new(),
+    next_version: 1,
+    searches_left: MAX_SEARCHES_PER_UPDATE,
+    updates_until_evict: EVICT_INTERVAL,
+  }
+}
+
+impl T {
+  /// Forget the surfaces of a block, because it's changed.
+  pub fn invalidate(&mut self, position: &BlockPosition) {
+    self.blocks.remove(position);
+    self.versions.insert(*position, self.next_version);
+    self.next_version += 1;
+  }
+
+  fn version(&self, position: &BlockPosition) -> u64 {
+    self.versions.get(position).cloned().unwrap_or(0)
+  }
+
+  /// Call this at the start of each world update. Returns whether it's time to `forget_unloaded`.
+  pub fn start_update(&mut self) -> bool {
+    self.searches_left = MAX_SEARCHES_PER_UPDATE;
+    if self.updates_until_evict == 0 {
+      self.updates_until_evict = EVICT_INTERVAL;
+      true
+    } else {
+      self.updates_until_evict -= 1;
+      false
+    }
+  }
+
+  /// Use up one of this update's searches. Returns false if there are none left.
+  pub fn take_search(&mut self) -> bool {
+    if self.searches_left == 0 {
+      false
+    } else {
+      self.searches_left -= 1;
+      true
+    }
+  }
+}
+
+/// Forget everything about blocks that nobody has loaded.
+pub fn forget_unloaded(navigation: &Mutex<T>, lod_map: &Mutex<LODMap>) {
+  // Don't hold both locks at once.
+  let known: Vec<BlockPosition> = {
+    let navigation = navigation.lock().unwrap();
+    navigation.blocks.keys().chain(navigation.versions.keys()).cloned().collect()
+  };
+  let unloaded: Vec<BlockPosition> = {
+    let lod_map = lod_map.lock().unwrap();
+    known.into_iter().filter(|position| !lod_map.is_loaded(position)).collect()
+  };
+  let mut navigation = navigation.lock().unwrap();
+  for position in &unloaded {
+    navigation.blocks.remove(position);
+    navigation.versions.remove(position);
+  }
+}
+
+// The surfaces of a block from the finest mesh of it that's been generated, and the block's version
+// when they were worked out. Blocks that haven't been generated at all have no surfaces.
+fn block(navigation: &Mutex<T>, terrain: &Terrain, position: &BlockPosition) -> (u64, Option<Arc<CachedBlock>>) {
+  let (version, cached) = {
+    let navigation = navigation.lock().unwrap();
+    (navigation.version(position), navigation.blocks.get(position).cloned())
+  };
+  let cached_lod = cached.as_ref().map(|block| block.lod);
+  if cached_lod == Some(0) {
+    return (version, cached)
+  }
+
+  let finer: Option<(u32, TerrainBlock)> = {
+    let all_blocks = terrain.all_blocks.lock().unwrap();
+    all_blocks.0.get(position).and_then(|mip_mesh| {
+      mip_mesh.lods.iter().position(|mesh| mesh.is_some())
+        .map(|lod| lod as u32)
+        .and_then(|lod| {
+          if cached_lod.map_or(true, |cached_lod| lod < cached_lod) {
+            mip_mesh.lods[lod as usize].as_ref().map(|mesh| (lod, mesh.clone()))
+          } else {
+            None
+          }
+        })
+    })
+  };
+  match finer {
+    None => (version, cached),
+    Some((lod, mesh)) => {
+      let block =
+        Arc::new(CachedBlock {
+          lod: lod,
+          columns: surfaces_of_block(&mesh),
+        });
+      let mut navigation = navigation.lock().unwrap();
+      // If the block changed while we were looking at it, this mesh might be out of date.
+      if navigation.version(position) == version {
+        navigation.blocks.insert(*position, block.clone());
+      }
+      (version, Some(block))
+    },
+  }
+}
+
+// Looks up the blocks a search needs, once each.
+struct Searcher<'a> {
+  navigation: &'a Mutex<T>,
+  terrain: &'a Terrain,
+  // Every block looked at, and its version when it was.
+  seen: HashMap<BlockPosition, (u64, Option<Arc<CachedBlock>>)>,
+}
+
+impl<'a> Searcher<'a> {
+  fn block(&mut self, position: &BlockPosition) -> Option<Arc<CachedBlock>> {
+    match self.seen.get(position) {
+      None => {},
+      Some(&(_, ref block)) => return block.clone(),
+    }
+    let (version, block) = block(self.navigation, self.terrain, position);
+    self.seen.insert(*position, (version, block.clone()));
+    block
+  }
+
+  // Every surface crossing the center of column (x, z) between heights `low` and `high`, lowest first.
+  fn surfaces(&mut self, x: i32, z: i32, low: f32, high: f32) -> Vec<Surface> {
+    // Triangles poke a little out of their blocks, so look in the neighboring blocks too.
+    let low_block = BlockPosition::of_world_position(&Point3::new(x as f32 - 1.0, low - 1.0, z as f32 - 1.0));
+    let high_block = BlockPosition::of_world_position(&Point3::new(x as f32 + 1.0, high + 1.0, z as f32 + 1.0));
+    let (low_block, high_block) = (low_block.as_pnt(), high_block.as_pnt());
+
+    let mut surfaces = Vec::new();
+    for bx in low_block.x .. high_block.x + 1 {
+    for by in low_block.y .. high_block.y + 1 {
+    for bz in low_block.z .. high_block.z + 1 {
+      self.block(&BlockPosition::new(bx, by, bz)).map(|block| {
+        block.columns.get(&(x, z)).map(|column| {
+          surfaces.extend(column.iter().filter(|s| s.y >= low && s.y <= high).cloned());
+        });
+      });
+    }}}
+    surfaces.sort_by(|a, b| a.y.partial_cmp(&b.y).unwrap_or(Ordering::Equal));
+    surfaces
+  }
+}
+
+/// Find a walkable path between two points, which are where the walker's feet are.
+/// `navigation` is only locked while blocks are looked up, not for the whole search.
+pub fn find_path(navigation: &Mutex<T>, terrain: &Terrain, from: &Point3<f32>, to: &Point3<f32>) -> Option<Path> {
+  let mut searcher =
+    Searcher {
+      navigation: navigation,
+      terrain: terrain,
+      seen: HashMap::new(),
+    };
+  let waypoints = {
+    let mut surfaces = |x, z, low, high| searcher.surfaces(x, z, low, high);
+    match search(&mut surfaces, from, to) {
+      None => return None,
+      Some(waypoints) => waypoints,
+    }
+  };
+
+  let mut blocks = HashSet::new();
+  for waypoint in &waypoints {
+    // The floor might belong to the block under the waypoint.
+    blocks.insert(BlockPosition::of_world_position(waypoint));
+    blocks.insert(BlockPosition::of_world_position(&Point3::new(waypoint.x, waypoint.y - 0.5, waypoint.z)));
+  }
+
+  // Use the versions the search saw, so changes made during the search make the path stale.
+  let blocks: Vec<(BlockPosition, u64)> = {
+    let navigation = navigation.lock().unwrap();
+    blocks.into_iter()
+      .map(|position| {
+        let version =
+          searcher.seen.get(&position)
+          .map(|&(version, _)| version)
+          .unwrap_or_else(|| navigation.version(&position));
+        (position, version)
+      })
+      .collect()
+  };
+
+  Some(Path {
+    waypoints: waypoints.into_iter().collect(),
+    blocks: blocks,
+    goal: *to,
+  })
+}
+
+/// A walkable route across the terrain.
+#[derive(Debug, Clone)]
+pub struct Path {
+  /// The points left to walk through, in order. Each one is on a floor, in the center of a column.
+  pub waypoints: VecDeque<Point3<f32>>,
+  // The blocks the path crosses, and their versions when it was found.
+  blocks: Vec<(BlockPosition, u64)>,
+  goal: Point3<f32>,
+}
+
+impl Path {
+  /// Is the terrain this path crosses unchanged since it was found?
+  pub fn is_valid(&self, navigation: &T) -> bool {
+    self.blocks.iter().all(|&(ref position, version)| navigation.version(position) == version)
+  }
+
+  /// Where the path was asked to go.
+  pub fn goal(&self) -> &Point3<f32> {
+    &self.goal
+  }
+}
+
+struct Open {
+  // The cost so far plus the estimated cost to the goal.
+  estimate: f32,
+  node: Node,
+}
+
+impl PartialEq for Open {
+  fn eq(&self, other: &Open) -> bool {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+
+impl Eq for Open {}
+
+impl PartialOrd for Open {
+  fn partial_cmp(&self, other: &Open) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Open {
+  fn cmp(&self, other: &Open) -> Ordering {
+    // `BinaryHeap` pops the greatest element, so the lowest estimate has to compare greatest.
+    other.estimate.partial_cmp(&self.estimate).unwrap_or(Ordering::Equal)
+  }
+}
+
+// The walkable floor in column (x, z) between heights `low` and `high` that's closest to `near`.
+fn floor_in<Surfaces>(surfaces: &mut Surfaces, x: i32, z: i32, low: f32, high: f32, near: f32) -> Option<f32>
+  where Surfaces: FnMut(i32, i32, f32, f32) -> Vec<Surface>
+{
+  let column = surfaces(x, z, low, high + CLEARANCE);
+  let mut best: Option<f32> = None;
+  for (i, surface) in column.iter().enumerate() {
+    if !surface.floor || surface.y > high {
+      continue
+    }
+    let blocked =
+      column[i + 1 ..].iter()
+      .any(|above| above.y > surface.y + SURFACE_TOLERANCE && above.y < surface.y + CLEARANCE);
+    if blocked {
+      continue
+    }
+    if best.map_or(true, |best| (surface.y - near).abs() < (best - near).abs()) {
+      best = Some(surface.y);
+    }
+  }
+  best
+}
+
+// The node of the floor under a point, and the floor's height.
+fn snap<Surfaces>(surfaces: &mut Surfaces, p: &Point3<f32>) -> Option<(Node, f32)>
+  where Surfaces: FnMut(i32, i32, f32, f32) -> Vec<Surface>
+{
+  let (x, z) = (p.x.floor() as i32, p.z.floor() as i32);
+  floor_in(surfaces, x, z, p.y - MAX_SNAP_DISTANCE, p.y + MAX_STEP_HEIGHT, p.y)
+    .map(|y| ((x, z, y.floor() as i32), y))
+}
+
+// The floor reached by stepping (dx, dz) from height `y` in column (x, z).
+fn step<Surfaces>(surfaces: &mut Surfaces, x: i32, z: i32, y: f32, dx: i32, dz: i32) -> Option<f32>
+  where Surfaces: FnMut(i32, i32, f32, f32) -> Vec<Surface>
+{
+  if dx != 0 && dz != 0 {
+    // Don't cut corners.
+    if step(surfaces, x, z, y, dx, 0).is_none() || step(surfaces, x, z, y, 0, dz).is_none() {
+      return None
+    }
+  }
+  floor_in(surfaces, x + dx, z + dz, y - MAX_STEP_HEIGHT, y + MAX_STEP_HEIGHT, y)
+}
+
+// A* from the floor under `from` to the floor under `to`.
+// Returns the points along the way, including the end but not the start.
+fn search<Surfaces>(surfaces: &mut Surfaces, from: &Point3<f32>, to: &Point3<f32>) -> Option<Vec<Point3<f32>>>
+  where Surfaces: FnMut(i32, i32, f32, f32) -> Vec<Surface>
+{
+  let (start, start_y) =
+    match snap(surfaces, from) {
+      None => return None,
+      Some(start) => start,
+    };
+  let (goal, goal_y) =
+    match snap(surfaces, to) {
+      None => return None,
+      Some(goal) => goal,
+    };
+  let goal_point = center(&goal, goal_y);
+  let estimate = |node: &Node, y: f32| center(node, y).sub_p(&goal_point).length();
+
+  let mut heights = HashMap::new();
+  let mut costs = HashMap::new();
+  let mut parents = HashMap::new();
+  let mut closed = HashSet::new();
+  let mut open = BinaryHeap::new();
+
+  heights.insert(start, start_y);
+  costs.insert(start, 0.0);
+  open.push(Open { estimate: estimate(&start, start_y), node: start });
+
+  while let Some(Open { node, .. }) = open.pop() {
+    if node == goal {
+      let mut waypoints = Vec::new();
+      let mut node = node;
+      while node != start {
+        waypoints.push(center(&node, heights[&node]));
+        node = parents[&node];
+      }
+      waypoints.reverse();
+      return Some(waypoints)
+    }
+
+    if !closed.insert(node) {
+      continue
+    }
+    if closed.len() > MAX_SEARCH_NODES {
+      debug!("Gave up looking for a path from {:?} to {:?}", from, to);
+      return None
+    }
+
+    let y = heights[&node];
+    let cost = costs[&node];
+    for &(dx, dz) in &NEIGHBORS {
+      let next_y =
+        match step(surfaces, node.0, node.1, y, dx, dz) {
+          None => continue,
+          Some(next_y) => next_y,
+        };
+      let next = (node.0 + dx, node.1 + dz, next_y.floor() as i32);
+      if closed.contains(&next) {
+        continue
+      }
+
+      let next_cost = cost + ((dx * dx + dz * dz) as f32).sqrt() + (next_y - y).abs();
+      if costs.get(&next).map_or(false, |&c| c <= next_cost) {
+        continue
+      }
+      costs.insert(next, next_cost);
+      heights.insert(next, next_y);
+      parents.insert(next, node);
+      open.push(Open { estimate: next_cost + estimate(&next, next_y), node: next });
+    }
+  }
+
+  None
+}
+
+#[cfg(test)]
+// The surfaces of a heightmap that's only defined on [-8, 8] in x and z.
+fn heightmap<Height>(height: &Height, x: i32, z: i32, low: f32, high: f32) -> Vec<Surface>
+  where Height: Fn(i32, i32) -> f32
+{
+  if x.abs() > 8 || z.abs() > 8 {
+    return Vec::new()
+  }
+  let y = height(x, z);
+  if y < low || y > high {
+    Vec::new()
+  } else {
+    vec!(Surface { y: y, floor: true })
+  }
+}
+
+#[test]
+fn paths_climb_steps() {
+  let steps = |x: i32, _: i32| if x >= 3 { MAX_STEP_HEIGHT } else { 0.0 };
+  let mut surfaces = |x, z, low, high| heightmap(&steps, x, z, low, high);
+  let path = search(&mut surfaces, &Point3::new(0.5, 0.0, 0.5), &Point3::new(6.5, MAX_STEP_HEIGHT, 0.5)).unwrap();
+  assert_eq!(path.len(), 6);
+  let end = path[path.len() - 1];
+  assert_eq!((end.x, end.y, end.z), (6.5, MAX_STEP_HEIGHT, 0.5));
+}
+
+#[test]
+fn paths_go_around_walls() {
+  let wall = |x: i32, z: i32| if x == 3 && z != 5 { 3.0 * MAX_STEP_HEIGHT } else { 0.0 };
+  let mut surfaces = |x, z, low, high| heightmap(&wall, x, z, low, high);
+  let path = search(&mut surfaces, &Point3::new(0.5, 0.0, 0.5), &Point3::new(6.5, 0.0, 0.5)).unwrap();
+  for waypoint in &path {
+    assert_eq!(waypoint.y, 0.0);
+  }
+  assert!(path.iter().any(|waypoint| waypoint.x == 3.5 && waypoint.z == 5.5));
+
+  let wall = |x: i32, _: i32| if x == 3 { 3.0 * MAX_STEP_HEIGHT } else { 0.0 };
+  let mut surfaces = |x, z, low, high| heightmap(&wall, x, z, low, high);
+  assert!(search(&mut surfaces, &Point3::new(0.5, 0.0, 0.5), &Point3::new(6.5, 0.0, 0.5)).is_none());
+}
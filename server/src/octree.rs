@@ -1,8 +1,22 @@
-use cgmath::{Aabb, Aabb3, Point3};
+use cgmath::{Aabb, Aabb3, Point3, Vector3};
+use std::cmp;
 use std::fmt::Debug;
 use std::ptr;
 
+use common::collision;
+use common::collision::Impact;
+
+#[cfg(test)]
+use rand::{Rng, SeedableRng, XorShiftRng};
+#[cfg(test)]
+use test::{Bencher, black_box};
+
 pub const MIN_CELL_WIDTH: f32 = 0.1;
+// Leaves holding more than this many objects are split.
+pub const MAX_LEAF_ITEMS: usize = 8;
+// Branches holding at most this many objects are collapsed back into leaves. This is lower than
+// MAX_LEAF_ITEMS, so that objects moving around a cell boundary don't split and collapse it every update.
+pub const MIN_BRANCH_ITEMS: usize = MAX_LEAF_ITEMS / 2;
 
 fn aabb_overlap(aabb1: &Aabb3<f32>, aabb2: &Aabb3<f32>) -> bool {
   true
@@ -14,6 +28,49 @@ fn aabb_overlap(aabb1: &Aabb3<f32>, aabb2: &Aabb3<f32>) -> bool {
   && aabb2.min.z < aabb1.max.z
 }
 
+// Like aabb_overlap, but boxes that only share a face count too.
+fn aabb_touch(aabb1: &Aabb3<f32>, aabb2: &Aabb3<f32>) -> bool {
+  true
+  && aabb1.min.x <= aabb2.max.x
+  && aabb1.min.y <= aabb2.max.y
+  && aabb1.min.z <= aabb2.max.z
+  && aabb2.min.x <= aabb1.max.x
+  && aabb2.min.y <= aabb1.max.y
+  && aabb2.min.z <= aabb1.max.z
+}
+
+// Whether the segment from `origin` to `origin + v` touches `bounds`.
+fn segment_touches(bounds: &Aabb3<f32>, origin: &Point3<f32>, v: &Vector3<f32>) -> bool {
+  let mut t_min: f32 = 0.0;
+  let mut t_max: f32 = 1.0;
+  for &d in &[Dimension::X, Dimension::Y, Dimension::Z] {
+    let o = get(d, origin);
+    let (low, high) = (get(d, &bounds.min), get(d, &bounds.max));
+    let dv = match d { Dimension::X => v.x, Dimension::Y => v.y, Dimension::Z => v.z };
+    if dv == 0.0 {
+      if o < low || o > high {
+        return false
+      }
+    } else {
+      let (t1, t2) = ((low - o) / dv, (high - o) / dv);
+      let (t1, t2) = if t1 <= t2 { (t1, t2) } else { (t2, t1) };
+      t_min = t_min.max(t1);
+      t_max = t_max.min(t2);
+      if t_min > t_max {
+        return false
+      }
+    }
+  }
+  true
+}
+
+fn union(aabb1: &Aabb3<f32>, aabb2: &Aabb3<f32>) -> Aabb3<f32> {
+  Aabb3::new(
+    Point3::new(aabb1.min.x.min(aabb2.min.x), aabb1.min.y.min(aabb2.min.y), aabb1.min.z.min(aabb2.min.z)),
+    Point3::new(aabb1.max.x.max(aabb2.max.x), aabb1.max.y.max(aabb2.max.y), aabb1.max.z.max(aabb2.max.z)),
+  )
+}
+
 fn contains(aabb1: &Aabb3<f32>, aabb2: &Aabb3<f32>) -> bool {
   true
   && aabb1.min.x <= aabb2.min.x
@@ -82,6 +139,25 @@ enum OctreeContents<V> {
   Branch(Branches<V>),
 }
 
+// The shape of an octree, for keeping an eye on its performance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+  // The depth of the deepest leaf; a tree with just a root has depth 0.
+  pub depth: usize,
+  pub nodes: usize,
+  pub leaves: usize,
+  // Objects split across several leaves are counted once per leaf.
+  pub leaf_items: usize,
+  pub max_leaf_items: usize,
+}
+
+impl Stats {
+  #[allow(dead_code)]
+  pub fn mean_leaf_items(&self) -> f32 {
+    self.leaf_items as f32 / self.leaves as f32
+  }
+}
+
 // TODO: allow inserting things with a "mobile" flag; don't subdivide those objects.
 pub struct Octree<V> {
   parent: *mut Octree<V>,
@@ -120,7 +196,7 @@ impl<V: Debug + Copy + Eq + PartialOrd> Octree<V> {
 
         let l = length(&self.bounds, self.dimension);
         let should_bisect_cell =
-          l > MIN_CELL_WIDTH && avg_length < length(&self.bounds, self.dimension) / 2.0;
+          vs.len() > MAX_LEAF_ITEMS && l > MIN_CELL_WIDTH && avg_length < l / 2.0;
         if should_bisect_cell {
           let (low, high) =
             Octree::bisect(
@@ -130,8 +206,8 @@ impl<V: Debug + Copy + Eq + PartialOrd> Octree<V> {
               vs
             );
           Some(OctreeContents::Branch(Branches {
-            low_tree: Box::new(low),
-            high_tree: Box::new(high),
+            low_tree: low,
+            high_tree: high,
           }))
         } else {
           None
@@ -155,7 +231,7 @@ impl<V: Debug + Copy + Eq + PartialOrd> Octree<V> {
     bounds: &Aabb3<f32>,
     dimension: Dimension,
     vs: &LeafContents<V>
-  ) -> (Octree<V>, Octree<V>) {
+  ) -> (Box<Octree<V>>, Box<Octree<V>>) {
     let mid = middle(bounds, dimension);
     let (low_bounds, high_bounds) = split(mid, dimension, bounds.clone());
     let low_bounds = low_bounds.unwrap();
@@ -166,18 +242,20 @@ impl<V: Debug + Copy + Eq + PartialOrd> Octree<V> {
         Dimension::Z => Dimension::X,
       };
 
-    let mut low = Octree {
+    // Box the subtrees before inserting into them, so that if they split too, their children point at
+    // where they'll stay.
+    let mut low = Box::new(Octree {
       parent: parent,
       dimension: new_d,
       bounds: low_bounds.clone(),
       contents: OctreeContents::Leaf(Vec::new()),
-    };
-    let mut high = Octree {
+    });
+    let mut high = Box::new(Octree {
       parent: parent,
       dimension: new_d,
       bounds: high_bounds.clone(),
       contents: OctreeContents::Leaf(Vec::new()),
-    };
+    });
 
     for &(ref bounds, v) in vs.iter() {
       let (low_bounds, high_bounds) = split(mid, dimension, bounds.clone());
@@ -188,6 +266,33 @@ impl<V: Debug + Copy + Eq + PartialOrd> Octree<V> {
     (low, high)
   }
 
+  // If both subtrees are leaves with few enough objects between them, the contents of a leaf holding
+  // everything in both. Objects split between the two are joined back together.
+  fn merge(low: &Octree<V>, high: &Octree<V>) -> Option<LeafContents<V>> {
+    match (&low.contents, &high.contents) {
+      (&OctreeContents::Leaf(ref low), &OctreeContents::Leaf(ref high)) => {
+        if low.len() > MIN_BRANCH_ITEMS || high.len() > MIN_BRANCH_ITEMS {
+          return None
+        }
+
+        let mut vs = low.clone();
+        for &(ref bounds, v) in high.iter() {
+          match vs.iter().position(|&(_, x)| x == v) {
+            None => vs.push((bounds.clone(), v)),
+            Some(i) => vs[i].0 = union(&vs[i].0, bounds),
+          }
+        }
+
+        if vs.len() <= MIN_BRANCH_ITEMS {
+          Some(vs)
+        } else {
+          None
+        }
+      },
+      _ => None,
+    }
+  }
+
   #[allow(dead_code)]
   fn on_ancestor<T, F>(&self, bounds: &Aabb3<f32>, mut f: F) -> T
     where F: FnMut(&Octree<V>) -> T
@@ -218,6 +323,7 @@ impl<V: Debug + Copy + Eq + PartialOrd> Octree<V> {
   // Find whether there are objects overlapping the object & bounds provided in
   // this/child trees. Uses equality comparison on V to ignore "same" objects.
   // Returns the value associated with the first object intersected.
+  #[allow(dead_code)]
   pub fn intersect(&self, bounds: &Aabb3<f32>, self_v: Option<V>) -> Option<(Aabb3<f32>, V)> {
     match self.contents {
       OctreeContents::Leaf(ref vs) => {
@@ -246,6 +352,70 @@ impl<V: Debug + Copy + Eq + PartialOrd> Octree<V> {
     }
   }
 
+  // Find the first object that `bounds` would run into while moving by `v`, ignoring objects equal to `self_v`.
+  // Returns the impact, and the value associated with the object hit.
+  pub fn sweep(&self, bounds: &Aabb3<f32>, v: &Vector3<f32>, self_v: Option<V>) -> Option<(Impact, V)> {
+    let mut first: Option<(Impact, V)> = None;
+    self.each_within(&collision::swept_bounds(bounds, v), &mut |bs, x| {
+      if Some(x) == self_v {
+        return
+      }
+      collision::sweep(bounds, v, bs).map(|impact| {
+        let is_first = first.as_ref().map_or(true, |&(ref f, _)| impact.toi < f.toi);
+        if is_first {
+          first = Some((impact, x));
+        }
+      });
+    });
+    first
+  }
+
+  // Call `f` on every object whose bounds touch `region`. Objects that span several cells are passed
+  // once per cell, with the part of their bounds inside that cell.
+  pub fn each_within<F>(&self, region: &Aabb3<f32>, f: &mut F)
+    where F: FnMut(&Aabb3<f32>, V)
+  {
+    match self.contents {
+      OctreeContents::Leaf(ref vs) => {
+        for &(ref bs, v) in vs.iter() {
+          if aabb_touch(region, bs) {
+            f(bs, v);
+          }
+        }
+      },
+      OctreeContents::Branch(ref b) => {
+        let mid = middle(&self.bounds, self.dimension);
+        let (low_region, high_region) = split(mid, self.dimension, region.clone());
+        low_region.map(|r| b.low_tree.each_within(&r, f));
+        high_region.map(|r| b.high_tree.each_within(&r, f));
+      },
+    }
+  }
+
+  // Call `f` on every object whose bounds touch the segment from `origin` to `origin + v`. Objects that span
+  // several cells are passed once per cell, like in each_within.
+  pub fn each_on_segment<F>(&self, origin: &Point3<f32>, v: &Vector3<f32>, f: &mut F)
+    where F: FnMut(&Aabb3<f32>, V)
+  {
+    if !segment_touches(&self.bounds, origin, v) {
+      return
+    }
+
+    match self.contents {
+      OctreeContents::Leaf(ref vs) => {
+        for &(ref bs, x) in vs.iter() {
+          if segment_touches(bs, origin, v) {
+            f(bs, x);
+          }
+        }
+      },
+      OctreeContents::Branch(ref b) => {
+        b.low_tree.each_on_segment(origin, v, f);
+        b.high_tree.each_on_segment(origin, v, f);
+      },
+    }
+  }
+
   // like insert, but before recursing downward, we recurse up the parents
   // until the bounds provided are inside the tree.
   fn insert_from(&mut self, bounds: Aabb3<f32>, v: V) {
@@ -264,25 +434,41 @@ impl<V: Debug + Copy + Eq + PartialOrd> Octree<V> {
             vs.swap_remove(i);
           },
         };
-        false
+        None
       },
       OctreeContents::Branch(ref mut bs) => {
         let (l, h) = split(middle(&self.bounds, self.dimension), self.dimension, bounds.clone());
         l.map(|low_half| bs.low_tree.remove(&low_half, v));
         h.map(|high_half| bs.high_tree.remove(&high_half, v));
-        bs.low_tree.is_empty() && bs.high_tree.is_empty()
+        Octree::merge(&bs.low_tree, &bs.high_tree)
       }
     };
 
-    if collapse_contents {
-      self.contents = OctreeContents::Leaf(Vec::new());
-    }
+    collapse_contents.map(|vs| self.contents = OctreeContents::Leaf(vs));
   }
 
-  pub fn is_empty(&self) -> bool {
+  pub fn stats(&self) -> Stats {
     match self.contents {
-      OctreeContents::Leaf(ref vs) => vs.is_empty(),
-      _ => false,
+      OctreeContents::Leaf(ref vs) => {
+        Stats {
+          depth: 0,
+          nodes: 1,
+          leaves: 1,
+          leaf_items: vs.len(),
+          max_leaf_items: vs.len(),
+        }
+      },
+      OctreeContents::Branch(ref b) => {
+        let low = b.low_tree.stats();
+        let high = b.high_tree.stats();
+        Stats {
+          depth: 1 + cmp::max(low.depth, high.depth),
+          nodes: 1 + low.nodes + high.nodes,
+          leaves: low.leaves + high.leaves,
+          leaf_items: low.leaf_items + high.leaf_items,
+          max_leaf_items: cmp::max(low.max_leaf_items, high.max_leaf_items),
+        }
+      },
     }
   }
 
@@ -291,3 +477,195 @@ impl<V: Debug + Copy + Eq + PartialOrd> Octree<V> {
     self.insert_from(new_bounds, v)
   }
 }
+
+#[cfg(test)]
+fn world() -> Aabb3<f32> {
+  Aabb3::new(Point3::new(-64.0, -64.0, -64.0), Point3::new(64.0, 64.0, 64.0))
+}
+
+#[cfg(test)]
+fn random_box<R: Rng>(rng: &mut R, max_size: f32) -> Aabb3<f32> {
+  let min =
+    Point3::new(
+      rng.gen_range(-64.0, 64.0 - max_size),
+      rng.gen_range(-64.0, 64.0 - max_size),
+      rng.gen_range(-64.0, 64.0 - max_size),
+    );
+  let max =
+    Point3::new(
+      min.x + rng.gen_range(0.0, max_size),
+      min.y + rng.gen_range(0.0, max_size),
+      min.z + rng.gen_range(0.0, max_size),
+    );
+  Aabb3::new(min, max)
+}
+
+#[cfg(test)]
+// Boxes like the bounds of terrain triangles: a thin, bumpy layer over a square.
+fn terrain_like(width: i32) -> Vec<(Aabb3<f32>, u32)> {
+  let mut boxes = Vec::new();
+  for x in -width / 2 .. width / 2 {
+  for z in -width / 2 .. width / 2 {
+    let (fx, fz) = (x as f32, z as f32);
+    let y = 4.0 * (fx / 8.0).sin() * (fz / 8.0).cos();
+    let dy = (fx / 8.0).cos().abs() / 2.0;
+    let bounds = Aabb3::new(Point3::new(fx, y, fz), Point3::new(fx + 1.0, y + dy, fz + 1.0));
+    boxes.push((bounds, boxes.len() as u32));
+  }}
+  boxes
+}
+
+#[test]
+fn intersect_matches_brute_force() {
+  let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+  let mut tree = Octree::new(&world());
+  let mut boxes: Vec<(Aabb3<f32>, u32)> = Vec::new();
+  for i in 0 .. 400 {
+    let bounds = random_box(&mut rng, 8.0);
+    tree.insert(bounds.clone(), i);
+    boxes.push((bounds, i));
+  }
+  // Remove some, so collapsed branches get checked too.
+  for _ in 0 .. 200 {
+    let i = rng.gen_range(0, boxes.len());
+    let (bounds, v) = boxes.swap_remove(i);
+    tree.remove(&bounds, v);
+  }
+
+  for _ in 0 .. 1000 {
+    let query = random_box(&mut rng, 16.0);
+    let expected = boxes.iter().any(|&(ref bounds, _)| aabb_overlap(&query, bounds));
+    match tree.intersect(&query, None) {
+      None => assert!(!expected, "missed an intersection with {:?}", query),
+      Some((_, v)) => {
+        let &(ref bounds, _) = boxes.iter().find(|&&(_, x)| x == v).unwrap();
+        assert!(aabb_overlap(&query, bounds));
+      },
+    }
+
+    let mut found = Vec::new();
+    tree.each_within(&query, &mut |_, v| found.push(v));
+    found.sort();
+    found.dedup();
+    let mut expected: Vec<_> =
+      boxes.iter()
+      .filter(|&&(ref bounds, _)| aabb_touch(&query, bounds))
+      .map(|&(_, v)| v)
+      .collect();
+    expected.sort();
+    assert_eq!(found, expected);
+  }
+}
+
+#[test]
+fn intersect_ignores_self() {
+  let mut tree = Octree::new(&world());
+  let bounds = Aabb3::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 1.0));
+  tree.insert(bounds.clone(), 0);
+  assert!(tree.intersect(&bounds, Some(0)).is_none());
+  assert_eq!(tree.intersect(&bounds, Some(1)).map(|(_, v)| v), Some(0));
+}
+
+#[test]
+fn removing_everything_collapses() {
+  let mut tree = Octree::new(&world());
+  let boxes = terrain_like(32);
+  for &(ref bounds, v) in &boxes {
+    tree.insert(bounds.clone(), v);
+  }
+  let stats = tree.stats();
+  assert!(stats.depth > 0);
+  assert!(stats.mean_leaf_items() > 0.0);
+
+  for &(ref bounds, v) in &boxes {
+    tree.remove(bounds, v);
+  }
+  assert_eq!(
+    tree.stats(),
+    Stats {
+      depth: 0,
+      nodes: 1,
+      leaves: 1,
+      leaf_items: 0,
+      max_leaf_items: 0,
+    }
+  );
+}
+
+#[bench]
+fn insert_terrain_bench(b: &mut Bencher) {
+  let boxes = terrain_like(64);
+  b.iter(|| {
+    let mut tree = Octree::new(&world());
+    for &(ref bounds, v) in &boxes {
+      tree.insert(bounds.clone(), v);
+    }
+    black_box(tree.stats());
+  });
+}
+
+#[bench]
+fn remove_terrain_bench(b: &mut Bencher) {
+  let boxes = terrain_like(64);
+  let mut tree = Octree::new(&world());
+  for &(ref bounds, v) in &boxes {
+    tree.insert(bounds.clone(), v);
+  }
+  // Unload and reload a block-sized patch, like terrain being swapped out for another LOD.
+  let patch: Vec<_> =
+    boxes.iter()
+    .filter(|&&(ref bounds, _)| 0.0 <= bounds.min.x && bounds.min.x < 8.0 && 0.0 <= bounds.min.z && bounds.min.z < 8.0)
+    .cloned()
+    .collect();
+  b.iter(|| {
+    for &(ref bounds, v) in &patch {
+      tree.remove(bounds, v);
+    }
+    for &(ref bounds, v) in &patch {
+      tree.insert(bounds.clone(), v);
+    }
+  });
+}
+
+#[bench]
+fn intersect_terrain_bench(b: &mut Bencher) {
+  let boxes = terrain_like(64);
+  let mut tree = Octree::new(&world());
+  for &(ref bounds, v) in &boxes {
+    tree.insert(bounds.clone(), v);
+  }
+  // Player-sized boxes walking over the terrain.
+  let queries: Vec<_> =
+    (-32 .. 31).map(|x| {
+      let x = x as f32;
+      Aabb3::new(Point3::new(x, 0.0, 0.5), Point3::new(x + 1.0, 2.0, 1.5))
+    })
+    .collect();
+  b.iter(|| {
+    for query in &queries {
+      black_box(tree.intersect(query, None));
+    }
+  });
+}
+
+#[bench]
+fn sweep_terrain_bench(b: &mut Bencher) {
+  let boxes = terrain_like(64);
+  let mut tree = Octree::new(&world());
+  for &(ref bounds, v) in &boxes {
+    tree.insert(bounds.clone(), v);
+  }
+  // Player-sized boxes falling onto the terrain.
+  let starts: Vec<_> =
+    (-32 .. 31).map(|x| {
+      let x = x as f32;
+      Aabb3::new(Point3::new(x, 8.0, 0.5), Point3::new(x + 1.0, 10.0, 1.5))
+    })
+    .collect();
+  let v = Vector3::new(0.3, -12.0, 0.0);
+  b.iter(|| {
+    for start in &starts {
+      black_box(tree.sweep(start, &v, None));
+    }
+  });
+}
@@ -1,12 +1,45 @@
-use cgmath::{Aabb3, Point, Vector, Vector3};
+use cgmath::{Aabb3, EuclideanVector, Point, Point3, Ray3, Vector, Vector3};
 use octree::Octree;
+use common::collision;
+use common::collision::Impact;
 use common::entity::EntityId;
+use common::terrain_block::Triangle;
 use std::collections::HashMap;
 
+// Something in physics, and which octree it's in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Collider {
+  Terrain(EntityId),
+  Misc(EntityId),
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct RayHit {
+  pub collider: Collider,
+  pub point: Point3<f32>,
+  // The normal of the surface hit, facing back toward the ray.
+  pub normal: Vector3<f32>,
+  pub distance: f32,
+}
+
+// The squared distance from `p` to the nearest point in `bounds`.
+fn distance2(bounds: &Aabb3<f32>, p: &Point3<f32>) -> f32 {
+  let nearest =
+    Point3::new(
+      p.x.max(bounds.min.x).min(bounds.max.x),
+      p.y.max(bounds.min.y).min(bounds.max.y),
+      p.z.max(bounds.min.z).min(bounds.max.z),
+    );
+  nearest.sub_p(p).length2()
+}
+
 pub struct Physics {
+  // Only used to find the terrain near something; collisions are against the triangles themselves.
   pub terrain_octree: Octree<EntityId>,
   pub misc_octree: Octree<EntityId>,
   pub bounds: HashMap<EntityId, Aabb3<f32>>,
+  pub triangles: HashMap<EntityId, Triangle<Point3<f32>>>,
 }
 
 impl Physics {
@@ -15,12 +48,14 @@ impl Physics {
       terrain_octree: Octree::new(&world_bounds),
       misc_octree: Octree::new(&world_bounds),
       bounds: HashMap::new(),
+      triangles: HashMap::new(),
     }
   }
 
-  pub fn insert_terrain(&mut self, id: EntityId, bounds: Aabb3<f32>) {
+  pub fn insert_terrain(&mut self, id: EntityId, bounds: Aabb3<f32>, triangle: Triangle<Point3<f32>>) {
     self.terrain_octree.insert(bounds.clone(), id);
     self.bounds.insert(id, bounds);
+    self.triangles.insert(id, triangle);
   }
 
   pub fn insert_misc(&mut self, id: EntityId, bounds: Aabb3<f32>) {
@@ -29,6 +64,7 @@ impl Physics {
   }
 
   pub fn remove_terrain(&mut self, id: EntityId) {
+    self.triangles.remove(&id);
     match self.bounds.get(&id) {
       None => {},
       Some(bounds) => {
@@ -38,10 +74,10 @@ impl Physics {
   }
 
   pub fn remove_misc(&mut self, id: EntityId) {
-    match self.bounds.get(&id) {
+    match self.bounds.remove(&id) {
       None => {},
       Some(bounds) => {
-        self.misc_octree.remove(bounds, id);
+        self.misc_octree.remove(&bounds, id);
       },
     }
   }
@@ -50,34 +86,203 @@ impl Physics {
     self.bounds.get(&id)
   }
 
-  pub fn reinsert(
-    octree: &mut Octree<EntityId>,
-    id: EntityId,
-    bounds: &mut Aabb3<f32>,
-    new_bounds: Aabb3<f32>,
-  ) -> Option<(Aabb3<f32>, EntityId)> {
-    match octree.intersect(&new_bounds, Some(id)) {
-      None => {
-        octree.reinsert(id, bounds, new_bounds.clone());
-        *bounds = new_bounds;
-        None
-      },
-      collision => collision,
-    }
+  // The first thing entity `id` would run into while moving from `bounds` by `v`: terrain or another entity.
+  pub fn sweep_misc(&self, id: EntityId, bounds: &Aabb3<f32>, v: &Vector3<f32>) -> Option<Impact> {
+    let misc = self.misc_octree.sweep(bounds, v, Some(id)).map(|(impact, _)| impact);
+    collision::earliest(self.sweep_terrain(bounds, v), misc)
+  }
+
+  // The first terrain triangle `bounds` would run into while moving by `v`.
+  pub fn sweep_terrain(&self, bounds: &Aabb3<f32>, v: &Vector3<f32>) -> Option<Impact> {
+    let triangles = &self.triangles;
+    let mut first = None;
+    self.terrain_octree.each_within(&collision::swept_bounds(bounds, v), &mut |_, id| {
+      triangles.get(&id).map(|triangle| {
+        first = collision::earliest(first.take(), collision::sweep_triangle(bounds, v, triangle));
+      });
+    });
+    first
   }
 
-  pub fn translate_misc(&mut self, id: EntityId, amount: Vector3<f32>) -> Option<(Aabb3<f32>, EntityId)> {
+  // Move entity `id` to `new_bounds`, without checking for collisions.
+  pub fn move_misc(&mut self, id: EntityId, new_bounds: Aabb3<f32>) {
     let bounds = self.bounds.get_mut(&id).unwrap();
-    let new_bounds =
-      Aabb3::new(
-        bounds.min.add_v(&amount),
-        bounds.max.add_v(&amount),
+    self.misc_octree.reinsert(id, bounds, new_bounds.clone());
+    *bounds = new_bounds;
+  }
+
+  // Move entity `id` by `v`, sliding along whatever it runs into.
+  // Returns the normals of the surfaces it ran into.
+  pub fn slide_misc(&mut self, id: EntityId, v: Vector3<f32>) -> Vec<Vector3<f32>> {
+    let mut normals = Vec::new();
+    let bounds =
+      collision::move_sliding(
+        self.bounds.get(&id).unwrap(),
+        &v,
+        |bounds, v| self.sweep_misc(id, bounds, v),
+        |impact, _, _| {
+          normals.push(impact.normal);
+          None
+        },
       );
-    let terrain_collision = self.terrain_octree.intersect(&new_bounds, None);
-    if terrain_collision.is_none() {
-      Physics::reinsert(&mut self.misc_octree, id, bounds, new_bounds)
-    } else {
-      terrain_collision
+    self.move_misc(id, bounds);
+    normals
+  }
+
+  // The first terrain or misc object hit by `ray` within `max_distance`, other than `ignore` (e.g. whoever's casting
+  // the ray). The ray's direction needn't be normalized. Rays that start inside something pass out through it.
+  #[allow(dead_code)]
+  pub fn cast_ray(&self, ray: &Ray3<f32>, max_distance: f32, ignore: Option<EntityId>) -> Option<RayHit> {
+    let v = ray.direction.normalize().mul_s(max_distance);
+    // A ray is a point swept along it.
+    let point = Aabb3::new(ray.origin, ray.origin);
+    let mut first: Option<(Impact, Collider)> = None;
+    {
+      let triangles = &self.triangles;
+      let bounds = &self.bounds;
+      let mut consider = |impact: Option<Impact>, collider| {
+        impact.map(|impact| {
+          let is_first = first.as_ref().map_or(true, |&(ref f, _)| impact.toi < f.toi);
+          if is_first {
+            first = Some((impact, collider));
+          }
+        });
+      };
+      self.terrain_octree.each_on_segment(&ray.origin, &v, &mut |_, id| {
+        triangles.get(&id).map(|triangle| {
+          consider(collision::sweep_triangle(&point, &v, triangle), Collider::Terrain(id));
+        });
+      });
+      self.misc_octree.each_on_segment(&ray.origin, &v, &mut |_, id| {
+        if Some(id) == ignore {
+          return
+        }
+        bounds.get(&id).map(|bounds| {
+          consider(collision::sweep(&point, &v, bounds), Collider::Misc(id));
+        });
+      });
     }
+
+    first.map(|(impact, collider)| {
+      RayHit {
+        collider: collider,
+        point: ray.origin.add_v(&v.mul_s(impact.toi)),
+        normal: impact.normal,
+        distance: impact.toi * max_distance,
+      }
+    })
   }
+
+  // Every misc object touching `bounds`.
+  pub fn misc_in_aabb(&self, bounds: &Aabb3<f32>) -> Vec<EntityId> {
+    let mut ids = Vec::new();
+    self.misc_octree.each_within(bounds, &mut |_, id| ids.push(id));
+    // Objects split across octree cells are found once per cell.
+    ids.sort();
+    ids.dedup();
+    ids
+  }
+
+  // Every misc object touching the sphere at `center`.
+  #[allow(dead_code)]
+  pub fn misc_in_sphere(&self, center: &Point3<f32>, radius: f32) -> Vec<EntityId> {
+    let r = Vector3::new(radius, radius, radius);
+    let mut ids = self.misc_in_aabb(&Aabb3::new(center.add_v(&-r), center.add_v(&r)));
+    ids.retain(|id| {
+      self.bounds.get(id).map_or(false, |bounds| distance2(bounds, center) <= radius * radius)
+    });
+    ids
+  }
+
+  // The misc object nearest to `point`, other than `ignore`, if there's one within `max_distance`.
+  #[allow(dead_code)]
+  pub fn nearest_misc(&self, point: &Point3<f32>, max_distance: f32, ignore: Option<EntityId>) -> Option<EntityId> {
+    if !max_distance.is_finite() || max_distance <= 0.0 {
+      return None
+    }
+
+    // Look in bigger and bigger spheres, so nearby objects are found without looking at far away ones.
+    let mut radius = max_distance.min(1.0);
+    loop {
+      let mut nearest: Option<(f32, EntityId)> = None;
+      for id in self.misc_in_sphere(point, radius) {
+        if Some(id) == ignore {
+          continue
+        }
+        let d = distance2(self.bounds.get(&id).unwrap(), point);
+        if nearest.map_or(true, |(nearest_d, _)| d < nearest_d) {
+          nearest = Some((d, id));
+        }
+      }
+
+      if nearest.is_some() || radius >= max_distance {
+        return nearest.map(|(_, id)| id)
+      }
+      radius = max_distance.min(2.0 * radius);
+    }
+  }
+}
+
+#[cfg(test)]
+fn test_physics() -> Physics {
+  Physics::new(Aabb3::new(Point3::new(-64.0, -64.0, -64.0), Point3::new(64.0, 64.0, 64.0)))
+}
+
+#[cfg(test)]
+fn id(i: u32) -> EntityId {
+  EntityId::default() + i
+}
+
+#[cfg(test)]
+fn unit_box(x: f32, y: f32, z: f32) -> Aabb3<f32> {
+  Aabb3::new(Point3::new(x, y, z), Point3::new(x + 1.0, y + 1.0, z + 1.0))
+}
+
+#[test]
+fn rays_hit_the_nearest_collider() {
+  use cgmath::Ray;
+  use common::terrain_block;
+
+  let mut physics = test_physics();
+  let floor =
+    terrain_block::tri(
+      Point3::new(-10.0, 0.0, -10.0),
+      Point3::new(-10.0, 0.0, 10.0),
+      Point3::new(10.0, 0.0, -10.0),
+    );
+  physics.insert_terrain(id(1), terrain_block::make_bounds(&floor.v1, &floor.v2, &floor.v3), floor);
+  physics.insert_misc(id(2), unit_box(0.0, 2.0, 0.0));
+
+  let down = Ray3::new(Point3::new(0.5, 10.0, 0.5), Vector3::new(0.0, -2.0, 0.0));
+
+  let hit = physics.cast_ray(&down, 20.0, None).unwrap();
+  assert_eq!(hit.collider, Collider::Misc(id(2)));
+  assert!((hit.point.y - 3.0).abs() < 0.001);
+  assert_eq!(hit.normal, Vector3::new(0.0, 1.0, 0.0));
+  assert!((hit.distance - 7.0).abs() < 0.001);
+
+  let hit = physics.cast_ray(&down, 20.0, Some(id(2))).unwrap();
+  assert_eq!(hit.collider, Collider::Terrain(id(1)));
+  assert!((hit.point.y - 0.0).abs() < 0.001);
+  assert!((hit.normal.y - 1.0).abs() < 0.001);
+  assert!((hit.distance - 10.0).abs() < 0.001);
+
+  assert!(physics.cast_ray(&down, 5.0, Some(id(2))).is_none());
+}
+
+#[test]
+fn nearest_misc_searches_outward() {
+  use std::f32;
+
+  let mut physics = test_physics();
+  physics.insert_misc(id(1), unit_box(3.0, 0.0, 0.0));
+  physics.insert_misc(id(2), unit_box(10.0, 0.0, 0.0));
+  let origin = Point3::new(0.0, 0.5, 0.5);
+
+  assert_eq!(physics.misc_in_sphere(&origin, 5.0), vec!(id(1)));
+  assert_eq!(physics.nearest_misc(&origin, 20.0, None), Some(id(1)));
+  assert_eq!(physics.nearest_misc(&origin, 20.0, Some(id(1))), Some(id(2)));
+  assert_eq!(physics.nearest_misc(&origin, 5.0, Some(id(1))), None);
+  assert_eq!(physics.nearest_misc(&origin, f32::NAN, None), None);
+  assert_eq!(physics.nearest_misc(&origin, 0.0, None), None);
 }
@@ -0,0 +1,273 @@
+//! Regions of the world that only some players may edit.
+//!
+//! Admins list the regions in a JSON file in the world directory, e.g.
+//!
+//! ```json
+//! {
+//!   "passwords": { "alice": "hunter2", "bob": "correct horse", "carol": "swordfish", "dave": "letmein" },
+//!   "roles": { "admin": ["alice"], "builders": ["bob", "carol"] },
+//!   "regions": [
+//!     { "name": "spawn", "min": [-32, -64, -32], "max": [32, 64, 32], "players": ["dave"], "roles": ["builders"] }
+//!   ]
+//! }
+//! ```
+//!
+//! Players can edit inside a region if they're listed in its `players`, or have one of its `roles`.
+//! Admins can edit anywhere. Outside of the regions, anyone can edit.
+//!
+//! Anyone can pick any name, so a player can only use a name listed in `passwords` by giving its password.
+//! Every name with a role or a region must have a password; otherwise anyone could play as them.
+
+use cgmath::{Aabb3, Point3};
+use rustc_serialize::json::Json;
+use std::collections::HashMap;
+
+/// Players with this role can edit every region.
+pub const ADMIN_ROLE: &'static str = "admin";
+
+#[derive(Debug, Clone)]
+/// A region of the world that only some players can edit.
+pub struct Region {
+  #[allow(missing_docs)]
+  pub name: String,
+  #[allow(missing_docs)]
+  pub bounds: Aabb3<f32>,
+  /// The players that can edit in this region.
+  pub players: Vec<String>,
+  /// The roles whose players can edit in this region.
+  pub roles: Vec<String>,
+}
+
+/// The protected regions of a world, and who has which roles.
+#[derive(Debug, Clone)]
+pub struct T {
+  /// The password a player needs to use each of these names.
+  pub passwords: HashMap<String, String>,
+  /// The players in each role.
+  pub roles: HashMap<String, Vec<String>>,
+  #[allow(missing_docs)]
+  pub regions: Vec<Region>,
+}
+
+/// No protected regions at all.
+pub fn new() -> T {
+  T {
+    passwords: HashMap::new(),
+    roles: HashMap::new(),
+    regions: Vec::new(),
+  }
+}
+
+fn strings(json: Option<&Json>, context: &str) -> Result<Vec<String>, String> {
+  match json {
+    None => Ok(Vec::new()),
+    Some(json) => {
+      let array = try!(json.as_array().ok_or_else(|| format!("{} should be an array", context)));
+      let mut strings = Vec::new();
+      for s in array {
+        strings.push(String::from(try!(s.as_string().ok_or_else(|| format!("{} should contain strings", context)))));
+      }
+      Ok(strings)
+    },
+  }
+}
+
+fn point(json: Option<&Json>, context: &str) -> Result<Point3<f32>, String> {
+  let coords =
+    try!(
+      json.and_then(|json| json.as_array())
+      .ok_or_else(|| format!("{} should be an array of coordinates", context))
+    );
+  let coords: Vec<f32> = coords.iter().filter_map(|x| x.as_f64()).map(|x| x as f32).collect();
+  if coords.len() != 3 {
+    return Err(format!("{} should be of the form [x, y, z]", context))
+  }
+  Ok(Point3::new(coords[0], coords[1], coords[2]))
+}
+
+fn parse_region(json: &Json) -> Result<Region, String> {
+  let name =
+    try!(
+      json.find("name").and_then(|name| name.as_string())
+      .ok_or_else(|| String::from("Every region needs a name"))
+    );
+  let context = |field: &str| format!("{} in region {:?}", field, name);
+
+  let min = try!(point(json.find("min"), &context("min")));
+  let max = try!(point(json.find("max"), &context("max")));
+  if min.x > max.x || min.y > max.y || min.z > max.z {
+    return Err(format!("Region {:?} has min greater than max", name))
+  }
+
+  Ok(Region {
+    name: String::from(name),
+    bounds: Aabb3::new(min, max),
+    players: try!(strings(json.find("players"), &context("players"))),
+    roles: try!(strings(json.find("roles"), &context("roles"))),
+  })
+}
+
+/// Read protected regions from JSON, in the format described above.
+pub fn parse(contents: &str) -> Result<T, String> {
+  let json = try!(Json::from_str(contents).map_err(|err| format!("{:?}", err)));
+
+  let mut passwords = HashMap::new();
+  match json.find("passwords") {
+    None => {},
+    Some(json) => {
+      let object = try!(json.as_object().ok_or_else(|| String::from("passwords should be an object")));
+      for (player, password) in object.iter() {
+        let password =
+          try!(password.as_string().ok_or_else(|| format!("The password for {:?} should be a string", player)));
+        passwords.insert(player.clone(), String::from(password));
+      }
+    },
+  }
+
+  let mut roles = HashMap::new();
+  match json.find("roles") {
+    None => {},
+    Some(json) => {
+      let object = try!(json.as_object().ok_or_else(|| String::from("roles should be an object")));
+      for (role, players) in object.iter() {
+        roles.insert(role.clone(), try!(strings(Some(players), &format!("role {:?}", role))));
+      }
+    },
+  }
+
+  let mut regions = Vec::new();
+  match json.find("regions") {
+    None => {},
+    Some(json) => {
+      let array = try!(json.as_array().ok_or_else(|| String::from("regions should be an array")));
+      for region in array {
+        regions.push(try!(parse_region(region)));
+      }
+    },
+  }
+
+  {
+    let privileged =
+      roles.values()
+      .chain(regions.iter().map(|region| &region.players))
+      .flat_map(|players| players.iter());
+    for player in privileged {
+      if !passwords.contains_key(player) {
+        return Err(format!("{:?} can edit protected regions, so they need a password", player))
+      }
+    }
+  }
+
+  Ok(T {
+    passwords: passwords,
+    roles: roles,
+    regions: regions,
+  })
+}
+
+/// The space taken up by the voxels in an inclusive range of voxel coordinates, like a brush's bounds.
+pub fn of_voxels(bounds: &Aabb3<i32>) -> Aabb3<f32> {
+  Aabb3::new(
+    Point3::new(bounds.min.x as f32, bounds.min.y as f32, bounds.min.z as f32),
+    Point3::new((bounds.max.x + 1) as f32, (bounds.max.y + 1) as f32, (bounds.max.z + 1) as f32),
+  )
+}
+
+fn overlap(a: &Aabb3<f32>, b: &Aabb3<f32>) -> bool {
+  true
+  && a.min.x < b.max.x && b.min.x < a.max.x
+  && a.min.y < b.max.y && b.min.y < a.max.y
+  && a.min.z < b.max.z && b.min.z < a.max.z
+}
+
+impl T {
+  fn has_role(&self, player: &str, role: &str) -> bool {
+    self.roles.get(role).map_or(false, |players| players.iter().any(|p| p == player))
+  }
+
+  /// Check that a player may use a name.
+  pub fn check_password(&self, player: &str, password: &str) -> Result<(), String> {
+    match self.passwords.get(player) {
+      None => Ok(()),
+      Some(expected) => {
+        if expected == password {
+          Ok(())
+        } else {
+          Err(format!("Wrong password for {:?}.", player))
+        }
+      },
+    }
+  }
+
+  /// Check that a player may edit everything in `bounds`, or say which region they can't edit.
+  pub fn check_edit(&self, player: &str, bounds: &Aabb3<f32>) -> Result<(), String> {
+    if self.has_role(player, ADMIN_ROLE) {
+      return Ok(())
+    }
+
+    for region in &self.regions {
+      if !overlap(&region.bounds, bounds) {
+        continue
+      }
+      let allowed =
+        region.players.iter().any(|p| p == player) ||
+        region.roles.iter().any(|role| self.has_role(player, role));
+      if !allowed {
+        return Err(format!("You can't edit the protected region {:?}.", region.name))
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[test]
+fn regions_protect_only_what_they_overlap() {
+  let protection =
+    parse(r#"{
+      "passwords": { "alice": "a", "bob": "b", "dave": "d" },
+      "roles": { "admin": ["alice"], "builders": ["bob"] },
+      "regions": [
+        { "name": "spawn", "min": [0, 0, 0], "max": [10, 10, 10], "players": ["dave"], "roles": ["builders"] }
+      ]
+    }"#).unwrap();
+
+  let inside = Aabb3::new(Point3::new(2.0, 2.0, 2.0), Point3::new(4.0, 4.0, 4.0));
+  let touching = Aabb3::new(Point3::new(10.0, 2.0, 2.0), Point3::new(12.0, 4.0, 4.0));
+
+  assert!(protection.check_edit("alice", &inside).is_ok());
+  assert!(protection.check_edit("bob", &inside).is_ok());
+  assert!(protection.check_edit("dave", &inside).is_ok());
+  assert!(protection.check_edit("eve", &inside).is_err());
+  assert!(protection.check_edit("eve", &touching).is_ok());
+}
+
+#[test]
+fn inverted_regions_are_rejected() {
+  let protection = parse(r#"{ "regions": [ { "name": "bad", "min": [1, 0, 0], "max": [0, 1, 1] } ] }"#);
+  assert!(protection.is_err());
+}
+
+#[test]
+fn privileged_names_need_passwords() {
+  let protection = parse(r#"{ "passwords": { "alice": "a" }, "roles": { "admin": ["alice"] } }"#).unwrap();
+  assert!(protection.check_password("alice", "a").is_ok());
+  assert!(protection.check_password("alice", "b").is_err());
+  assert!(protection.check_password("eve", "").is_ok());
+
+  assert!(parse(r#"{ "roles": { "admin": ["alice"] } }"#).is_err());
+  assert!(parse(r#"{ "regions": [ { "name": "r", "min": [0, 0, 0], "max": [1, 1, 1], "players": ["bob"] } ] }"#).is_err());
+}
+
+#[test]
+fn brushes_touching_a_region_edit_its_first_voxels() {
+  let protection =
+    parse(r#"{ "regions": [ { "name": "spawn", "min": [0, 0, 0], "max": [10, 10, 10] } ] }"#).unwrap();
+
+  // The last voxels in this range are [0, 1) on each axis, inside the region.
+  let touching = Aabb3::new(Point3::new(-4, -4, -4), Point3::new(0, 0, 0));
+  assert!(protection.check_edit("eve", &of_voxels(&touching)).is_err());
+
+  let outside = Aabb3::new(Point3::new(-4, -4, -4), Point3::new(-1, -1, -1));
+  assert!(protection.check_edit("eve", &of_voxels(&outside)).is_ok());
+}
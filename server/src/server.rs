@@ -1,106 +1,200 @@
 use cgmath::{Aabb3, Point3};
 use rand;
 use std::collections::HashMap;
+use std;
+use std::path::PathBuf;
 use std::sync::Mutex;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
 use time;
 
-use common::communicate::{ServerToClient, ClientId};
+use common::communicate::{ServerToClient, ClientId, SessionToken};
 use common::entity::EntityId;
 use common::id_allocator::IdAllocator;
 use common::interval_timer::IntervalTimer;
 use common::lod::OwnerId;
-use common::socket::SendSocket;
+use common::transport;
 
-use init_mobs::init_mobs;
+use config;
+use disconnect;
+use edit_history;
+use entities;
 use mob;
+use navigation;
 use physics::Physics;
-use player::Player;
+use protection;
+use snapshot;
 use sun::Sun;
 use terrain_loader::TerrainLoader;
-
-const UPDATES_PER_SECOND: u64 = 30;
-const SUN_TICK_NS: u64 = 1600000;
+use world_file;
+
+const AUTOSAVE_INTERVAL_NS: u64 = 60_000_000_000;
+
+/// Give up on clients that fall this many messages behind.
+const MAX_QUEUED_MESSAGES: usize = 1 << 12;
+
+/// Send messages written to the returned queue over `socket`, from a thread of its own so that a slow client
+/// doesn't hold up whoever is sending to it. The thread stops when the queue is dropped, or a write fails.
+pub fn spawn_writer(mut socket: transport::Sender) -> SyncSender<Vec<u8>> {
+  let (send, recv) = sync_channel::<Vec<u8>>(MAX_QUEUED_MESSAGES);
+  std::thread::spawn(move || {
+    for msg in recv.iter() {
+      match socket.write(msg.as_ref()) {
+        Ok(()) => {},
+        Err(err) => {
+          warn!("Error sending to client: {:?}", err);
+          return
+        },
+      }
+    }
+  });
+  send
+}
 
 pub struct Client {
-  pub socket: SendSocket,
+  /// Messages waiting to be sent by this client's writer thread; see `spawn_writer`.
+  pub outgoing: SyncSender<Vec<u8>>,
+  /// Messages claiming to be from this client must carry this token.
+  pub token: SessionToken,
+  /// The optional protocol features negotiated with this client.
+  pub capabilities: Vec<String>,
+  /// The players this client has added.
+  pub players: Vec<EntityId>,
+  /// The last time we heard a ping from this client.
+  pub last_ping: u64,
+  /// The next entity snapshot sent to this client should include every entity, not just the ones that moved.
+  pub needs_full_snapshot: bool,
+  /// Sending to this client failed, e.g. because it stopped reading or fell too far behind.
+  /// It's disconnected at the next ping.
+  pub broken: bool,
 }
 
 impl Client {
+  /// Did this client negotiate a given protocol feature?
+  pub fn has_capability(&self, capability: &str) -> bool {
+    self.capabilities.iter().any(|c| c == capability)
+  }
+
   pub fn send(&mut self, msg: ServerToClient) {
     use bincode::SizeLimit;
     use bincode::rustc_serialize::encode;
     let msg = encode(&msg, SizeLimit::Infinite).unwrap();
-    match self.socket.write(msg.as_ref()) {
+    // Never block here: callers are usually holding `clients`.
+    match self.outgoing.try_send(msg) {
       Ok(()) => {},
-      Err(err) => warn!("Error sending to client: {:?}", err),
+      Err(TrySendError::Full(_)) => {
+        if !self.broken {
+          warn!("Client fell more than {} messages behind", MAX_QUEUED_MESSAGES);
+        }
+        self.broken = true;
+      },
+      Err(TrySendError::Disconnected(_)) => {
+        // The writer thread already logged why it stopped.
+        self.broken = true;
+      },
     }
   }
 }
 
 // TODO: Audit for s/Mutex/RwLock.
 pub struct Server {
-  pub players: Mutex<HashMap<EntityId, Player>>,
-  pub mobs: Mutex<HashMap<EntityId, mob::Mob>>,
+  /// Lock this before `physics` if both are needed.
+  pub entities: Mutex<entities::T>,
 
   pub id_allocator: Mutex<IdAllocator<EntityId>>,
   pub owner_allocator: Mutex<IdAllocator<OwnerId>>,
   pub client_allocator: Mutex<IdAllocator<ClientId>>,
 
   pub physics: Mutex<Physics>,
+  /// Nothing in the world is outside of these bounds.
+  pub world_bounds: Aabb3<f32>,
   pub terrain_loader: TerrainLoader,
+  /// Paths for mobs. Don't lock the terrain's blocks or `lod_map` while holding this.
+  pub navigation: Mutex<navigation::T>,
+  /// Each player's terrain edits. Edits are only taken off the gaia queue and applied while this is held,
+  /// so they're applied and recorded in the order they arrive; lock it before anything in the terrain, and
+  /// before `clients`.
+  pub edit_history: Mutex<edit_history::Histories>,
   pub rng: Mutex<rand::StdRng>,
 
   pub clients: Mutex<HashMap<ClientId, Client>>,
 
   pub sun: Mutex<Sun>,
   pub update_timer: Mutex<IntervalTimer>,
+  /// The length of a world update tick.
+  pub tick_ns: u64,
+  pub snapshots: Mutex<snapshot::T>,
+  pub ping_timer: Mutex<IntervalTimer>,
+
+  /// The directory the world is saved to.
+  pub world_dir: PathBuf,
+  /// Who can edit where; this is read from the world directory.
+  pub protection: protection::T,
+  /// The low corner of newly-added players.
+  pub spawn_point: Point3<f32>,
+  pub autosave_timer: Mutex<IntervalTimer>,
+  /// How mobs spawn and behave.
+  pub mob_rules: mob::Rules,
 }
 
 impl Server {
   #[allow(missing_docs)]
-  pub fn new() -> Server {
-    let world_width: u32 = 1 << 11;
-    let world_width = world_width as f32;
-    let physics =
-      Physics::new(
-        Aabb3::new(
-          Point3 { x: -world_width, y: -512.0, z: -world_width },
-          Point3 { x: world_width, y: 512.0, z: world_width },
-        )
+  pub fn new(config: &config::T) -> Server {
+    let world_width = config.horizontal_extent;
+    let world_height = config.vertical_extent;
+    let world_bounds =
+      Aabb3::new(
+        Point3 { x: -world_width, y: -world_height, z: -world_width },
+        Point3 { x: world_width, y: world_height, z: world_width },
       );
+    let physics = Physics::new(world_bounds.clone());
+    let world_dir = PathBuf::from(&config.world_dir);
 
     let id_allocator = IdAllocator::new();
     let owner_allocator = Mutex::new(IdAllocator::new());
 
-    let server = Server {
-      players: Mutex::new(HashMap::new()),
-      mobs: Mutex::new(HashMap::new()),
+    let nanoseconds_per_second = 1000000000;
+    let tick_ns = nanoseconds_per_second / config.updates_per_second;
+
+    Server {
+      entities: Mutex::new(entities::new()),
 
       id_allocator: Mutex::new(id_allocator),
       owner_allocator: owner_allocator,
       client_allocator: Mutex::new(IdAllocator::new()),
 
       physics: Mutex::new(physics),
-      terrain_loader: TerrainLoader::new(),
+      world_bounds: world_bounds,
+      terrain_loader: TerrainLoader::new(&world_dir, config.seed),
+      navigation: Mutex::new(navigation::new()),
+      edit_history: Mutex::new(edit_history::new()),
       rng: {
-        let seed = [0];
+        let seed = [config.seed as usize];
         let seed: &[usize] = &seed;
         Mutex::new(rand::SeedableRng::from_seed(seed))
       },
 
       clients: Mutex::new(HashMap::new()),
-      sun: Mutex::new(Sun::new(SUN_TICK_NS)),
+      sun: Mutex::new(Sun::new(config.sun_tick_ns())),
 
       update_timer: {
         let now = time::precise_time_ns();
-        let nanoseconds_per_second = 1000000000;
-        Mutex::new(
-          IntervalTimer::new(nanoseconds_per_second / UPDATES_PER_SECOND, now)
-        )
-      }
-    };
+        Mutex::new(IntervalTimer::new(tick_ns, now))
+      },
+      tick_ns: tick_ns,
+      snapshots: Mutex::new(snapshot::new()),
+      ping_timer: {
+        let now = time::precise_time_ns();
+        Mutex::new(IntervalTimer::new(disconnect::PING_INTERVAL_NS, now))
+      },
 
-    init_mobs(&server);
-    server
+      protection: world_file::load_protection(&world_dir),
+      world_dir: world_dir,
+      spawn_point: config.spawn_point,
+      autosave_timer: {
+        let now = time::precise_time_ns();
+        Mutex::new(IntervalTimer::new(AUTOSAVE_INTERVAL_NS, now + AUTOSAVE_INTERVAL_NS))
+      },
+      mob_rules: mob::load_rules(config.mob_file.as_ref().map(|s| s.as_ref())),
+    }
   }
 }
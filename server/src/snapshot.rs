@@ -0,0 +1,91 @@
+//! Tell clients where entities are, once per tick, including only the entities that moved.
+
+use cgmath::Aabb3;
+use std::collections::HashMap;
+
+use common::communicate::{ServerToClient, Snapshot};
+use common::entity::EntityId;
+
+use entities::Shape;
+use server::Server;
+
+/// What the clients were last told.
+pub struct T {
+  tick: u64,
+  last_sent: HashMap<EntityId, Aabb3<f32>>,
+}
+
+#[allow(missing_docs)]
+pub fn new() -> T {
+  T {
+    tick: 0,
+    last_sent: HashMap::new(),
+  }
+}
+
+fn same(a: &Aabb3<f32>, b: &Aabb3<f32>) -> bool {
+  a.min == b.min && a.max == b.max
+}
+
+/// Send this tick's snapshot to every client. Clients that haven't had a snapshot yet get every entity.
+pub fn broadcast(server: &Server) {
+  let mut players = Vec::new();
+  let mut mobs = Vec::new();
+  {
+    let entities = server.entities.lock().unwrap();
+    let physics = server.physics.lock().unwrap();
+    for (&id, &shape) in entities.shapes.iter() {
+      // The entity might not have been added to physics yet.
+      let bounds =
+        match physics.get_bounds(id) {
+          None => continue,
+          Some(bounds) => bounds.clone(),
+        };
+      match shape {
+        Shape::Player => players.push((id, bounds)),
+        Shape::Mob => mobs.push((id, bounds)),
+      }
+    }
+  }
+
+  let mut snapshots = server.snapshots.lock().unwrap();
+  snapshots.tick += 1;
+
+  let delta = {
+    let last_sent = &snapshots.last_sent;
+    let changed = |entities: &Vec<(EntityId, Aabb3<f32>)>| -> Vec<(EntityId, Aabb3<f32>)> {
+      entities.iter()
+        .filter(|&&(id, ref bounds)| {
+          last_sent.get(&id).map(|last| !same(last, bounds)).unwrap_or(true)
+        })
+        .cloned()
+        .collect()
+    };
+    Snapshot {
+      tick: snapshots.tick,
+      tick_ns: server.tick_ns,
+      players: changed(&players),
+      mobs: changed(&mobs),
+    }
+  };
+  let full =
+    Snapshot {
+      tick: snapshots.tick,
+      tick_ns: server.tick_ns,
+      players: players.clone(),
+      mobs: mobs.clone(),
+    };
+
+  // Forget entities that are gone, so they're sent in full if their ids come back.
+  snapshots.last_sent = players.into_iter().chain(mobs.into_iter()).collect();
+
+  let delta_is_empty = delta.players.is_empty() && delta.mobs.is_empty();
+  for (_, client) in server.clients.lock().unwrap().iter_mut() {
+    if client.needs_full_snapshot {
+      client.needs_full_snapshot = false;
+      client.send(ServerToClient::Snapshot(full.clone()));
+    } else if !delta_is_empty {
+      client.send(ServerToClient::Snapshot(delta.clone()));
+    }
+  }
+}
@@ -0,0 +1,109 @@
+//! Spawn mobs on the terrain near players, and remove them once no players are near.
+
+use cgmath::{Aabb3, EuclideanVector, Point, Point3, Vector, Vector3};
+use rand::Rng;
+use std::f32::consts::PI;
+
+use common::collision;
+use common::communicate::ServerToClient;
+use common::entity::EntityId;
+use common::lod::LOD;
+
+use entities;
+use entities::{Loader, Shape};
+use mob::Behavior;
+use server::Server;
+
+// How far above and below a player to look for the ground to spawn on.
+const SPAWN_HEIGHT_RANGE: f32 = 16.0;
+
+/// Add and remove mobs according to the server's spawn rules.
+pub fn update(server: &Server) {
+  let rules = &server.mob_rules.spawn;
+
+  let (players, mobs): (Vec<EntityId>, Vec<EntityId>) = {
+    let entities = server.entities.lock().unwrap();
+    (entities.controllers.keys().cloned().collect(), entities.behaviors.keys().cloned().collect())
+  };
+  let players: Vec<Point3<f32>> = players.into_iter().filter_map(|id| entities::position(server, id)).collect();
+  let mobs: Vec<(EntityId, Point3<f32>)> =
+    mobs.into_iter().filter_map(|id| entities::position(server, id).map(|p| (id, p))).collect();
+
+  let near = |a: &Point3<f32>, b: &Point3<f32>| a.sub_p(b).length() < rules.despawn_distance;
+
+  let mut kept = Vec::new();
+  for (id, position) in mobs {
+    if players.iter().any(|player| near(player, &position)) {
+      kept.push(position);
+    } else {
+      debug!("Despawning mob {:?}", id);
+      remove_mob(server, id);
+    }
+  }
+
+  // Spawn at most one mob per update, so spawns are spread out.
+  let lonely_player =
+    players.iter().find(|&player| {
+      kept.iter().filter(|&position| near(player, position)).count() < rules.per_player
+    });
+  lonely_player.map(|player| {
+    let (angle, distance) = {
+      let mut rng = server.rng.lock().unwrap();
+      (rng.gen_range(0.0, 2.0 * PI), rng.gen_range(rules.min_distance, rules.max_distance + 0.001))
+    };
+    let offset = Vector3::new(angle.cos(), 0.0, angle.sin()).mul_s(distance);
+    surface_below(server, &player.add_v(&offset).add_v(&Vector3::new(0.0, SPAWN_HEIGHT_RANGE, 0.0)))
+      .map(|low_corner| {
+        add_mob(server, low_corner, Behavior::new(server.mob_rules.behavior.clone()));
+      });
+  });
+}
+
+// The low corner of a mob dropped from `above` onto the loaded terrain, if there's room for it there.
+fn surface_below(server: &Server, above: &Point3<f32>) -> Option<Point3<f32>> {
+  let physics = server.physics.lock().unwrap();
+  let start = mob_bounds(above.add_v(&Vector3::new(-0.5, 0.0, -0.5)));
+  let fall = Vector3::new(0.0, -2.0 * SPAWN_HEIGHT_RANGE, 0.0);
+  // Terrain that hasn't loaded yet isn't in the terrain octree, so mobs won't be put on it.
+  let landed =
+    match physics.sweep_terrain(&start, &fall) {
+      None => return None,
+      Some(impact) => collision::contact(&start, &fall, &impact),
+    };
+
+  if physics.misc_in_aabb(&landed).is_empty() {
+    Some(landed.min)
+  } else {
+    None
+  }
+}
+
+fn mob_bounds(low_corner: Point3<f32>) -> Aabb3<f32> {
+  Aabb3::new(low_corner, low_corner.add_v(&Vector3::new(1.0, 2.0, 1.0 as f32)))
+}
+
+fn add_mob(
+  server: &Server,
+  low_corner: Point3<f32>,
+  behavior: Behavior,
+) {
+  let entity_id = server.id_allocator.lock().unwrap().allocate();
+  debug!("Spawning mob {:?} at {:?}", entity_id, low_corner);
+
+  server.physics.lock().unwrap().insert_misc(entity_id, mob_bounds(low_corner));
+
+  let loader = Loader::new(server, 1, LOD::Placeholder);
+  let mut entities = server.entities.lock().unwrap();
+  entities.velocities.insert(entity_id, Vector3::new(0.0, 0.0, 0.0));
+  entities.behaviors.insert(entity_id, behavior);
+  entities.loaders.insert(entity_id, vec!(loader));
+  entities.shapes.insert(entity_id, Shape::Mob);
+}
+
+fn remove_mob(server: &Server, id: EntityId) {
+  entities::remove(server, id);
+
+  for (_, client) in server.clients.lock().unwrap().iter_mut() {
+    client.send(ServerToClient::RemoveMob(id));
+  }
+}
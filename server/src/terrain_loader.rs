@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Mutex;
 use stopwatch;
 
@@ -7,11 +9,13 @@ use common::id_allocator::IdAllocator;
 use common::lod::{LOD, LODIndex, OwnerId, LODMap};
 use common::terrain_block::TerrainBlock;
 
+use gaia_queue;
 use in_progress_terrain::InProgressTerrain;
 use physics::Physics;
-use terrain::{Terrain, Seed};
+use terrain::Terrain;
 use update_gaia;
 use update_gaia::LoadReason;
+use world_file;
 
 // TODO: Consider factoring this logic such that what to load is separated from how it's loaded.
 
@@ -19,17 +23,61 @@ use update_gaia::LoadReason;
 /// Each TerrainBlock can be owned by a set of owners, each of which can independently request LODs.
 /// The maximum LOD requested is the one that is actually loaded.
 pub struct TerrainLoader {
+  pub seed: u32,
   pub terrain: Terrain,
   pub in_progress_terrain: Mutex<InProgressTerrain>,
   pub lod_map: Mutex<LODMap>,
+  /// Loads that have been requested from gaia but haven't been inserted yet.
+  /// A request is stale, and is dropped, once its entry here is removed or replaced.
+  /// Only locked while `lod_map` is locked.
+  pending: Mutex<HashMap<(BlockPosition, OwnerId), LODIndex>>,
 }
 
 impl TerrainLoader {
-  pub fn new() -> TerrainLoader {
+  /// Load the terrain saved in `world_dir`, or start fresh terrain from `seed` if there's nothing there.
+  pub fn new(world_dir: &Path, seed: u32) -> TerrainLoader {
+    let saved = world_file::load(world_dir);
+    let seed =
+      match saved.as_ref() {
+        None => seed,
+        Some(saved) => {
+          if saved.seed != seed {
+            warn!("Using saved world seed {} instead of {}.", saved.seed, seed);
+          }
+          saved.seed
+        },
+      };
+
+    let terrain = Terrain::new(seed);
+    saved.map(|saved| {
+      terrain.restore_voxels(saved.edited_regions, saved.voxels);
+    });
+
     TerrainLoader {
-      terrain: Terrain::new(Seed::new(0)),
+      seed: seed,
+      terrain: terrain,
       in_progress_terrain: Mutex::new(InProgressTerrain::new()),
       lod_map: Mutex::new(LODMap::new()),
+      pending: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Is a load of this block still wanted by `owner`?
+  pub fn is_pending(&self, block_position: &BlockPosition, lod: LODIndex, owner: OwnerId) -> bool {
+    let _lod_map = self.lod_map.lock().unwrap();
+    self.pending.lock().unwrap().get(&(*block_position, owner)) == Some(&lod)
+  }
+
+  /// Mark a pending load as done, if it's still wanted. Must be called with `lod_map` locked.
+  /// Returns false if the load is stale.
+  pub fn take_pending(&self, block_position: &BlockPosition, lod: LODIndex, owner: OwnerId) -> bool {
+    let mut pending = self.pending.lock().unwrap();
+    let key = (*block_position, owner);
+    if pending.get(&key) == Some(&lod) {
+      pending.remove(&key);
+      true
+    } else {
+      false
     }
   }
 
@@ -42,6 +90,7 @@ impl TerrainLoader {
     block_position: &BlockPosition,
     new_lod: LOD,
     owner: OwnerId,
+    requester: &BlockPosition,
     load_block: &mut LoadBlock,
   ) where LoadBlock: FnMut(update_gaia::Message)
   {
@@ -49,6 +98,17 @@ impl TerrainLoader {
     let max_lod_changed: bool;
     let mut lod_map = self.lod_map.lock().unwrap();
     let mut in_progress_terrain = self.in_progress_terrain.lock().unwrap();
+
+    {
+      let mut pending = self.pending.lock().unwrap();
+      let key = (*block_position, owner);
+      if pending.get(&key).map(|&lod| LOD::LodIndex(lod)) == Some(new_lod) {
+        // Already on its way.
+        return;
+      }
+      // Whatever was requested before is superseded by this request.
+      pending.remove(&key);
+    }
     match lod_map.get(block_position, owner) {
       Some((Some(prev), lods)) => {
         prev_lod = Some(prev);
@@ -91,8 +151,14 @@ impl TerrainLoader {
       LOD::LodIndex(new_lod) => {
         let mut generate_block = || {
           debug!("{:?} requested from gaia", block_position);
+          self.pending.lock().unwrap().insert((*block_position, owner), new_lod);
           load_block(
-            update_gaia::Message::Load(*block_position, new_lod, LoadReason::Local(owner))
+            update_gaia::Message::Load(update_gaia::Load {
+              position: *block_position,
+              lod: new_lod,
+              reason: LoadReason::Local(owner),
+              distance: gaia_queue::distance(block_position, requester),
+            })
           );
         };
         match self.terrain.all_blocks.lock().unwrap().get(block_position) {
@@ -133,10 +199,8 @@ impl TerrainLoader {
   ) {
     let lod = LOD::LodIndex(lod);
     let (_, change) = lod_map.insert(*position, lod, owner);
-    // TODO: This should be an unwrap, but the preconditions of another TODO aren't
-    // satisfied in src/update_gaia.rs.
-    // (i.e. blocks sometimes get here when they're stale).
     let change = match change {
+      // Another owner's load of this block finished first; it's already in physics.
       None => return,
       Some(change) => change,
     };
@@ -159,20 +223,44 @@ impl TerrainLoader {
 
     stopwatch::time("terrain_loader.load.physics", || {
       let mut physics = physics.lock().unwrap();
-      for &(ref id, ref bounds) in &block.bounds {
-        physics.insert_terrain(*id, bounds.clone());
+      for (&(ref id, ref bounds), triangle) in block.bounds.iter().zip(block.vertex_coordinates.iter()) {
+        physics.insert_terrain(*id, bounds.clone(), *triangle);
       }
     });
   }
 
+  /// Release every handle `owner` has on the terrain.
+  pub fn unload_owner(
+    &self,
+    physics: &Mutex<Physics>,
+    owner: OwnerId,
+  ) {
+    let positions = {
+      let lod_map = self.lod_map.lock().unwrap();
+      let mut pending = self.pending.lock().unwrap();
+      let cancelled: Vec<_> = pending.keys().filter(|&&(_, o)| o == owner).map(|&k| k).collect();
+      for key in &cancelled {
+        pending.remove(key);
+      }
+      lod_map.owned_by(owner)
+    };
+    for position in &positions {
+      self.unload(physics, position, owner);
+    }
+  }
+
   pub fn unload(
     &self,
     physics: &Mutex<Physics>,
     block_position: &BlockPosition,
     owner: OwnerId,
   ) {
-    let (_, mlod_change) =
-      self.lod_map.lock().unwrap().remove(*block_position, owner);
+    let (_, mlod_change) = {
+      let mut lod_map = self.lod_map.lock().unwrap();
+      // Cancel any load of this block that's still waiting on gaia.
+      self.pending.lock().unwrap().remove(&(*block_position, owner));
+      lod_map.remove(*block_position, owner)
+    };
 
     let lod_change;
     match mlod_change {
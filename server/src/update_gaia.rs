@@ -6,7 +6,12 @@ use common::communicate;
 use common::communicate::{ClientId, ServerToClient, TerrainBlockSend};
 use common::lod::{LODIndex, OwnerId};
 use common::block_position::BlockPosition;
+use common::block_wire;
+use common::entity::EntityId;
+use common::terrain_block::TerrainBlock;
 
+use edit_history;
+use gaia_queue;
 use server::Server;
 use terrain;
 use terrain_loader::TerrainLoader;
@@ -18,83 +23,198 @@ pub enum LoadReason {
   ForClient(ClientId),
 }
 
+/// A request to load a block.
+pub struct Load {
+  #[allow(missing_docs)]
+  pub position: BlockPosition,
+  #[allow(missing_docs)]
+  pub lod: LODIndex,
+  #[allow(missing_docs)]
+  pub reason: LoadReason,
+  /// The squared distance in blocks to whoever asked for it; nearer blocks are loaded first.
+  pub distance: u32,
+}
+
 pub enum Message {
-  Load(BlockPosition, LODIndex, LoadReason),
-  Brush(voxel_data::brush::T<Box<voxel_data::mosaic::T<terrain::voxel::Material> + Send>>),
+  Load(Load),
+  /// Change the terrain. Edits are applied in the order they're sent.
+  Edit(Edit),
+}
+
+pub enum Edit {
+  /// A player's brush stroke.
+  Brush(EntityId, voxel_data::brush::T<Box<voxel_data::mosaic::T<terrain::voxel::Material> + Send>>),
+  /// Undo a player's last edit.
+  Undo(EntityId),
+  /// Redo a player's last undone edit.
+  Redo(EntityId),
+}
+
+/// Apply every waiting edit, in order. Returns whether there were any.
+pub fn apply_edits(server: &Server, to_gaia: &gaia_queue::T) -> bool {
+  if !to_gaia.has_edits() {
+    return false
+  }
+  // Edits are only taken off the queue under this lock, so no two threads can apply them out of order.
+  let mut histories = server.edit_history.lock().unwrap();
+  while let Some(edit) = to_gaia.pop_edit() {
+    stopwatch::time("update_gaia", || apply_edit(server, &mut *histories, edit));
+  }
+  true
+}
+
+fn apply_edit(server: &Server, histories: &mut edit_history::Histories, edit: Edit) {
+  match edit {
+    Edit::Brush(player, brush) => {
+      let before =
+        server.terrain_loader.terrain.brush(
+          &server.id_allocator,
+          &brush,
+          |old, new, position, lod| block_changed(server, old, new, position, lod),
+        );
+      // Don't keep history for players that have left.
+      let keep_history = server.entities.lock().unwrap().controllers.contains_key(&player);
+      histories.edited(player, before, keep_history);
+    },
+    Edit::Undo(player) => {
+      match histories.take_undo(player) {
+        Err(err) => refuse(server, player, err),
+        Ok(None) => {},
+        Ok(Some(snapshot)) => {
+          let before = restore(server, snapshot);
+          histories.undone(player, before);
+        },
+      }
+    },
+    Edit::Redo(player) => {
+      match histories.take_redo(player) {
+        Err(err) => refuse(server, player, err),
+        Ok(None) => {},
+        Ok(Some(snapshot)) => {
+          let before = restore(server, snapshot);
+          histories.redone(player, before);
+        },
+      }
+    },
+  }
+}
+
+fn restore(server: &Server, snapshot: terrain::Snapshot) -> terrain::Snapshot {
+  server.terrain_loader.terrain.restore(
+    &server.id_allocator,
+    snapshot,
+    |old, new, position, lod| block_changed(server, old, new, position, lod),
+  )
+}
+
+// Tell the client that owns a player why their undo or redo didn't happen.
+fn refuse(server: &Server, player: EntityId, err: String) {
+  info!("Refusing to restore {:?}'s edit: {}", player, err);
+  for (_, client) in server.clients.lock().unwrap().iter_mut() {
+    if client.players.contains(&player) {
+      client.send(ServerToClient::Error(err.clone()));
+    }
+  }
 }
 
-// TODO: Consider adding terrain loads to a thread pool instead of having one monolithic separate thread.
+/// Load a single block. This is called concurrently from each of the terrain worker threads;
+/// edits are only applied by `apply_edits`.
 pub fn update_gaia(
   server: &Server,
-  update: Message,
+  load: Load,
 ) {
   stopwatch::time("update_gaia", move || {
-    match update {
-      Message::Load(position, lod, load_reason) => {
-        stopwatch::time("terrain.load", || {
-          // TODO: Just lock `terrain` for the check and then the move;
-          // don't lock for the whole time where we're generating the block.
-          let mut lod_map = server.terrain_loader.lod_map.lock().unwrap();
-          let mut in_progress_terrain = server.terrain_loader.in_progress_terrain.lock().unwrap();
-          server.terrain_loader.terrain.load(
-            &server.id_allocator,
-            &position,
-            lod,
-            |block| {
-              match load_reason {
-                LoadReason::Local(owner) => {
-                  // TODO: Check that this block isn't stale, i.e. should still be loaded.
-                  // Maybe this should just ping the original thread, same as we ping the client.
-                  TerrainLoader::insert_block(
-                    block,
-                    &position,
-                    lod,
-                    owner,
-                    &server.physics,
-                    &mut *lod_map,
-                    &mut *in_progress_terrain,
-                  );
-                },
-                LoadReason::ForClient(id) => {
-                  let mut clients = server.clients.lock().unwrap();
-                  let client = clients.get_mut(&id).unwrap();
-                  client.send(
-                    ServerToClient::Block(
-                      TerrainBlockSend {
-                        position: position,
-                        block: block.clone(),
-                        lod: lod,
-                      },
-                      communicate::BlockReason::Requested,
-                    )
-                  );
-                },
+    let Load { position, lod, reason: load_reason, distance: _ } = load;
+
+    // Don't bother generating blocks nobody wants anymore.
+    let wanted =
+      match load_reason {
+        LoadReason::Local(owner) => server.terrain_loader.is_pending(&position, lod, owner),
+        LoadReason::ForClient(id) => server.clients.lock().unwrap().contains_key(&id),
+      };
+    if !wanted {
+      debug!("Dropping stale request for {:?}", position);
+      return
+    }
+
+    stopwatch::time("terrain.load", || {
+      server.terrain_loader.terrain.load(
+        &server.id_allocator,
+        &position,
+        lod,
+        |block| {
+          match load_reason {
+            LoadReason::Local(owner) => {
+              let mut lod_map = server.terrain_loader.lod_map.lock().unwrap();
+              // The block might have been unloaded or re-requested while it was being generated.
+              if !server.terrain_loader.take_pending(&position, lod, owner) {
+                debug!("Dropping stale block {:?}", position);
+                return
               }
+              let mut in_progress_terrain = server.terrain_loader.in_progress_terrain.lock().unwrap();
+              TerrainLoader::insert_block(
+                block,
+                &position,
+                lod,
+                owner,
+                &server.physics,
+                &mut *lod_map,
+                &mut *in_progress_terrain,
+              );
             },
-          )
-        });
-      },
-      Message::Brush(brush) => {
-        server.terrain_loader.terrain.brush(
-          &server.id_allocator,
-          &brush,
-          |block, position, lod| {
-            let mut clients = server.clients.lock().unwrap();
-            for (_, client) in clients.iter_mut() {
+            LoadReason::ForClient(id) => {
+              let mut clients = server.clients.lock().unwrap();
+              let client =
+                match clients.get_mut(&id) {
+                  None => {
+                    debug!("Dropping block for disconnected client {:?}", id);
+                    return
+                  },
+                  Some(client) => client,
+                };
+              let compress = client.has_capability(communicate::LZ_BLOCKS);
               client.send(
                 ServerToClient::Block(
                   TerrainBlockSend {
-                    position: *position,
-                    block: block.clone(),
+                    position: position,
+                    block: block_wire::pack(block_wire::full(&position, block), compress),
                     lod: lod,
                   },
-                  communicate::BlockReason::Updated,
+                  communicate::BlockReason::Requested,
                 )
               );
-            }
-          },
-        );
-      },
-    };
+            },
+          }
+        },
+      )
+    });
   })
 }
+
+// Tell every client about a block that an edit changed.
+fn block_changed(
+  server: &Server,
+  old: &TerrainBlock,
+  new: &TerrainBlock,
+  position: &BlockPosition,
+  lod: LODIndex,
+) {
+  server.navigation.lock().unwrap().invalidate(position);
+
+  // Clients that have the old version only need the triangles that changed.
+  let contents = block_wire::diff(position, old, new);
+  let mut clients = server.clients.lock().unwrap();
+  for (_, client) in clients.iter_mut() {
+    let compress = client.has_capability(communicate::LZ_BLOCKS);
+    client.send(
+      ServerToClient::Block(
+        TerrainBlockSend {
+          position: *position,
+          block: block_wire::pack(contents.clone(), compress),
+          lod: lod,
+        },
+        communicate::BlockReason::Updated,
+      )
+    );
+  }
+}
@@ -1,77 +1,123 @@
-use cgmath::{Point, Vector, Vector3};
-use std::ops::Neg;
-use std::sync::mpsc::Sender;
+use cgmath::{Vector, Vector3};
 use stopwatch;
 
-use common::block_position::BlockPosition;
+use common::collision;
 use common::communicate::ServerToClient::*;
-use common::lod::{LOD, OwnerId};
-use common::surroundings_loader::LoadType;
+use common::movement;
 
+use disconnect;
+use entities;
+use gaia_queue;
 use mob;
+use navigation;
 use server::Server;
-use update_gaia;
+use snapshot;
+use spawn_mobs;
 
 // TODO: Consider removing the IntervalTimer.
 
 pub fn update_world(
   server: &Server,
-  request_block: &Sender<update_gaia::Message>,
+  to_gaia: &gaia_queue::T,
 ) {
-  let mut request_block = |block| { request_block.send(block).unwrap() };
+  let mut request_block = |block| { to_gaia.push(block) };
 
   stopwatch::time("update_world", || {
-    stopwatch::time("update_world.player", || {
-      for (_, player) in server.players.lock().unwrap().iter_mut() {
-        player.update(server, &mut request_block);
+    stopwatch::time("update_world.loaders", || {
+      let mut entities = server.entities.lock().unwrap();
+      for (&id, loaders) in entities.loaders.iter_mut() {
+        // The entity might not have been added to physics yet.
+        let position =
+          match entities::position(server, id) {
+            None => continue,
+            Some(position) => position,
+          };
+        for loader in loaders.iter_mut() {
+          loader.update(server, &position, &mut request_block);
+        }
+      }
+    });
+
+    stopwatch::time("update_world.controllers", || {
+      let mut entities = server.entities.lock().unwrap();
+      let mut physics = server.physics.lock().unwrap();
+      for (&id, controller) in entities.controllers.iter_mut() {
+        controller.step(&mut physics, id);
       }
+    });
 
-      let players: Vec<_> = server.players.lock().unwrap().keys().map(|&x| x).collect();
+    stopwatch::time("update_world.acks", || {
+      // Tell clients where their own players really are, so they can correct their predictions.
+      let acks: Vec<_> = {
+        let entities = server.entities.lock().unwrap();
+        let physics = server.physics.lock().unwrap();
+        entities.controllers.iter()
+          .filter_map(|(&id, controller)| physics.get_bounds(id).map(|bounds| (id, controller.ack(bounds.clone()))))
+          .collect()
+      };
       for (_, client) in server.clients.lock().unwrap().iter_mut() {
-        for &id in &players {
-          let bounds = server.physics.lock().unwrap().get_bounds(id).unwrap().clone();
-          client.send(UpdatePlayer(id, bounds));
+        for &(id, ref ack) in &acks {
+          if client.players.contains(&id) {
+            client.send(PlayerState(id, ack.clone()));
+          }
         }
       }
     });
 
-    stopwatch::time("update_world.mobs", || {
-      for (_, mob) in server.mobs.lock().unwrap().iter_mut() {
-        let block_position = BlockPosition::of_world_position(&mob.position);
-
-        let owner_id = mob.owner_id;
-        for (position, load_type) in mob.surroundings_loader.updates(block_position.as_pnt()) {
-          let position = BlockPosition::of_pnt(&position);
-          load_placeholders(
-            owner_id,
-            server,
-            &mut request_block,
-            &position,
-            load_type,
-          )
-        }
+    disconnect::ping_clients(server);
 
-        {
-          let behavior = mob.behavior;
-          (behavior)(server, mob);
-        }
-
-        mob.speed = mob.speed.add_v(&-Vector3::new(0.0, 0.1, 0.0 as f32));
+    if server.navigation.lock().unwrap().start_update() {
+      stopwatch::time("update_world.navigation", || {
+        navigation::forget_unloaded(&server.navigation, &server.terrain_loader.lod_map);
+      });
+    }
 
-        // TODO: This logic is dumb (isolating along components shouldn't be a thing). Change it.
-        let delta_p = mob.speed;
-        if delta_p.x != 0.0 {
-          translate_mob(server, mob, &Vector3::new(delta_p.x, 0.0, 0.0));
+    stopwatch::time("update_world.behaviors", || {
+      // Behaviors look at other entities, so don't hold the lock while they run.
+      let behaviors: Vec<_> = {
+        let entities = server.entities.lock().unwrap();
+        entities.behaviors.iter()
+          .map(|(&id, behavior)| (id, behavior.clone(), entities.velocities.get(&id).cloned()))
+          .collect()
+      };
+      for (id, mut behavior, velocity) in behaviors {
+        let mut velocity = velocity.unwrap_or(Vector3::new(0.0, 0.0, 0.0));
+        behavior.update(server, id, &mut velocity);
+
+        let mut entities = server.entities.lock().unwrap();
+        if !entities.behaviors.contains_key(&id) {
+          // It was removed while its behavior ran.
+          continue
         }
-        if delta_p.y != 0.0 {
-          translate_mob(server, mob, &Vector3::new(0.0, delta_p.y, 0.0));
-        }
-        if delta_p.z != 0.0 {
-          translate_mob(server, mob, &Vector3::new(0.0, 0.0, delta_p.z));
+        entities.behaviors.insert(id, behavior);
+        entities.velocities.get_mut(&id).map(|v| *v = velocity);
+      }
+    });
+
+    stopwatch::time("update_world.velocities", || {
+      let mut entities = server.entities.lock().unwrap();
+      let entities = &mut *entities;
+      for (&id, velocity) in entities.velocities.iter_mut() {
+        velocity.add_self_v(&-Vector3::new(0.0, 0.1, 0.0 as f32));
+
+        for normal in server.physics.lock().unwrap().slide_misc(id, *velocity) {
+          // Stop moving into whatever we ran into.
+          *velocity = collision::slide(velocity, &normal);
+          if normal.y.abs() < movement::MIN_FLOOR_NORMAL_Y {
+            entities.behaviors.get_mut(&id).map(|behavior| behavior.notify(mob::Event::Bumped));
+          }
         }
       }
     });
 
+    stopwatch::time("update_world.mobs", || {
+      spawn_mobs::update(server);
+    });
+
+    stopwatch::time("update_world.snapshot", || {
+      snapshot::broadcast(server);
+    });
+
     server.sun.lock().unwrap().update().map(|fraction| {
       for (_, client) in server.clients.lock().unwrap().iter_mut() {
         client.send(UpdateSun(fraction));
@@ -79,59 +125,3 @@ pub fn update_world(
     });
   });
 }
-
-fn translate_mob(
-  server: &Server,
-  mob: &mut mob::Mob,
-  delta_p: &Vector3<f32>,
-) {
-  let bounds;
-  {
-    let mut physics = server.physics.lock().unwrap();
-    if physics.translate_misc(mob.entity_id, *delta_p).is_some() {
-      mob.speed.add_self_v(&delta_p.neg());
-      return;
-    } else {
-      bounds = physics.get_bounds(mob.entity_id).unwrap().clone();
-    }
-  }
-
-  mob.position.add_self_v(delta_p);
-
-  for (_, client) in server.clients.lock().unwrap().iter_mut() {
-    client.send(
-      UpdateMob(mob.entity_id, bounds.clone())
-    );
-  }
-}
-
-#[inline]
-pub fn load_placeholders<RequestBlock>(
-  owner: OwnerId,
-  server: &Server,
-  request_block: &mut RequestBlock,
-  pos: &BlockPosition,
-  load_type: LoadType,
-) where
-  RequestBlock: FnMut(update_gaia::Message),
-{
-  match load_type {
-    LoadType::Load | LoadType::Update => {
-      server.terrain_loader.load(
-        &server.id_allocator,
-        &server.physics,
-        &pos,
-        LOD::Placeholder,
-        owner,
-        request_block,
-      );
-    },
-    LoadType::Unload => {
-      server.terrain_loader.unload(
-        &server.physics,
-        &pos,
-        owner,
-      );
-    },
-  }
-}
@@ -0,0 +1,134 @@
+//! Check requests coming off the wire before they're applied to the server state.
+
+use common::block_position::BlockPosition;
+use common::brush;
+use common::communicate::{ClientId, ClientToServer, Session};
+use common::entity::EntityId;
+use common::lod::LODIndex;
+use common::movement;
+use common::terrain_block;
+
+use server::Server;
+
+const MAX_NAME_LENGTH: usize = 32;
+
+/// Why a request was refused.
+#[derive(Debug)]
+pub enum Invalid {
+  /// We can't tell who sent the request, so there's nobody to tell.
+  Unattributable(String),
+  /// The request came from this client, which should be told what went wrong.
+  Reply(ClientId, String),
+  /// A block request from this client was refused; it should stop waiting for the block.
+  Block(ClientId, BlockPosition, LODIndex, String),
+}
+
+// Check that the session belongs to a connected client.
+fn authenticate(server: &Server, session: &Session) -> Result<(), Invalid> {
+  match server.clients.lock().unwrap().get(&session.client_id) {
+    None => Err(Invalid::Unattributable(format!("Unknown client {:?}", session.client_id))),
+    Some(client) => {
+      if client.token == session.token {
+        Ok(())
+      } else {
+        Err(Invalid::Unattributable(format!("Bad session token for {:?}", session.client_id)))
+      }
+    },
+  }
+}
+
+// Check that the session's client is the one that added a player.
+fn owned_player(server: &Server, session: &Session, player_id: EntityId) -> Result<(), Invalid> {
+  try!(authenticate(server, session));
+
+  let owned =
+    server.clients.lock().unwrap()
+    .get(&session.client_id)
+    .map(|client| client.players.contains(&player_id))
+    .unwrap_or(false);
+  if owned {
+    Ok(())
+  } else {
+    Err(Invalid::Reply(session.client_id, format!("Player {:?} isn't yours", player_id)))
+  }
+}
+
+fn finite(client_id: ClientId, xs: &[f32]) -> Result<(), Invalid> {
+  if xs.iter().all(|x| x.is_finite()) {
+    Ok(())
+  } else {
+    Err(Invalid::Reply(client_id, format!("Non-finite values in {:?}", xs)))
+  }
+}
+
+// Check that a block overlaps the world. Positions come from clients, so they might be anything.
+fn in_world(server: &Server, position: &BlockPosition) -> bool {
+  let width = terrain_block::WIDTH as i64;
+  let overlaps = |block: i32, low: f32, high: f32| {
+    let block_low = block as i64 * width;
+    (block_low as f64) < (high as f64) && (low as f64) < ((block_low + width) as f64)
+  };
+  let p = position.as_pnt();
+  let bounds = &server.world_bounds;
+  overlaps(p.x, bounds.min.x, bounds.max.x) &&
+  overlaps(p.y, bounds.min.y, bounds.max.y) &&
+  overlaps(p.z, bounds.min.z, bounds.max.z)
+}
+
+/// Check that a request comes from who it claims to, refers to things that exist, and is well-formed.
+pub fn validate(server: &Server, update: &ClientToServer) -> Result<(), Invalid> {
+  match update {
+    &ClientToServer::Init(_, _, _) => Ok(()),
+    &ClientToServer::Ping(ref session) |
+    &ClientToServer::Disconnect(ref session) => {
+      authenticate(server, session)
+    },
+    &ClientToServer::AddPlayer(ref session, ref name, ref password) => {
+      try!(authenticate(server, session));
+      if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(Invalid::Reply(session.client_id, format!("Player names must be 1 to {} bytes long", MAX_NAME_LENGTH)))
+      }
+      server.protection.check_password(name, password).map_err(|err| Invalid::Reply(session.client_id, err))
+    },
+    &ClientToServer::RequestBlock(ref session, ref position, lod) => {
+      try!(authenticate(server, session));
+      if (lod.0 as usize) >= terrain_block::LOD_COUNT {
+        return Err(Invalid::Block(session.client_id, *position, lod, format!("No such LOD: {:?}", lod)))
+      }
+      if !in_world(server, position) {
+        return Err(Invalid::Block(session.client_id, *position, lod, format!("Block {:?} is outside the world", position)))
+      }
+      Ok(())
+    },
+    &ClientToServer::Input(ref session, player_id, _, ref input) => {
+      try!(owned_player(server, session, player_id));
+      match input {
+        &movement::Input::Walk(v) => {
+          try!(finite(session.client_id, &[v.x, v.y, v.z]));
+          if v.x.abs() > 1.0 || v.y.abs() > 1.0 || v.z.abs() > 1.0 {
+            return Err(Invalid::Reply(session.client_id, format!("Walk vector too large: {:?}", v)))
+          }
+          Ok(())
+        },
+        &movement::Input::Rotate(v) => {
+          finite(session.client_id, &[v.x, v.y])
+        },
+        &movement::Input::StartJump |
+        &movement::Input::StopJump => Ok(()),
+      }
+    },
+    &ClientToServer::PlantTree(ref session, player_id) |
+    &ClientToServer::Undo(ref session, player_id) |
+    &ClientToServer::Redo(ref session, player_id) => {
+      owned_player(server, session, player_id)
+    },
+    &ClientToServer::Brush(ref session, player_id, ref brush) => {
+      try!(owned_player(server, session, player_id));
+      try!(finite(session.client_id, &[brush.size]));
+      if brush.size < brush::MIN_SIZE || brush.size > brush::MAX_SIZE {
+        return Err(Invalid::Reply(session.client_id, format!("Brush size out of range: {}", brush.size)))
+      }
+      Ok(())
+    },
+  }
+}
@@ -0,0 +1,116 @@
+//! On-disk format for persisting a world between server runs.
+//! A world is a directory; the terrain edits and seed live in a single bincoded file inside it.
+//! Admins can also put a JSON file of protected regions in it, which the server only reads.
+
+use bincode;
+use bincode::SizeLimit;
+use cgmath::Aabb3;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use stopwatch;
+
+use terrain;
+use terrain::VoxelBounds;
+
+use protection;
+use server::Server;
+
+const TERRAIN_FILE: &'static str = "terrain.bin";
+const TERRAIN_TMP_FILE: &'static str = "terrain.bin.tmp";
+const PROTECTION_FILE: &'static str = "protection.json";
+
+#[derive(RustcEncodable, RustcDecodable)]
+/// Everything about the terrain that can't be regenerated from the seed.
+pub struct SavedTerrain {
+  pub seed: u32,
+  /// The bounds of each brush applied to the terrain.
+  pub edited_regions: Vec<Aabb3<i32>>,
+  /// The contents of all the voxels inside `edited_regions`.
+  pub voxels: Vec<(VoxelBounds, terrain::voxel::T<terrain::voxel::Material>)>,
+}
+
+/// Read the saved terrain out of a world directory, if there is one.
+/// Panics if there's a save that can't be read.
+pub fn load(world_dir: &Path) -> Option<SavedTerrain> {
+  let path = world_dir.join(TERRAIN_FILE);
+  let mut file =
+    match File::open(&path) {
+      Ok(file) => file,
+      Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+        info!("No saved terrain at {:?}.", path);
+        return None
+      },
+      Err(err) => {
+        // Starting a fresh world here would overwrite the real one at the next autosave.
+        panic!("Couldn't open saved terrain at {:?}: {:?}", path, err);
+      },
+    };
+
+  match bincode::rustc_serialize::decode_from(&mut file, SizeLimit::Infinite) {
+    Ok(saved) => {
+      info!("Loaded saved terrain from {:?}.", path);
+      Some(saved)
+    },
+    Err(err) => {
+      panic!("Couldn't decode saved terrain at {:?}: {:?}", path, err);
+    },
+  }
+}
+
+/// Read a world directory's protected regions. Worlds without any have no protection.
+/// Panics if there are protected regions that can't be read.
+pub fn load_protection(world_dir: &Path) -> protection::T {
+  let path = world_dir.join(PROTECTION_FILE);
+  let mut contents = String::new();
+  match File::open(&path).and_then(|mut file| file.read_to_string(&mut contents)) {
+    Ok(_) => {},
+    Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+      info!("No protected regions at {:?}.", path);
+      return protection::new()
+    },
+    Err(err) => {
+      // Carrying on would leave every protected region open to anyone.
+      panic!("Couldn't read protected regions at {:?}: {:?}", path, err);
+    },
+  }
+
+  let protection =
+    protection::parse(contents.as_ref())
+    .unwrap_or_else(|err| panic!("Invalid protected regions in {:?}: {}", path, err));
+  info!("Loaded {} protected regions from {:?}.", protection.regions.len(), path);
+  protection
+}
+
+/// Write the server's terrain edits into a world directory.
+pub fn save(server: &Server, world_dir: &Path) -> io::Result<()> {
+  stopwatch::time("world_file.save", || {
+    let saved =
+      SavedTerrain {
+        seed: server.terrain_loader.seed,
+        edited_regions: server.terrain_loader.terrain.edited_regions.lock().unwrap().clone(),
+        voxels: server.terrain_loader.terrain.edited_voxels(),
+      };
+
+    try!(fs::create_dir_all(world_dir));
+
+    // Write to a temporary file first, so a crash mid-save doesn't clobber the last good save.
+    let tmp_path = world_dir.join(TERRAIN_TMP_FILE);
+    {
+      let mut file = try!(File::create(&tmp_path));
+      match bincode::rustc_serialize::encode_into(&saved, &mut file, SizeLimit::Infinite) {
+        Ok(()) => {},
+        Err(err) => {
+          return Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", err)))
+        },
+      }
+      try!(file.sync_all());
+    }
+
+    try!(fs::rename(&tmp_path, world_dir.join(TERRAIN_FILE)));
+    debug!("Saved {} edited voxels to {:?}.", saved.voxels.len(), world_dir);
+    Ok(())
+  })
+}
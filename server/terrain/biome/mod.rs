@@ -3,3 +3,4 @@
 pub mod caves;
 pub mod hills;
 pub mod mountains;
+pub mod selector;
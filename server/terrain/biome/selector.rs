@@ -0,0 +1,143 @@
+//! A mosaic that picks between the other biomes using a low-frequency "climate" map,
+//! and blends their densities near the borders so the terrain stays continuous.
+
+use cgmath::{Point3, Vector3, EuclideanVector};
+use noise::{Seed, perlin2};
+
+use biome::{caves, hills, mountains};
+use voxel;
+use voxel_data;
+
+/// How quickly the climate changes across the world. Lower is bigger biomes.
+const CLIMATE_FREQUENCY: f64 = 1.0 / 1024.0;
+/// Move the climate samples away from the origin, so they don't correlate with the biomes' own noise.
+const CLIMATE_OFFSET: f64 = 7919.5;
+
+/// The climate value at which each biome is strongest. Adjacent centers are `BAND_WIDTH` apart,
+/// so the weights always sum to one.
+const CAVES_CENTER: f64 = -BAND_WIDTH;
+const HILLS_CENTER: f64 = 0.0;
+const MOUNTAINS_CENTER: f64 = BAND_WIDTH;
+const BAND_WIDTH: f64 = 0.6;
+
+#[allow(missing_docs)]
+pub struct T {
+  pub hills: hills::T,
+  pub mountains: mountains::T,
+  pub caves: caves::T,
+  pub seed: Seed,
+}
+
+#[allow(missing_docs)]
+pub fn new(seed: Seed) -> T {
+  T {
+    hills: hills::new(seed.clone()),
+    mountains: mountains::new(seed.clone()),
+    caves: caves::new(seed.clone()),
+    seed: seed,
+  }
+}
+
+/// The relative weights of each biome at a given point. They sum to one.
+#[allow(missing_docs)]
+pub struct Weights {
+  pub caves: f32,
+  pub hills: f32,
+  pub mountains: f32,
+}
+
+// A smoothed triangle function: 1 at `center`, falling off to 0 at `BAND_WIDTH` away.
+fn band(climate: f64, center: f64) -> f32 {
+  let t = 1.0 - (climate - center).abs() / BAND_WIDTH;
+  if t <= 0.0 {
+    0.0
+  } else {
+    (t * t * (3.0 - 2.0 * t)) as f32
+  }
+}
+
+impl T {
+  /// The climate at a given column of the world, in [CAVES_CENTER, MOUNTAINS_CENTER].
+  pub fn climate(&self, x: f32, z: f32) -> f64 {
+    let climate =
+      perlin2(
+        &self.seed,
+        &[
+          x as f64 * CLIMATE_FREQUENCY + CLIMATE_OFFSET,
+          z as f64 * CLIMATE_FREQUENCY + CLIMATE_OFFSET,
+        ],
+      );
+    f64::max(CAVES_CENTER, f64::min(MOUNTAINS_CENTER, climate))
+  }
+
+  /// How much each biome contributes to the terrain at a given point.
+  pub fn weights(&self, p: &Point3<f32>) -> Weights {
+    let climate = self.climate(p.x, p.z);
+    Weights {
+      caves: band(climate, CAVES_CENTER),
+      hills: band(climate, HILLS_CENTER),
+      mountains: band(climate, MOUNTAINS_CENTER),
+    }
+  }
+}
+
+impl voxel_data::field::T for T {
+  fn density(&self, p: &Point3<f32>) -> f32 {
+    let weights = self.weights(p);
+    let mut density = 0.0;
+    // Don't bother sampling biomes that don't contribute.
+    if weights.caves > 0.0 {
+      density += weights.caves * voxel_data::field::T::density(&self.caves, p);
+    }
+    if weights.hills > 0.0 {
+      density += weights.hills * voxel_data::field::T::density(&self.hills, p);
+    }
+    if weights.mountains > 0.0 {
+      density += weights.mountains * voxel_data::field::T::density(&self.mountains, p);
+    }
+    density
+  }
+
+  fn normal(&self, p: &Point3<f32>) -> Vector3<f32> {
+    // Use density differential in each dimension as an approximation of the normal.
+
+    let delta = 0.01;
+
+    macro_rules! differential(($d:ident) => {{
+      let high: f32 = {
+        let mut p = *p;
+        p.$d += delta;
+        voxel_data::field::T::density(self, &p)
+      };
+      let low: f32 = {
+        let mut p = *p;
+        p.$d -= delta;
+        voxel_data::field::T::density(self, &p)
+      };
+      high - low
+    }});
+
+    let v = Vector3::new(differential!(x), differential!(y), differential!(z));
+    // Negate because we're leaving the volume when density is decreasing.
+    let v = -v;
+    v.normalize()
+  }
+}
+
+impl voxel_data::mosaic::T<voxel::Material> for T {
+  fn material(&self, p: &Point3<f32>) -> Option<voxel::Material> {
+    if voxel_data::field::T::density(self, p) < 0.0 {
+      return Some(voxel::Material::Empty)
+    }
+
+    // Solid voxels take the material of whichever biome dominates here.
+    let weights = self.weights(p);
+    Some(
+      if weights.hills >= weights.mountains && weights.hills >= weights.caves {
+        voxel::Material::Terrain
+      } else {
+        voxel::Material::Stone
+      }
+    )
+  }
+}
@@ -0,0 +1,163 @@
+//! The voxel brushes that players edit the terrain with.
+
+use cgmath::{Aabb3, Point, Point3, Vector3};
+
+use common::brush;
+
+use voxel;
+use voxel_data;
+use voxel_data::field;
+use voxel_data::mosaic;
+
+mod cube {
+  use cgmath::{Point3, Vector3};
+
+  use voxel_data::field;
+
+  pub struct T {
+    pub half_width: f32,
+  }
+
+  impl field::T for T {
+    fn density(&self, p: &Point3<f32>) -> f32 {
+      self.half_width - p.x.abs().max(p.y.abs()).max(p.z.abs())
+    }
+
+    fn normal(&self, p: &Point3<f32>) -> Vector3<f32> {
+      // Point out of whichever face is closest.
+      let (x, y, z) = (p.x.abs(), p.y.abs(), p.z.abs());
+      if x >= y && x >= z {
+        Vector3::new(p.x.signum(), 0.0, 0.0)
+      } else if y >= z {
+        Vector3::new(0.0, p.y.signum(), 0.0)
+      } else {
+        Vector3::new(0.0, 0.0, p.z.signum())
+      }
+    }
+  }
+}
+
+mod cylinder {
+  use cgmath::{Point3, Vector3, EuclideanVector};
+
+  use voxel_data::field;
+
+  pub struct T {
+    pub radius: f32,
+    pub half_height: f32,
+  }
+
+  impl field::T for T {
+    fn density(&self, p: &Point3<f32>) -> f32 {
+      let side = self.radius - Vector3::new(p.x, 0.0, p.z).length();
+      let cap = self.half_height - p.y.abs();
+      side.min(cap)
+    }
+
+    fn normal(&self, p: &Point3<f32>) -> Vector3<f32> {
+      let side = self.radius - Vector3::new(p.x, 0.0, p.z).length();
+      let cap = self.half_height - p.y.abs();
+      if cap < side {
+        Vector3::new(0.0, p.y.signum(), 0.0)
+      } else {
+        Vector3::new(p.x, 0.0, p.z).normalize()
+      }
+    }
+  }
+}
+
+fn material(material: brush::Material) -> voxel::Material {
+  match material {
+    brush::Material::Empty => voxel::Material::Empty,
+    brush::Material::Terrain => voxel::Material::Terrain,
+    brush::Material::Bark => voxel::Material::Bark,
+    brush::Material::Leaves => voxel::Material::Leaves,
+    brush::Material::Stone => voxel::Material::Stone,
+  }
+}
+
+fn solid<Field>(center: &Point3<f32>, field: Field, material: voxel::Material) -> Box<mosaic::T<voxel::Material> + Send>
+  where Field: field::T + Send + 'static,
+{
+  Box::new(
+    mosaic::solid::T {
+      field: field::translation::T {
+        translation: center.to_vec(),
+        field: field,
+      },
+      material: material,
+    }
+  )
+}
+
+/// Build the voxel brush for a player's brush stroke centered at `center`.
+pub fn new(
+  center: &Point3<f32>,
+  brush: &brush::T,
+) -> voxel_data::brush::T<Box<mosaic::T<voxel::Material> + Send>> {
+  let material = material(brush.material);
+  let mosaic =
+    match brush.shape {
+      brush::Shape::Sphere => solid(center, field::sphere::T { radius: brush.size }, material),
+      brush::Shape::Cube => solid(center, cube::T { half_width: brush.size }, material),
+      brush::Shape::Cylinder => solid(center, cylinder::T { radius: brush.size, half_height: brush.size }, material),
+    };
+
+  // Every shape fits in a cube of the brush size; leave a voxel of room around it.
+  let r = brush.size + 1.0;
+  voxel_data::brush::T {
+    bounds:
+      Aabb3::new(
+        {
+          let low = center.add_v(&-Vector3::new(r, r, r));
+          Point3::new(low.x.floor() as i32, low.y.floor() as i32, low.z.floor() as i32)
+        },
+        {
+          let high = center.add_v(&Vector3::new(r, r, r));
+          Point3::new(high.x.ceil() as i32, high.y.ceil() as i32, high.z.ceil() as i32)
+        },
+      ),
+    min_lg_size: 0,
+    mosaic: mosaic,
+  }
+}
+
+#[test]
+fn shapes_are_solid_inside_and_empty_outside() {
+  let size = brush::MAX_SIZE;
+  let sphere = field::sphere::T { radius: size };
+  let cube = cube::T { half_width: size };
+  let cylinder = cylinder::T { radius: size, half_height: size };
+  let density =
+    |shape: &field::T, x: f32, y: f32, z: f32| field::T::density(shape, &Point3::new(x * size, y * size, z * size));
+
+  for shape in &[&sphere as &field::T, &cube, &cylinder] {
+    assert!(density(*shape, 0.0, 0.0, 0.0) > 0.0);
+    assert!(density(*shape, 0.0, 0.9, 0.0) > 0.0);
+    assert!(density(*shape, 0.0, -0.9, 0.0) > 0.0);
+    assert!(density(*shape, 0.9, 0.0, 0.0) > 0.0);
+    assert!(density(*shape, 1.1, 0.0, 0.0) < 0.0);
+    assert!(density(*shape, 0.0, 1.1, 0.0) < 0.0);
+    assert!(density(*shape, 0.0, 0.0, -1.1) < 0.0);
+  }
+
+  // The shapes differ toward the corners.
+  assert!(density(&sphere, 0.6, 0.9, 0.6) < 0.0);
+  assert!(density(&cylinder, 0.6, 0.9, 0.6) > 0.0);
+  assert!(density(&cylinder, 0.9, 0.9, 0.9) < 0.0);
+  assert!(density(&cube, 0.9, 0.9, 0.9) > 0.0);
+}
+
+#[test]
+fn bounds_contain_the_whole_shape() {
+  let center = Point3::new(0.3, -5.7, 10.5);
+  let size = brush::MAX_SIZE;
+  for &shape in &[brush::Shape::Sphere, brush::Shape::Cube, brush::Shape::Cylinder] {
+    let stroke = new(&center, &brush::T { shape: shape, size: size, material: brush::Material::Terrain });
+    let (low, high) = (stroke.bounds.min, stroke.bounds.max);
+    // Every shape is inside the cube `size` away from its center on each axis.
+    assert!(low.x as f32 <= center.x - size && center.x + size <= high.x as f32, "{:?}", shape);
+    assert!(low.y as f32 <= center.y - size && center.y + size <= high.y as f32, "{:?}", shape);
+    assert!(low.z as f32 <= center.z - size && center.z + size <= high.z as f32, "{:?}", shape);
+  }
+}
@@ -1,4 +1,4 @@
-use cgmath::{Aabb3, Point3, Point, Vector3};
+use cgmath::{Point3, Point, Vector3};
 use isosurface_extraction::dual_contouring;
 use std::sync::Mutex;
 use stopwatch;
@@ -14,66 +14,6 @@ use common::terrain_block::{TerrainBlock, tri};
 
 use voxel;
 
-#[inline]
-fn partial_min<I, It>(mut it: It) -> Option<I>
-where
-  I: PartialOrd,
-  It: Iterator<Item=I>,
-{
-  match it.next() {
-    None => None,
-    Some(mut best) => {
-      for thing in it {
-        if thing < best {
-          best = thing;
-        }
-      }
-      Some(best)
-    },
-  }
-}
-
-#[inline]
-fn partial_max<I, It>(mut it: It) -> Option<I>
-where
-  I: PartialOrd,
-  It: Iterator<Item=I>,
-{
-  match it.next() {
-    None => None,
-    Some(mut best) => {
-      for thing in it {
-        if thing > best {
-          best = thing;
-        }
-      }
-      Some(best)
-    },
-  }
-}
-
-fn make_bounds(
-  v1: &Point3<f32>,
-  v2: &Point3<f32>,
-  v3: &Point3<f32>,
-) -> Aabb3<f32> {
-  let minx = *partial_min([v1.x, v2.x, v3.x].iter()).unwrap();
-  let maxx = *partial_max([v1.x, v2.x, v3.x].iter()).unwrap();
-
-  let miny = *partial_min([v1.y, v2.y, v3.y].iter()).unwrap();
-  let maxy = *partial_max([v1.y, v2.y, v3.y].iter()).unwrap();
-
-  let minz = *partial_min([v1.z, v2.z, v3.z].iter()).unwrap();
-  let maxz = *partial_max([v1.z, v2.z, v3.z].iter()).unwrap();
-
-  Aabb3::new(
-    // TODO: Remove this - 1.0. It's a temporary hack until voxel collisions work,
-    // to avoid zero-height Aabb3s.
-    Point3::new(minx, miny - 1.0, minz),
-    Point3::new(maxx, maxy, maxz),
-  )
-}
-
 impl dual_contouring::material::T for voxel::Material {
   fn is_opaque(&self) -> bool {
     *self != voxel::Material::Empty
@@ -147,6 +87,42 @@ mod voxel_storage {
   }
 }
 
+// The range of samples along each edge of a block, in voxel coordinates at the block's LOD.
+fn sample_range(position: &BlockPosition, lod_index: LODIndex) -> (Point3<i32>, Point3<i32>) {
+  let lg_edge_samples = terrain_block::LG_EDGE_SAMPLES[lod_index.0 as usize];
+
+  let low = position.as_pnt();
+  let high = low.add_v(&Vector3::new(1, 1, 1));
+  let low =
+    Point3::new(
+      low.x << lg_edge_samples,
+      low.y << lg_edge_samples,
+      low.z << lg_edge_samples,
+    );
+  let high =
+    Point3::new(
+      high.x << lg_edge_samples,
+      high.y << lg_edge_samples,
+      high.z << lg_edge_samples,
+    );
+  (low, high)
+}
+
+/// The bounds of every voxel that `generate_block` might read for a given block.
+/// Each edge looks at the voxels on both sides of it, so this is one voxel wider than the block.
+pub fn voxels_read(position: &BlockPosition, lod_index: LODIndex) -> Vec<voxel_data::bounds::T> {
+  let lg_sample_size = terrain_block::LG_SAMPLE_SIZE[lod_index.0 as usize];
+  let (low, high) = sample_range(position, lod_index);
+
+  let mut r = Vec::new();
+  for x in range_inclusive(low.x - 1, high.x) {
+  for y in range_inclusive(low.y - 1, high.y) {
+  for z in range_inclusive(low.z - 1, high.z) {
+    r.push(voxel_data::bounds::new(x, y, z, lg_sample_size));
+  }}}
+  r
+}
+
 /// Generate a `TerrainBlock` based on a given position in a `voxel::tree::T`.
 /// Any necessary voxels will be generated.
 pub fn generate_block<Mosaic>(
@@ -161,23 +137,8 @@ pub fn generate_block<Mosaic>(
   stopwatch::time("update.generate_block", || {
     let mut block = TerrainBlock::empty();
 
-    let lg_edge_samples = terrain_block::LG_EDGE_SAMPLES[lod_index.0 as usize];
     let lg_sample_size = terrain_block::LG_SAMPLE_SIZE[lod_index.0 as usize];
-
-    let low = position.as_pnt();
-    let high = low.add_v(&Vector3::new(1, 1, 1));
-    let low =
-      Point3::new(
-        low.x << lg_edge_samples,
-        low.y << lg_edge_samples,
-        low.z << lg_edge_samples,
-      );
-    let high =
-      Point3::new(
-        high.x << lg_edge_samples,
-        high.y << lg_edge_samples,
-        high.z << lg_edge_samples,
-      );
+    let (low, high) = sample_range(position, lod_index);
 
     {
       let mut edges = |direction, low_x, high_x, low_y, high_y, low_z, high_z| {
@@ -206,7 +167,7 @@ pub fn generate_block<Mosaic>(
               block.normals.push(tri(polygon.normals[0], polygon.normals[1], polygon.normals[2]));
               block.materials.push(polygon.material as i32);
               block.ids.push(id);
-              block.bounds.push((id, make_bounds(&polygon.vertices[0], &polygon.vertices[1], &polygon.vertices[2])));
+              block.bounds.push((id, terrain_block::make_bounds(&polygon.vertices[0], &polygon.vertices[1], &polygon.vertices[2])));
             }
           );
         }}}
@@ -21,6 +21,7 @@ extern crate isosurface_extraction;
 extern crate log;
 extern crate noise;
 extern crate rand;
+extern crate rustc_serialize;
 extern crate stopwatch;
 extern crate test;
 extern crate time;
@@ -29,15 +30,21 @@ extern crate num;
 
 mod generate;
 pub mod biome;
+pub mod brush;
 
 pub mod tree;
+pub mod vegetation;
 
 pub use noise::Seed;
 
-use cgmath::Aabb;
+use cgmath::{Aabb, Aabb3, Point, Point3, Vector3};
+use std::cmp;
 use std::collections::hash_map::HashMap;
+use std::collections::hash_set::HashSet;
 use num::iter::range_inclusive;
+use std::mem;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use common::block_position::BlockPosition;
 use common::entity::EntityId;
@@ -50,7 +57,7 @@ use common::terrain_block::TerrainBlock;
 pub mod voxel {
   pub use voxel_data::impls::surface_vertex::*;
 
-  #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+  #[derive(Debug, Copy, Clone, PartialEq, Eq, RustcEncodable, RustcDecodable)]
   #[allow(missing_docs)]
   /// Terrain materials
   pub enum Material {
@@ -117,24 +124,270 @@ impl MipMeshMap {
 /// This struct contains and lazily generates the world's terrain.
 #[allow(missing_docs)]
 pub struct Terrain {
-  pub mosaic: biome::hills::T,
+  pub mosaic: vegetation::T,
   // all the blocks that have ever been created.
   pub all_blocks: Mutex<MipMeshMap>,
   pub voxels: Mutex<voxel::tree::T>,
+  /// The bounds of every brush that has been applied, i.e. the voxels that can't be regenerated.
+  pub edited_regions: Mutex<Vec<Aabb3<i32>>>,
+  /// Incremented (while `voxels` is locked) every time a brush changes the voxels,
+  /// so blocks generated from older voxels can be detected and regenerated.
+  edit_count: AtomicUsize,
+  /// Held for the duration of a brush, so concurrent brushes can't remesh blocks out of order.
+  brushing: Mutex<()>,
+}
+
+/// The position and size of a voxel, as (x, y, z, lg_size).
+pub type VoxelBounds = (i32, i32, i32, i16);
+
+/// The contents of a region of voxels at every LOD, e.g. from before it was brushed.
+pub struct Snapshot {
+  #[allow(missing_docs)]
+  pub bounds: Aabb3<i32>,
+  voxels: Vec<(VoxelBounds, voxel::T<voxel::Material>)>,
+}
+
+impl Snapshot {
+  /// A snapshot of a region that doesn't save any voxels.
+  pub fn empty(bounds: Aabb3<i32>) -> Snapshot {
+    Snapshot {
+      bounds: bounds,
+      voxels: Vec::new(),
+    }
+  }
+
+  /// The number of voxels saved.
+  pub fn len(&self) -> usize {
+    self.voxels.len()
+  }
+}
+
+macro_rules! voxel_range(($bounds:expr, $d:ident, $scale:expr) => {{
+  let low = $bounds.min().$d >> $scale;
+  let high = $bounds.max().$d >> $scale;
+  range_inclusive(low, high)
+}});
+
+// Set a voxel only if it hasn't been generated already.
+fn fill_voxel<F>(
+  voxels: &mut voxel::tree::T,
+  bounds: &voxel_data::bounds::T,
+  value: F,
+) where F: FnOnce() -> voxel::T<voxel::Material>
+{
+  let voxel = voxels.get_mut_or_create(bounds);
+  match voxel {
+    &mut voxel::tree::Empty => {
+      *voxel = voxel::tree::TreeBody::leaf(Some(value()));
+    },
+    &mut voxel::tree::Branch { ref mut data, branches: _ } => {
+      match data {
+        &mut None => *data = Some(value()),
+        &mut Some(_) => {},
+      }
+    },
+  }
+}
+
+fn set_voxel(
+  voxels: &mut voxel::tree::T,
+  bounds: &voxel_data::bounds::T,
+  value: voxel::T<voxel::Material>,
+) {
+  let voxel = voxels.get_mut_or_create(bounds);
+  match voxel {
+    &mut voxel::tree::Empty => {
+      *voxel = voxel::tree::TreeBody::leaf(Some(value));
+    },
+    &mut voxel::tree::Branch { ref mut data, branches: _ } => {
+      *data = Some(value);
+    },
+  }
+}
+
+// Look up a voxel's contents without creating anything in the tree.
+fn get_voxel(
+  voxels: &voxel::tree::T,
+  bounds: &voxel_data::bounds::T,
+) -> Option<voxel::T<voxel::Material>> {
+  voxels.get(bounds).cloned()
+}
+
+fn volume(region: &Aabb3<i32>) -> i64 {
+  let d = region.max().sub_p(region.min());
+  (d.x as i64 + 1) * (d.y as i64 + 1) * (d.z as i64 + 1)
+}
+
+fn union(a: &Aabb3<i32>, b: &Aabb3<i32>) -> Aabb3<i32> {
+  Aabb3::new(
+    Point3::new(cmp::min(a.min.x, b.min.x), cmp::min(a.min.y, b.min.y), cmp::min(a.min.z, b.min.z)),
+    Point3::new(cmp::max(a.max.x, b.max.x), cmp::max(a.max.y, b.max.y), cmp::max(a.max.z, b.max.z)),
+  )
+}
+
+/// Add a region to a list of edited regions. Regions are merged whenever a box around both of them is
+/// no bigger than the two of them put together, so that editing the same area over and over doesn't
+/// keep growing the list.
+fn add_edited_region(regions: &mut Vec<Aabb3<i32>>, region: &Aabb3<i32>) {
+  let mut region = region.clone();
+  loop {
+    let merge =
+      regions.iter().position(|r| {
+        volume(&union(r, &region)) <= volume(r) + volume(&region)
+      });
+    match merge {
+      None => break,
+      Some(i) => {
+        let r = regions.swap_remove(i);
+        region = union(&r, &region);
+      },
+    }
+  }
+  regions.push(region);
+}
+
+// The exact contents of one triangle of a block, for finding triangles that didn't change.
+fn triangle_key(block: &TerrainBlock, i: usize) -> Vec<u32> {
+  let bits = |x: f32| -> u32 { unsafe { mem::transmute(x) } };
+  let v = &block.vertex_coordinates[i];
+  let n = &block.normals[i];
+  let mut key = Vec::with_capacity(19);
+  for p in &[v.v1, v.v2, v.v3] {
+    key.push(bits(p.x));
+    key.push(bits(p.y));
+    key.push(bits(p.z));
+  }
+  for n in &[n.v1, n.v2, n.v3] {
+    key.push(bits(n.x));
+    key.push(bits(n.y));
+    key.push(bits(n.z));
+  }
+  key.push(block.materials[i] as u32);
+  key
+}
+
+/// Give the triangles in `new` that are identical to ones in `old` the same ids they had in `old`,
+/// so that clients can be sent only the triangles that changed.
+fn reuse_ids(old: &TerrainBlock, new: &mut TerrainBlock) {
+  let mut old_ids = HashMap::new();
+  for i in 0 .. old.ids.len() {
+    old_ids.insert(triangle_key(old, i), old.ids[i]);
+  }
+
+  for i in 0 .. new.ids.len() {
+    // Remove as we go, so duplicate triangles don't end up sharing an id.
+    match old_ids.remove(&triangle_key(new, i)) {
+      None => {},
+      Some(id) => {
+        new.ids[i] = id;
+        new.bounds[i].0 = id;
+      },
+    }
+  }
 }
 
 impl Terrain {
   #[allow(missing_docs)]
-  pub fn new(terrain_seed: Seed) -> Terrain {
+  pub fn new(terrain_seed: u32) -> Terrain {
     Terrain {
-      mosaic: biome::hills::new(terrain_seed),
+      mosaic: vegetation::new(terrain_seed),
       all_blocks: Mutex::new(MipMeshMap::new()),
       voxels: Mutex::new(voxel::tree::T::new()),
+      edited_regions: Mutex::new(Vec::new()),
+      edit_count: AtomicUsize::new(0),
+      brushing: Mutex::new(()),
+    }
+  }
+
+  /// Collect the contents of every voxel inside an edited region, at every LOD.
+  pub fn edited_voxels(&self) -> Vec<(VoxelBounds, voxel::T<voxel::Material>)> {
+    let edited_regions = self.edited_regions.lock().unwrap();
+    let voxels = self.voxels.lock().unwrap();
+    let mut seen = HashSet::new();
+    let mut r = Vec::new();
+    for region in edited_regions.iter() {
+      for &lg_size in &terrain_block::LG_SAMPLE_SIZE {
+        for x in voxel_range!(region, x, lg_size) {
+        for y in voxel_range!(region, y, lg_size) {
+        for z in voxel_range!(region, z, lg_size) {
+          if !seen.insert((x, y, z, lg_size)) {
+            continue
+          }
+
+          let bounds = voxel_data::bounds::new(x, y, z, lg_size);
+          match get_voxel(&voxels, &bounds) {
+            None => {
+              // Nothing was ever generated here, so it can be regenerated from the mosaic.
+            },
+            Some(data) => r.push(((x, y, z, lg_size), data)),
+          }
+        }}}
+      }
+    }
+    r
+  }
+
+  /// Overwrite voxels with previously-saved contents, and mark their regions as edited.
+  pub fn restore_voxels(
+    &self,
+    regions: Vec<Aabb3<i32>>,
+    saved: Vec<(VoxelBounds, voxel::T<voxel::Material>)>,
+  ) {
+    let mut voxels = self.voxels.lock().unwrap();
+    for ((x, y, z, lg_size), voxel) in saved.into_iter() {
+      set_voxel(&mut voxels, &voxel_data::bounds::new(x, y, z, lg_size), voxel);
+    }
+    let mut edited_regions = self.edited_regions.lock().unwrap();
+    for region in &regions {
+      add_edited_region(&mut edited_regions, region);
     }
   }
 
+  /// Generate a block without holding any locks on the shared terrain while it's meshed.
+  /// The voxels the block needs are copied into a private tree, and any that had to be generated
+  /// are merged back afterwards. Returns the block, along with the edit count of the voxels it came from.
+  fn generate(
+    &self,
+    id_allocator: &Mutex<IdAllocator<EntityId>>,
+    position: &BlockPosition,
+    lod_index: LODIndex,
+  ) -> (usize, TerrainBlock) {
+    let bounds = generate::voxels_read(position, lod_index);
+
+    let mut scratch = voxel::tree::T::new();
+    let edit_count;
+    {
+      let voxels = self.voxels.lock().unwrap();
+      for bounds in &bounds {
+        get_voxel(&voxels, bounds).map(|data| set_voxel(&mut scratch, bounds, data));
+      }
+      edit_count = self.edit_count.load(Ordering::SeqCst);
+    }
+
+    let mosaic = {
+      let width = terrain_block::WIDTH as f32;
+      let p = position.as_pnt();
+      let low = Point3::new(p.x as f32 * width, p.y as f32 * width, p.z as f32 * width);
+      // The block's edges read voxels up to a block width past it.
+      self.mosaic.near(
+        &low.add_v(&Vector3::new(-width, -width, -width)),
+        &low.add_v(&Vector3::new(2.0 * width, 2.0 * width, 2.0 * width)),
+      )
+    };
+    let block = generate::generate_block(id_allocator, &mosaic, &mut scratch, position, lod_index);
+
+    {
+      let mut voxels = self.voxels.lock().unwrap();
+      for bounds in &bounds {
+        get_voxel(&scratch, bounds).map(|data| fill_voxel(&mut voxels, bounds, || data));
+      }
+    }
+
+    (edit_count, block)
+  }
+
   /// Load the block of terrain at a given position.
-  // TODO: Allow this to be performed in such a way that self is only briefly locked.
+  /// Independent blocks can be loaded from several threads at once.
   pub fn load<F>(
     &self,
     id_allocator: &Mutex<IdAllocator<EntityId>>,
@@ -143,85 +396,152 @@ impl Terrain {
     f: F
   ) where F: FnOnce(&TerrainBlock)
   {
-    let mut all_blocks = self.all_blocks.lock().unwrap();
-    let mip_mesh = all_blocks.get_mut(position);
-    let mesh = mip_mesh.get_mut(lod_index.0 as usize);
-    match mesh {
-      &mut None => {
-        let new_mesh =
-          generate::generate_block(
-            id_allocator,
-            &self.mosaic,
-            &mut *self.voxels.lock().unwrap(),
-            position,
-            lod_index,
-          );
-        f(&new_mesh);
+    let lod = lod_index.0 as usize;
+
+    let existing =
+      self.all_blocks.lock().unwrap()
+      .get(position)
+      .and_then(|mip_mesh| mip_mesh.lods.get(lod).and_then(|mesh| mesh.clone()));
+    if let Some(block) = existing {
+      f(&block);
+      return
+    }
+
+    let block;
+    loop {
+      let (edit_count, new_mesh) = self.generate(id_allocator, position, lod_index);
+
+      let mut all_blocks = self.all_blocks.lock().unwrap();
+      if self.edit_count.load(Ordering::SeqCst) != edit_count {
+        // A brush changed the voxels while we were generating, and couldn't have updated this
+        // block because it wasn't in `all_blocks` yet.
+        continue
+      }
+
+      let mesh = all_blocks.get_mut(position).get_mut(lod);
+      block =
+        match mesh {
+          &mut Some(ref mesh) => {
+            // Another thread generated this block while we were.
+            mesh.clone()
+          },
+          &mut None => new_mesh.clone(),
+        };
+      if mesh.is_none() {
         *mesh = Some(new_mesh);
-      },
-      &mut Some(ref mesh) => {
-        f(mesh)
-      },
+      }
+      break
     }
+
+    f(&block);
   }
 
-  /// Apply a voxel brush to the terrain.
+  /// Apply a voxel brush to the terrain, and return the voxels it overwrote.
+  /// `block_changed` is called with the old and new versions of every loaded block the brush changes.
   pub fn brush<F, Mosaic>(
     &self,
     id_allocator: &Mutex<IdAllocator<EntityId>>,
     brush: &voxel_data::brush::T<Mosaic>,
     mut block_changed: F,
-  ) where
-    F: FnMut(&TerrainBlock, &BlockPosition, LODIndex),
+  ) -> Snapshot where
+    F: FnMut(&TerrainBlock, &TerrainBlock, &BlockPosition, LODIndex),
     Mosaic: voxel_data::mosaic::T<voxel::Material>,
   {
-    macro_rules! voxel_range(($d:ident, $scale:expr) => {{
-      let low = brush.bounds.min().$d >> $scale;
-      let high = brush.bounds.max().$d >> $scale;
-      range_inclusive(low, high)
-    }});
+    let _brushing = self.brushing.lock().unwrap();
 
-    {
+    let before = {
       let mut voxels = self.voxels.lock().unwrap();
       // Make sure that all the voxels this brush might touch are generated; if they're not generated
       // now, the brush might "expose" them, the mesh extraction phase will generate them, and there
       // may be inconsistencies between the brush-altered voxels and the newly-generated ones.
-      for &lg_size in &terrain_block::LG_SAMPLE_SIZE {
-        for x in voxel_range!(x, lg_size) {
-        for y in voxel_range!(y, lg_size) {
-        for z in voxel_range!(z, lg_size) {
-          let bounds = voxel_data::bounds::new(x, y, z, lg_size);
-          let voxel = voxels.get_mut_or_create(&bounds);
-          match voxel {
-            &mut voxel::tree::Empty => {
-              *voxel =
-                voxel::tree::TreeBody::leaf(
-                  Some(voxel::unwrap(voxel::of_field(&self.mosaic, &bounds)))
-                );
-            },
-            &mut voxel::tree::Branch { ref mut data, branches: _ } => {
-              match data {
-                &mut None => {
-                  *data =
-                    Some(voxel::unwrap(voxel::of_field(&self.mosaic, &bounds)));
-                },
-                &mut Some(_) => {},
-              }
-            },
-          }
-        }}}
-      }
+      let before = self.snapshot(&mut voxels, &brush.bounds);
 
       voxels.brush(
         brush,
         &mut |_| None,
         &mut |_, _| {},
       );
+
+      add_edited_region(&mut self.edited_regions.lock().unwrap(), &brush.bounds);
+      self.edit_count.fetch_add(1, Ordering::SeqCst);
+      before
+    };
+
+    self.remesh(id_allocator, &brush.bounds, &mut block_changed);
+    before
+  }
+
+  /// Put back the voxels in a snapshot, and remesh the blocks they're in like `brush` does.
+  /// Returns the voxels that were replaced.
+  pub fn restore<F>(
+    &self,
+    id_allocator: &Mutex<IdAllocator<EntityId>>,
+    snapshot: Snapshot,
+    mut block_changed: F,
+  ) -> Snapshot where
+    F: FnMut(&TerrainBlock, &TerrainBlock, &BlockPosition, LODIndex),
+  {
+    let _brushing = self.brushing.lock().unwrap();
+
+    let before = {
+      let mut voxels = self.voxels.lock().unwrap();
+      let before = self.snapshot(&mut voxels, &snapshot.bounds);
+      for ((x, y, z, lg_size), voxel) in snapshot.voxels.into_iter() {
+        set_voxel(&mut voxels, &voxel_data::bounds::new(x, y, z, lg_size), voxel);
+      }
+
+      add_edited_region(&mut self.edited_regions.lock().unwrap(), &snapshot.bounds);
+      self.edit_count.fetch_add(1, Ordering::SeqCst);
+      before
+    };
+
+    self.remesh(id_allocator, &before.bounds, &mut block_changed);
+    before
+  }
+
+  // Save the contents of every voxel in a region that a block's mesh could use, generating them first if need be.
+  fn snapshot(&self, voxels: &mut voxel::tree::T, region: &Aabb3<i32>) -> Snapshot {
+    let mosaic = {
+      // Voxels are at most a block wide, so none reach further than that past the region.
+      let width = terrain_block::WIDTH as f32;
+      self.mosaic.near(
+        &Point3::new(region.min.x as f32 - width, region.min.y as f32 - width, region.min.z as f32 - width),
+        &Point3::new(region.max.x as f32 + width, region.max.y as f32 + width, region.max.z as f32 + width),
+      )
+    };
+    let mut saved = Vec::new();
+    for &lg_size in &terrain_block::LG_SAMPLE_SIZE {
+      for x in voxel_range!(region, x, lg_size) {
+      for y in voxel_range!(region, y, lg_size) {
+      for z in voxel_range!(region, z, lg_size) {
+        let bounds = voxel_data::bounds::new(x, y, z, lg_size);
+        fill_voxel(voxels, &bounds, || voxel::unwrap(voxel::of_field(&mosaic, &bounds)));
+        match voxels.get_mut_or_create(&bounds) {
+          &mut voxel::tree::Branch { data: Some(ref data), branches: _ } => {
+            saved.push(((x, y, z, lg_size), data.clone()));
+          },
+          _ => unreachable!(),
+        }
+      }}}
+    }
+    Snapshot {
+      bounds: region.clone(),
+      voxels: saved,
     }
+  }
 
+  // Regenerate every loaded block that overlaps a region of changed voxels.
+  fn remesh<F>(
+    &self,
+    id_allocator: &Mutex<IdAllocator<EntityId>>,
+    region: &Aabb3<i32>,
+    block_changed: &mut F,
+  ) where
+    F: FnMut(&TerrainBlock, &TerrainBlock, &BlockPosition, LODIndex),
+  {
     macro_rules! block_range(($d:ident) => {{
-      let low = brush.bounds.min().$d >> terrain_block::LG_WIDTH;
-      let high = brush.bounds.max().$d >> terrain_block::LG_WIDTH;
+      let low = region.min().$d >> terrain_block::LG_WIDTH;
+      let high = region.max().$d >> terrain_block::LG_WIDTH;
       range_inclusive(low, high)
     }});
 
@@ -229,28 +549,43 @@ impl Terrain {
     for y in block_range!(y) {
     for z in block_range!(z) {
       let position = BlockPosition::new(x, y, z);
-      let mut all_blocks = self.all_blocks.lock().unwrap();
-      let mip_mesh = all_blocks.get_mut(&position);
-
-      for (i, mesh) in mip_mesh.lods.iter_mut().enumerate() {
-        match mesh {
-          &mut None => {},
-          &mut Some(ref mut mesh) => {
-            let lod_index = LODIndex(i as u32);
-            *mesh =
-              generate::generate_block(
-                id_allocator,
-                &self.mosaic,
-                &mut *self.voxels.lock().unwrap(),
-                &position,
-                lod_index,
-              )
-            ;
-
-            block_changed(mesh, &position, lod_index);
+      let loaded: Vec<LODIndex> =
+        match self.all_blocks.lock().unwrap().get(&position) {
+          None => Vec::new(),
+          Some(mip_mesh) => {
+            mip_mesh.lods.iter().enumerate()
+              .filter(|&(_, mesh)| mesh.is_some())
+              .map(|(i, _)| LODIndex(i as u32))
+              .collect()
           },
-        }
+        };
+
+      for lod_index in loaded.into_iter() {
+        let (_, mut mesh) = self.generate(id_allocator, &position, lod_index);
+        let old = {
+          let mut all_blocks = self.all_blocks.lock().unwrap();
+          let old = all_blocks.get_mut(&position).get_mut(lod_index.0 as usize);
+          let prev = mem::replace(old, None).unwrap_or(TerrainBlock::empty());
+          reuse_ids(&prev, &mut mesh);
+          *old = Some(mesh.clone());
+          prev
+        };
+        block_changed(&old, &mesh, &position, lod_index);
       }
     }}}
   }
 }
+
+#[test]
+fn overlapping_edits_merge() {
+  let region = |low: i32, high: i32| Aabb3::new(Point3::new(low, low, low), Point3::new(high, high, high));
+  let mut regions = Vec::new();
+  for _ in 0..10 {
+    add_edited_region(&mut regions, &region(0, 4));
+  }
+  add_edited_region(&mut regions, &region(1, 3));
+  assert_eq!(regions.len(), 1);
+
+  add_edited_region(&mut regions, &region(100, 104));
+  assert_eq!(regions.len(), 2);
+}
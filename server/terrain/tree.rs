@@ -1,11 +1,16 @@
-//! A tree is comprised of a cylindrical trunk, a spherical bunch of leaves, and a spherical
-//! rounding to the bottom of the trunk.
+//! Procedurally generated trees. A tree is a trunk rounded at both ends, limbs branching off of it,
+//! and leaves on the limbs, all shaped by its species.
+//!
+//! A tree is entirely determined by its species and seed, so the same tree can be regenerated
+//! anywhere.
 
 use cgmath::{Point, Point3, Vector, Vector3, EuclideanVector, Rotation};
 use rand;
+use rand::{Rng, SeedableRng, XorShiftRng};
+use rand::distributions::IndependentSample;
+use std::f32::consts::PI;
 
 use voxel;
-use voxel_data;
 use voxel_data::field;
 use voxel_data::mosaic;
 
@@ -35,12 +40,195 @@ mod pillar {
   }
 }
 
+mod cone {
+  use cgmath::{Point3, Vector, Vector3, EuclideanVector};
+
+  use voxel_data::field;
+
+  /// A cone with its base centered on the origin, pointing up.
+  pub struct T {
+    pub radius: f32,
+    pub height: f32,
+  }
+
+  unsafe impl Send for T {}
+
+  impl field::T for T {
+    fn density(&self, p: &Point3<f32>) -> f32 {
+      let side = self.radius * (1.0 - p.y / self.height) - Vector3::new(p.x, 0.0, p.z).length();
+      side.min(p.y)
+    }
+
+    fn normal(&self, p: &Point3<f32>) -> Vector3<f32> {
+      let side = self.radius * (1.0 - p.y / self.height) - Vector3::new(p.x, 0.0, p.z).length();
+      if p.y < side {
+        Vector3::new(0.0, -1.0, 0.0)
+      } else {
+        let out = Vector3::new(p.x, 0.0, p.z);
+        if out.length2() == 0.0 {
+          Vector3::new(0.0, 1.0, 0.0)
+        } else {
+          out.normalize().mul_s(self.height).add_v(&Vector3::new(0.0, self.radius, 0.0)).normalize()
+        }
+      }
+    }
+  }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub enum Species {
+  Oak,
+  Pine,
+  Bush,
+  Dead,
+}
+
+#[allow(missing_docs)]
+pub const SPECIES: [Species; 4] = [Species::Oak, Species::Pine, Species::Bush, Species::Dead];
+
+/// A normally-distributed parameter, clamped to a range.
+struct Range {
+  mean: f64,
+  stddev: f64,
+  min: f64,
+  max: f64,
+}
+
+impl Range {
+  fn sample<Rng: rand::Rng>(&self, rng: &mut Rng) -> f32 {
+    let x = rand::distributions::normal::Normal::new(self.mean, self.stddev).ind_sample(rng);
+    f64::max(self.min, f64::min(self.max, x)) as f32
+  }
+}
+
+/// Where a species' limbs grow.
+enum Branching {
+  /// Limbs fan out from the top of the trunk to points throughout the crown.
+  Crown,
+  /// Rings of drooping limbs up the top of the trunk, shorter toward the top.
+  Whorls,
+  /// A few bare limbs jutting up from the top half of the trunk.
+  Sparse,
+}
+
+/// What a species' leaves look like.
+enum Leaves {
+  None,
+  /// A ball of leaves at the end of every limb.
+  Clusters {
+    radius: f32,
+  },
+  /// A single cone of leaves around the top of the trunk.
+  Cone,
+}
+
+struct Params {
+  trunk_radius: Range,
+  /// In multiples of the trunk radius.
+  trunk_height: Range,
+  /// How far limbs reach from the trunk, in multiples of the trunk radius.
+  crown_radius: Range,
+  branching: Branching,
+  leaves: Leaves,
+}
+
+impl Species {
+  fn params(self) -> Params {
+    match self {
+      Species::Oak =>
+        Params {
+          trunk_radius: Range { mean: 2.0, stddev: 0.5, min: 1.0, max: 3.0 },
+          trunk_height: Range { mean: 8.0, stddev: 2.0, min: 4.0, max: 12.0 },
+          crown_radius: Range { mean: 4.0, stddev: 1.0, min: 2.0, max: 6.0 },
+          branching: Branching::Crown,
+          leaves: Leaves::Clusters { radius: 4.0 },
+        },
+      Species::Pine =>
+        Params {
+          trunk_radius: Range { mean: 1.5, stddev: 0.3, min: 1.0, max: 2.0 },
+          trunk_height: Range { mean: 16.0, stddev: 3.0, min: 10.0, max: 24.0 },
+          crown_radius: Range { mean: 4.0, stddev: 0.5, min: 3.0, max: 5.0 },
+          branching: Branching::Whorls,
+          leaves: Leaves::Cone,
+        },
+      Species::Bush =>
+        Params {
+          trunk_radius: Range { mean: 0.75, stddev: 0.1, min: 0.5, max: 1.0 },
+          trunk_height: Range { mean: 2.0, stddev: 0.5, min: 1.0, max: 3.0 },
+          crown_radius: Range { mean: 4.0, stddev: 1.0, min: 3.0, max: 6.0 },
+          branching: Branching::Crown,
+          leaves: Leaves::Clusters { radius: 2.0 },
+        },
+      Species::Dead =>
+        Params {
+          trunk_radius: Range { mean: 1.5, stddev: 0.5, min: 1.0, max: 2.5 },
+          trunk_height: Range { mean: 8.0, stddev: 2.0, min: 4.0, max: 12.0 },
+          crown_radius: Range { mean: 3.0, stddev: 1.0, min: 2.0, max: 5.0 },
+          branching: Branching::Sparse,
+          leaves: Leaves::None,
+        },
+    }
+  }
+}
+
+/// Like `mosaic::union`, but its parts can be shared between threads, so trees can be too.
+mod union {
+  use cgmath::{Point3, Vector3};
+
+  use voxel;
+  use voxel_data::field;
+
+  pub struct T {
+    pub parts: Vec<(voxel::Material, Box<field::T + Send + Sync>)>,
+  }
+
+  impl T {
+    // The part with the greatest density at a point, and its density.
+    fn densest(&self, p: &Point3<f32>) -> Option<(&(voxel::Material, Box<field::T + Send + Sync>), f32)> {
+      let mut r: Option<(&(voxel::Material, Box<field::T + Send + Sync>), f32)> = None;
+      for part in &self.parts {
+        let density = field::T::density(&*part.1, p);
+        if r.map_or(true, |(_, best)| density > best) {
+          r = Some((part, density));
+        }
+      }
+      r
+    }
+
+    pub fn density(&self, p: &Point3<f32>) -> f32 {
+      self.densest(p).map_or(-1.0, |(_, density)| density)
+    }
+
+    pub fn normal(&self, p: &Point3<f32>) -> Vector3<f32> {
+      self.densest(p).map_or(Vector3::new(0.0, 1.0, 0.0), |(part, _)| field::T::normal(&*part.1, p))
+    }
+
+    pub fn material(&self, p: &Point3<f32>) -> Option<voxel::Material> {
+      match self.densest(p) {
+        Some((part, density)) if density >= 0.0 => Some(part.0),
+        _ => None,
+      }
+    }
+  }
+}
+
 #[allow(missing_docs)]
 pub struct T {
-  union: voxel_data::mosaic::union::T<voxel::Material>,
+  union: union::T,
+  low: Point3<f32>,
+  high: Point3<f32>,
 }
 
-unsafe impl Send for T {}
+/// The generator for a tree's seed. The seed is scrambled first, so that nearby seeds give unrelated
+/// trees, and so that the generator's state is never all zeros.
+fn rng(seed: u32) -> XorShiftRng {
+  let mut x = seed;
+  x = (x ^ (x >> 16)).wrapping_mul(0x85ebca6b);
+  x = (x ^ (x >> 13)).wrapping_mul(0xc2b2ae35);
+  x = x ^ (x >> 16);
+  SeedableRng::from_seed([x, !x, 0x9e3779b9, 0x7f4a7c15])
+}
 
 fn inside_sphere<Rng>(
   rng: &mut Rng,
@@ -61,94 +249,201 @@ fn inside_sphere<Rng>(
   }
 }
 
-#[allow(missing_docs)]
-pub fn new<Rng>(
-  rng: &mut Rng,
-  trunk_height: f32,
-  trunk_radius: f32,
-  leaf_radius: f32,
-) -> T
-  where Rng: rand::Rng,
-{
-  let trunk_top = Point3::new(0.0, trunk_height, 0.0);
-  let leaf_center = trunk_top.add_v(&Vector3::new(0.0, leaf_radius / 2.0, 0.0));
-  let trunk_center = Point3::new(0.0, trunk_height / 2.0, 0.0);
-
-  let mut union = mosaic::union::new();
+/// A horizontal unit vector `angle` radians around the trunk, tilted up by `rise`.
+fn heading(angle: f32, rise: f32) -> Vector3<f32> {
+  Vector3::new(angle.cos(), rise, angle.sin()).normalize()
+}
 
-  let trunk =
-    field::translation::T {
-      translation: trunk_center.to_vec(),
-      field: field::intersection::new(
-        pillar::T {
-          radius: trunk_radius,
-        },
-        field::sphere::T {
-          radius: trunk_height / 2.0,
-        },
+impl T {
+  fn push<Field>(&mut self, material: voxel::Material, center: &Point3<f32>, radius: f32, field: Field)
+    where Field: field::T + Send + Sync + 'static,
+  {
+    self.low = Point3::new(self.low.x.min(center.x - radius), self.low.y.min(center.y - radius), self.low.z.min(center.z - radius));
+    self.high = Point3::new(self.high.x.max(center.x + radius), self.high.y.max(center.y + radius), self.high.z.max(center.z + radius));
+    self.union.parts.push((
+      material,
+      Box::new(
+        field::translation::T {
+          translation: center.to_vec(),
+          field: field,
+        }
       ),
-    };
-
-  union.push(voxel::Material::Bark, trunk);
-
-  let leaf_count = {
-    let leaf_radius = leaf_radius / 3.0;
-    (leaf_radius * leaf_radius * leaf_radius) as i32
-  };
-  for _ in 0..leaf_count {
-    let branch_end = leaf_center.add_v(&inside_sphere(rng, leaf_radius).to_vec());
-    let branch = branch_end.sub_p(&trunk_top);
-    let half_length = branch.length() / 2.0;
+    ));
+  }
 
-    union.push(
+  fn limb(&mut self, from: &Point3<f32>, to: &Point3<f32>, radius: f32) {
+    let limb = to.sub_p(from);
+    let half_length = limb.length() / 2.0;
+    let center = from.add_v(&limb.div_s(2.0));
+    self.push(
       voxel::Material::Bark,
-      field::translation::T {
-        translation: trunk_top.to_vec(),
-        field: field::rotation::T {
-          rotation: Rotation::between_vectors(&Vector3::new(0.0, 1.0, 0.0), &branch.normalize()),
-          field: field::translation::T {
-            translation: Vector3::new(0.0, half_length, 0.0),
-            field: field::intersection::new(
-              field::sphere::T {
-                radius: half_length,
-              },
-              pillar::T {
-                radius: 0.5,
-              },
-            ),
+      &center,
+      half_length,
+      field::rotation::T {
+        rotation: Rotation::between_vectors(&Vector3::new(0.0, 1.0, 0.0), &limb.normalize()),
+        field: field::intersection::new(
+          field::sphere::T {
+            radius: half_length,
           },
-        },
+          pillar::T {
+            radius: radius,
+          },
+        ),
       },
     );
+  }
 
-    union.push(
-      voxel::Material::Leaves,
-      field::translation::T {
-        translation: branch_end.to_vec(),
-        field: field::sphere::T {
-          radius: 4.0,
-        },
+  /// The corners of a box around everything in the tree, relative to the base of its trunk.
+  pub fn corners(&self) -> (Point3<f32>, Point3<f32>) {
+    (self.low, self.high)
+  }
+}
+
+/// Generate a tree of the given species, with its trunk based at the origin.
+pub fn new(species: Species, seed: u32) -> T {
+  let rng = &mut rng(seed);
+  let params = species.params();
+
+  let trunk_radius = params.trunk_radius.sample(rng);
+  let trunk_height = trunk_radius * params.trunk_height.sample(rng);
+  let crown_radius = trunk_radius * params.crown_radius.sample(rng);
+  let limb_radius = f32::max(0.5, trunk_radius / 4.0);
+
+  let trunk_top = Point3::new(0.0, trunk_height, 0.0);
+
+  let mut tree =
+    T {
+      union: union::T { parts: Vec::new() },
+      low: Point3::new(0.0, 0.0, 0.0),
+      high: Point3::new(0.0, 0.0, 0.0),
+    };
+
+  tree.push(
+    voxel::Material::Bark,
+    &Point3::new(0.0, trunk_height / 2.0, 0.0),
+    trunk_height / 2.0,
+    field::intersection::new(
+      pillar::T {
+        radius: trunk_radius,
       },
-    );
+      field::sphere::T {
+        radius: trunk_height / 2.0,
+      },
+    ),
+  );
+
+  let mut limb_ends = Vec::new();
+  match params.branching {
+    Branching::Crown => {
+      let crown_center = trunk_top.add_v(&Vector3::new(0.0, crown_radius / 2.0, 0.0));
+      let cluster_radius =
+        match params.leaves {
+          Leaves::Clusters { radius } => radius,
+          _ => 4.0,
+        };
+      let limb_count = {
+        let r = crown_radius / (0.75 * cluster_radius);
+        i32::max(1, (r * r * r) as i32)
+      };
+      for _ in 0..limb_count {
+        let end = crown_center.add_v(&inside_sphere(rng, crown_radius).to_vec());
+        tree.limb(&trunk_top, &end, limb_radius);
+        limb_ends.push(end);
+      }
+    },
+    Branching::Whorls => {
+      let crown_base = trunk_height / 3.0;
+      let whorl_count = i32::max(2, ((trunk_height - crown_base) / 3.0) as i32);
+      for i in 0..whorl_count {
+        let y = crown_base + (trunk_height - crown_base) * i as f32 / whorl_count as f32;
+        // Stay inside the cone of leaves.
+        let length = crown_radius * (1.0 - (y - crown_base) / (trunk_height - crown_base)) * rng.gen_range(0.6, 0.9);
+        if length < 1.0 {
+          continue
+        }
+        let limbs_per_whorl = rng.gen_range(4, 7);
+        let offset = rng.gen_range(0.0, 2.0 * PI);
+        for j in 0..limbs_per_whorl {
+          let angle = offset + 2.0 * PI * j as f32 / limbs_per_whorl as f32;
+          let from = Point3::new(0.0, y, 0.0);
+          let end = from.add_v(&heading(angle, -0.2).mul_s(length));
+          tree.limb(&from, &end, limb_radius);
+          limb_ends.push(end);
+        }
+      }
+    },
+    Branching::Sparse => {
+      let limb_count = rng.gen_range(2, 6);
+      for _ in 0..limb_count {
+        let from = Point3::new(0.0, trunk_height * rng.gen_range(0.5, 0.9), 0.0);
+        let length = crown_radius * rng.gen_range(0.5, 1.0);
+        let end = from.add_v(&heading(rng.gen_range(0.0, 2.0 * PI), rng.gen_range(0.3, 1.0)).mul_s(length));
+        tree.limb(&from, &end, limb_radius);
+        limb_ends.push(end);
+      }
+    },
   }
 
-  T {
-    union: union,
+  match params.leaves {
+    Leaves::None => {},
+    Leaves::Clusters { radius } => {
+      for end in &limb_ends {
+        tree.push(
+          voxel::Material::Leaves,
+          end,
+          radius,
+          field::sphere::T {
+            radius: radius,
+          },
+        );
+      }
+    },
+    Leaves::Cone => {
+      let crown_base = trunk_height / 3.0;
+      let height = trunk_height - crown_base + crown_radius / 2.0;
+      // The cone's base is at its origin, so bound it by its full height in every direction.
+      let center = Point3::new(0.0, crown_base, 0.0);
+      tree.push(
+        voxel::Material::Leaves,
+        &center,
+        f32::max(crown_radius, height),
+        cone::T {
+          radius: crown_radius,
+          height: height,
+        },
+      );
+    },
   }
+
+  tree
 }
 
 impl field::T for T {
   fn density(&self, p: &Point3<f32>) -> f32 {
-    field::T::density(&self.union, p)
+    self.union.density(p)
   }
 
   fn normal(&self, p: &Point3<f32>) -> Vector3<f32> {
-    field::T::normal(&self.union, p)
+    self.union.normal(p)
   }
 }
 
 impl mosaic::T<voxel::Material> for T {
   fn material(&self, p: &Point3<f32>) -> Option<voxel::Material> {
-    mosaic::T::material(&self.union, p)
+    self.union.material(p)
+  }
+}
+
+#[test]
+fn seeds_determine_trees() {
+  for &species in SPECIES.iter() {
+    let a = new(species, 7);
+    let b = new(species, 7);
+    assert_eq!(a.corners(), b.corners());
+    for i in 0..64 {
+      let p = Point3::new((i % 4) as f32 - 2.0, (i / 4) as f32, 1.5);
+      assert_eq!(field::T::density(&a, &p), field::T::density(&b, &p));
+      assert_eq!(mosaic::T::material(&a, &p), mosaic::T::material(&b, &p));
+    }
   }
 }
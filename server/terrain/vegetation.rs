@@ -0,0 +1,368 @@
+//! A mosaic that scatters trees and bushes over the generated terrain.
+//!
+//! The world is divided into square regions of columns, and each region's plants are chosen from
+//! the world seed and the region's position alone. The mosaic is therefore a pure function of
+//! position, so a plant straddling several blocks is meshed the same way in all of them, however
+//! and in whatever order they're generated.
+//!
+//! Regions are cached, so to keep from contending for the cache on every sample, plants are looked up
+//! once for each box of terrain being generated; see `T::near`.
+
+use cgmath::{Point, Point3, Vector3};
+use rand;
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use noise::Seed;
+
+use biome;
+use tree;
+use voxel;
+use voxel_data;
+
+/// The width of the square regions that plants are placed in, in voxels.
+/// No plant reaches farther than this from its base, so only the neighboring regions need checking.
+pub const REGION_WIDTH: i32 = 32;
+
+/// Forget every cached region once there are more than this. They can always be placed again.
+const MAX_CACHED_REGIONS: usize = 1 << 12;
+
+/// The number of places in each region where a plant might grow.
+const CANDIDATES_PER_REGION: u32 = 12;
+
+/// Plants don't grow on ground steeper than this.
+const MIN_NORMAL_Y: f32 = 0.7;
+
+/// The range of heights that are searched for the ground.
+const MAX_HEIGHT: i32 = 512;
+const MIN_HEIGHT: i32 = -512;
+
+/// What grows in a biome.
+struct Flora {
+  /// The expected number of plants in a region of flat ground.
+  plants_per_region: f32,
+  /// The relative likelihood of each species.
+  species: &'static [(tree::Species, u32)],
+}
+
+const HILLS: Flora =
+  Flora {
+    plants_per_region: 8.0,
+    species: &[(tree::Species::Oak, 4), (tree::Species::Bush, 5), (tree::Species::Pine, 1)],
+  };
+
+const MOUNTAINS: Flora =
+  Flora {
+    plants_per_region: 3.0,
+    species: &[(tree::Species::Pine, 6), (tree::Species::Dead, 2), (tree::Species::Bush, 1)],
+  };
+
+const CAVES: Flora =
+  Flora {
+    plants_per_region: 1.0,
+    species: &[(tree::Species::Bush, 2), (tree::Species::Dead, 1)],
+  };
+
+/// A plant, and where it's been put.
+struct Plant {
+  base: Point3<f32>,
+  low: Point3<f32>,
+  high: Point3<f32>,
+  tree: tree::T,
+}
+
+impl Plant {
+  fn contains(&self, p: &Point3<f32>) -> bool {
+    true
+    && self.low.x <= p.x && p.x <= self.high.x
+    && self.low.y <= p.y && p.y <= self.high.y
+    && self.low.z <= p.z && p.z <= self.high.z
+  }
+
+  fn local(&self, p: &Point3<f32>) -> Point3<f32> {
+    p.sub_v(&self.base.to_vec())
+  }
+}
+
+#[allow(missing_docs)]
+pub struct T {
+  pub ground: biome::selector::T,
+  seed: u32,
+  /// The plants in every region that's been looked at, keyed by the region's lowest (x, z) corner.
+  regions: Mutex<HashMap<(i32, i32), Arc<Vec<Plant>>>>,
+}
+
+#[allow(missing_docs)]
+pub fn new(seed: u32) -> T {
+  T {
+    ground: biome::selector::new(Seed::new(seed)),
+    seed: seed,
+    regions: Mutex::new(HashMap::new()),
+  }
+}
+
+// Scramble the bits of an integer, so that nearby inputs give unrelated outputs.
+fn mix(x: u32) -> u32 {
+  let x = (x ^ (x >> 16)).wrapping_mul(0x85ebca6b);
+  let x = (x ^ (x >> 13)).wrapping_mul(0xc2b2ae35);
+  x ^ (x >> 16)
+}
+
+fn region_of(x: f32) -> i32 {
+  (x / REGION_WIDTH as f32).floor() as i32 * REGION_WIDTH
+}
+
+fn choose_species<Rng: rand::Rng>(rng: &mut Rng, flora: &Flora) -> tree::Species {
+  let total = flora.species.iter().fold(0, |total, &(_, weight)| total + weight);
+  let mut pick = rng.gen_range(0, total);
+  for &(species, weight) in flora.species {
+    if pick < weight {
+      return species
+    }
+    pick -= weight;
+  }
+  unreachable!()
+}
+
+/// The plant with the most solid density at a point, if any are solid there.
+fn plant_at<'a>(regions: &'a [Arc<Vec<Plant>>], p: &Point3<f32>) -> Option<(&'a Plant, f32)> {
+  let mut r: Option<(&'a Plant, f32)> = None;
+  for plants in regions {
+    for plant in plants.iter() {
+      if !plant.contains(p) {
+        continue
+      }
+      let density = voxel_data::field::T::density(&plant.tree, &plant.local(p));
+      if density >= 0.0 && r.map_or(true, |(_, best)| density > best) {
+        r = Some((plant, density));
+      }
+    }
+  }
+  r
+}
+
+impl T {
+  /// The highest point of solid ground in a column, if there is any.
+  fn ground_height(&self, x: f32, z: f32) -> Option<f32> {
+    let solid = |y: i32| voxel_data::field::T::density(&self.ground, &Point3::new(x, y as f32, z)) >= 0.0;
+    // Look for the ground coarsely, then go back up to find its top.
+    let mut y = MAX_HEIGHT;
+    while !solid(y) {
+      y -= 4;
+      if y < MIN_HEIGHT {
+        return None
+      }
+    }
+    while y < MAX_HEIGHT && solid(y + 1) {
+      y += 1;
+    }
+    Some(y as f32)
+  }
+
+  fn place(&self, region_x: i32, region_z: i32) -> Vec<Plant> {
+    let mut rng: XorShiftRng =
+      SeedableRng::from_seed([
+        mix(self.seed),
+        mix(region_x as u32 ^ 0x2545f491),
+        mix(region_z as u32 ^ 0x6a09e667),
+        0x9e3779b9,
+      ]);
+
+    let mut plants = Vec::new();
+    for _ in 0..CANDIDATES_PER_REGION {
+      // Draw everything up front, so each candidate uses the same numbers whether or not the ones
+      // before it grew.
+      let x = region_x as f32 + rng.gen_range(0.0, REGION_WIDTH as f32);
+      let z = region_z as f32 + rng.gen_range(0.0, REGION_WIDTH as f32);
+      let chance: f32 = rng.gen();
+      let which_biome: f32 = rng.gen();
+      let mut species_rng: XorShiftRng = SeedableRng::from_seed([rng.gen(), rng.gen(), rng.gen(), 0x7f4a7c15]);
+      let seed: u32 = rng.gen();
+
+      let y =
+        match self.ground_height(x, z) {
+          None => continue,
+          Some(y) => y,
+        };
+      let base = Point3::new(x, y, z);
+
+      let normal = voxel_data::field::T::normal(&self.ground, &base);
+      if normal.y < MIN_NORMAL_Y {
+        continue
+      }
+      let flatness = (normal.y - MIN_NORMAL_Y) / (1.0 - MIN_NORMAL_Y);
+
+      let weights = self.ground.weights(&base);
+      let flora =
+        if which_biome < weights.hills {
+          &HILLS
+        } else if which_biome < weights.hills + weights.mountains {
+          &MOUNTAINS
+        } else {
+          &CAVES
+        };
+      let plants_per_region =
+        weights.hills * HILLS.plants_per_region +
+        weights.mountains * MOUNTAINS.plants_per_region +
+        weights.caves * CAVES.plants_per_region;
+      if chance >= flatness * plants_per_region / CANDIDATES_PER_REGION as f32 {
+        continue
+      }
+
+      let species = choose_species(&mut species_rng, flora);
+      let tree = tree::new(species, seed);
+      let (low, high) = tree.corners();
+      let reach = REGION_WIDTH as f32;
+      if low.x < -reach || low.z < -reach || high.x > reach || high.z > reach {
+        // This plant could be missed from blocks that aren't in neighboring regions.
+        continue
+      }
+
+      plants.push(Plant {
+        base: base,
+        low: base.add_v(&low.to_vec()),
+        high: base.add_v(&high.to_vec()),
+        tree: tree,
+      });
+    }
+    plants
+  }
+
+  fn region(&self, region_x: i32, region_z: i32) -> Arc<Vec<Plant>> {
+    match self.regions.lock().unwrap().get(&(region_x, region_z)) {
+      None => {},
+      Some(plants) => return plants.clone(),
+    }
+
+    // Place without holding the lock; if another thread placed this region meanwhile, it got the same result.
+    let plants = Arc::new(self.place(region_x, region_z));
+    let mut regions = self.regions.lock().unwrap();
+    if regions.len() >= MAX_CACHED_REGIONS {
+      regions.clear();
+    }
+    regions
+      .entry((region_x, region_z))
+      .or_insert(plants)
+      .clone()
+  }
+
+  fn regions_near(&self, p: &Point3<f32>) -> Vec<Arc<Vec<Plant>>> {
+    let x = region_of(p.x);
+    let z = region_of(p.z);
+    let mut regions = Vec::with_capacity(9);
+    for &dx in &[-REGION_WIDTH, 0, REGION_WIDTH] {
+    for &dz in &[-REGION_WIDTH, 0, REGION_WIDTH] {
+      regions.push(self.region(x + dx, z + dz));
+    }}
+    regions
+  }
+
+  /// The mosaic for the box from `low` to `high`. Every plant that could reach into the box is looked up
+  /// now, so sampling inside it doesn't touch the region cache.
+  pub fn near<'a>(&'a self, low: &Point3<f32>, high: &Point3<f32>) -> Patch<'a> {
+    let mut regions = Vec::new();
+    let mut x = region_of(low.x) - REGION_WIDTH;
+    while x <= region_of(high.x) + REGION_WIDTH {
+      let mut z = region_of(low.z) - REGION_WIDTH;
+      while z <= region_of(high.z) + REGION_WIDTH {
+        regions.push(self.region(x, z));
+        z += REGION_WIDTH;
+      }
+      x += REGION_WIDTH;
+    }
+    Patch {
+      vegetation: self,
+      low: *low,
+      high: *high,
+      regions: regions,
+    }
+  }
+}
+
+/// The vegetation mosaic, with the plants around a box already looked up.
+/// It can still be sampled outside the box, but then it has to look plants up for every sample.
+pub struct Patch<'a> {
+  vegetation: &'a T,
+  low: Point3<f32>,
+  high: Point3<f32>,
+  regions: Vec<Arc<Vec<Plant>>>,
+}
+
+impl<'a> Patch<'a> {
+  fn with_plant_at<R, F>(&self, p: &Point3<f32>, f: F) -> R
+    where F: FnOnce(Option<(&Plant, f32)>) -> R
+  {
+    let inside =
+      true
+      && self.low.x <= p.x && p.x <= self.high.x
+      && self.low.y <= p.y && p.y <= self.high.y
+      && self.low.z <= p.z && p.z <= self.high.z;
+    if inside {
+      f(plant_at(&self.regions, p))
+    } else {
+      let regions = self.vegetation.regions_near(p);
+      f(plant_at(&regions, p))
+    }
+  }
+}
+
+impl<'a> voxel_data::field::T for Patch<'a> {
+  fn density(&self, p: &Point3<f32>) -> f32 {
+    let ground = voxel_data::field::T::density(&self.vegetation.ground, p);
+    self.with_plant_at(p, |plant| {
+      match plant {
+        None => ground,
+        Some((_, density)) => f32::max(ground, density),
+      }
+    })
+  }
+
+  fn normal(&self, p: &Point3<f32>) -> Vector3<f32> {
+    let ground = voxel_data::field::T::density(&self.vegetation.ground, p);
+    self.with_plant_at(p, |plant| {
+      match plant {
+        Some((plant, density)) if density > ground => voxel_data::field::T::normal(&plant.tree, &plant.local(p)),
+        _ => voxel_data::field::T::normal(&self.vegetation.ground, p),
+      }
+    })
+  }
+}
+
+impl<'a> voxel_data::mosaic::T<voxel::Material> for Patch<'a> {
+  fn material(&self, p: &Point3<f32>) -> Option<voxel::Material> {
+    let ground = voxel_data::field::T::density(&self.vegetation.ground, p);
+    self.with_plant_at(p, |plant| {
+      match plant {
+        Some((plant, density)) if density > ground => voxel_data::mosaic::T::material(&plant.tree, &plant.local(p)),
+        _ => voxel_data::mosaic::T::material(&self.vegetation.ground, p),
+      }
+    })
+  }
+}
+
+#[test]
+fn regions_are_placed_the_same_in_any_order() {
+  let a = new(7);
+  let b = new(7);
+  let a_regions = vec!(a.region(0, 0), a.region(REGION_WIDTH, 0));
+  let b_regions = vec!(b.region(REGION_WIDTH, 0), b.region(0, 0));
+
+  for (a, b) in a_regions.iter().zip(b_regions.iter().rev()) {
+    assert_eq!(a.len(), b.len());
+    for (a, b) in a.iter().zip(b.iter()) {
+      assert_eq!(a.base, b.base);
+      assert_eq!((a.low, a.high), (b.low, b.high));
+    }
+  }
+}
+
+#[test]
+fn patches_match_lookups_anywhere() {
+  let vegetation = new(7);
+  let patch = vegetation.near(&Point3::new(0.0, -8.0, 0.0), &Point3::new(8.0, 0.0, 8.0));
+  for &p in &[Point3::new(4.0, -4.0, 4.0), Point3::new(100.0, -4.0, -100.0)] {
+    let expected = plant_at(&vegetation.regions_near(&p), &p).map(|(plant, density)| (plant.base, density));
+    assert_eq!(patch.with_plant_at(&p, |plant| plant.map(|(plant, density)| (plant.base, density))), expected);
+  }
+}